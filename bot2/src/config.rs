@@ -12,6 +12,9 @@ pub struct Config {
     pub worker_memory_limit: usize,
     pub concurrency: usize,
     pub credentials: Option<Credentials>,
+    pub record: Option<String>,
+    pub replay: Option<String>,
+    pub replay_speed: f64,
 }
 #[derive(Clone, serde::Deserialize)]
 struct PartialConfig {
@@ -20,6 +23,9 @@ struct PartialConfig {
     worker_memory_limit: Option<usize>,
     concurrency: Option<usize>,
     credentials: Option<Credentials>,
+    record: Option<String>,
+    replay: Option<String>,
+    replay_speed: Option<f64>,
 }
 impl Config {
     pub fn init(path: &str) -> Config {
@@ -54,6 +60,20 @@ impl Config {
                 },
                 _ => twitch::conn::Login::Anonymous,
             },
+            record: self.record.as_ref().map(twitch::conn::Recording::new),
+            on_malformed: twitch::conn::OnMalformed::Message,
+            ..Default::default()
+        }
+    }
+
+    pub fn replay_speed(&self) -> twitch::conn::record::Speed {
+        use twitch::conn::record::Speed;
+        if self.replay_speed <= 0.0 {
+            Speed::Unlimited
+        } else if (self.replay_speed - 1.0).abs() < f64::EPSILON {
+            Speed::Original
+        } else {
+            Speed::Accelerated(self.replay_speed)
         }
     }
 
@@ -74,6 +94,9 @@ impl From<PartialConfig> for Config {
             worker_memory_limit: cfg.worker_memory_limit.unwrap_or(512 * 1024 * 1024),
             concurrency: cfg.concurrency.unwrap_or_else(num_cpus::get),
             credentials: cfg.credentials,
+            record: cfg.record,
+            replay: cfg.replay,
+            replay_speed: cfg.replay_speed.unwrap_or(1.0),
         }
     }
 }
@@ -84,6 +107,9 @@ impl Display for Config {
         writeln!(f, "\tworker_memory_limit = {},", self.worker_memory_limit)?;
         writeln!(f, "\tconcurrency = {},", self.concurrency)?;
         writeln!(f, "\tcredentials = ...,")?;
+        writeln!(f, "\trecord = {:?},", self.record)?;
+        writeln!(f, "\treplay = {:?},", self.replay)?;
+        writeln!(f, "\treplay_speed = {},", self.replay_speed)?;
         write!(f, "}}")
     }
 }
@@ -4,12 +4,13 @@ mod db;
 mod util;
 mod worker;
 
-use std::{collections::HashMap, sync::Arc, thread::JoinHandle, time::Duration};
+use std::{collections::HashMap, pin::Pin, sync::Arc, thread::JoinHandle};
 
 use anyhow::Result;
 use async_channel as mpmc;
 use config::Config;
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::mpsc;
+use tokio_stream::{Stream, StreamExt};
 use worker::Worker;
 
 fn init_logger() -> Result<()> {
@@ -19,6 +20,26 @@ fn init_logger() -> Result<()> {
     Ok(alto_logger::init_term_logger()?)
 }
 
+/// Messages from TMI, or from a recording of it
+type TmiReader = Pin<Box<dyn Stream<Item = twitch::conn::Result<twitch::Message>> + Send>>;
+
+/// Outcome of a join or a part, reported back to the main loop once Twitch
+/// confirmed it (or didn't), so that messages keep being read meanwhile
+enum Membership {
+    Joined {
+        name: String,
+        prefix: Option<String>,
+        /// Channel the command came from
+        reply_to: String,
+        result: twitch::conn::Result<()>,
+    },
+    Left {
+        name: String,
+        reply_to: String,
+        result: twitch::conn::Result<()>,
+    },
+}
+
 struct Bot {
     config: Config,
     db: sqlx::SqliteConnection,
@@ -27,29 +48,28 @@ struct Bot {
     commands: HashMap<String, db::Command>,
 
     inst_senders: Vec<mpsc::Sender<worker::Instruction>>,
-    /// TMI Message Sender (wrapper over a TCP stream write half)
-    tmi_sender: Arc<Mutex<twitch::conn::Sender>>,
+    /// TMI Message Sender (routes messages to the pooled connection which
+    /// joined the channel, cloned into every worker)
+    tmi_sender: twitch::conn::pool::Sender,
     /// Sender to Workers (for handling messages)
     msg_sender: mpmc::Sender<worker::Command>,
-    /// TMI Message Reader (wrapper over a TCP stream read half)
-    tmi_reader: twitch::conn::Reader,
+    /// TMI Message Reader (merged messages of all pooled connections, or a
+    /// replayed recording)
+    tmi_reader: TmiReader,
+    /// Confirmations of joins and parts, sent by the tasks waiting for them
+    membership_sender: mpsc::Sender<Membership>,
+    membership_receiver: mpsc::Receiver<Membership>,
+    /// Fake TMI server which receives messages sent while replaying
+    _replay_server: Option<twitch::testing::Server>,
     _workers: Vec<JoinHandle<()>>,
 }
 
 macro_rules! respond {
     ($self:ident, $channel:expr, $msg:literal) => {{
-        $self.tmi_sender
-            .lock()
-            .await
-            .privmsg($channel, $msg)
-            .await?;
+        $self.tmi_sender.privmsg($channel, $msg).await?;
     }};
     ($self:ident, $channel:expr, $($arg:tt)*) => {{
-        $self.tmi_sender
-            .lock()
-            .await
-            .privmsg($channel, &format!($($arg)*))
-            .await?;
+        $self.tmi_sender.privmsg($channel, &format!($($arg)*)).await?;
     }}
 }
 
@@ -71,7 +91,31 @@ impl Bot {
         let mut db = db::connect(true).await?;
 
         // connect to twitch
-        let (mut tmi_sender, tmi_reader) = twitch::connect(config.twitch()).await?.split();
+        let (tmi_sender, tmi_reader, replay_server) = match &config.replay {
+            Some(path) => {
+                log::info!("Replaying {}", path);
+                // messages are sent to a fake TMI server instead
+                let server = twitch::testing::Server::new();
+                let tmi_config = twitch::Config {
+                    record: None,
+                    ..config.twitch()
+                };
+                let (tmi_sender, mut pool_reader) = twitch::conn::pool::connect_with(tmi_config, server.transport())
+                    .await?
+                    .split();
+                // the pool only forwards its connections' messages, and
+                // resolves join and part confirmations, while it's read
+                tokio::spawn(async move {
+                    while !matches!(pool_reader.next().await, Err(twitch::conn::Error::StreamClosed)) {}
+                });
+                let replay = twitch::conn::Replay::open(path, config.replay_speed()).await?;
+                (tmi_sender, Box::pin(replay) as TmiReader, Some(server))
+            }
+            None => {
+                let (tmi_sender, tmi_reader) = twitch::conn::pool::connect(config.twitch()).await?.split();
+                (tmi_sender, Box::pin(tmi_reader) as TmiReader, None)
+            }
+        };
 
         // join channels
         // main channel
@@ -86,8 +130,6 @@ impl Bot {
             channels.insert(channel.name.clone(), channel);
         }
 
-        let tmi_sender = Arc::new(Mutex::new(tmi_sender));
-
         // multi-producer multi-consumer queue for incoming messages
         // these will be produced by the main message loop
         // and consumed by workers
@@ -112,7 +154,9 @@ impl Bot {
             ))
         }
 
-        tmi_sender.lock().await.privmsg("moscowwbish", "Connected").await?;
+        tmi_sender.privmsg("moscowwbish", "Connected").await?;
+
+        let (membership_sender, membership_receiver) = mpsc::channel(16);
 
         let mut bot = Bot {
             config,
@@ -123,6 +167,9 @@ impl Bot {
             tmi_sender,
             msg_sender,
             tmi_reader,
+            membership_sender,
+            membership_receiver,
+            _replay_server: replay_server,
             _workers: workers,
         };
 
@@ -136,22 +183,6 @@ impl Bot {
         Ok(bot)
     }
 
-    pub async fn reconnect(&mut self) -> Result<()> {
-        let (mut tmi_sender, tmi_reader) = twitch::connect(twitch::Config::default()).await?.split();
-        tmi_sender.join(&self.config.main_channel).await?;
-
-        for channel in self.channels.values() {
-            if channel.joined == 1 {
-                tmi_sender.join(&channel.name).await?;
-            }
-        }
-
-        *(self.tmi_sender.lock().await) = tmi_sender;
-        self.tmi_reader = tmi_reader;
-
-        Ok(())
-    }
-
     pub fn is_privileged_user(&mut self, login: &str) -> bool {
         // TODO: configure this through db
         ["moscowwbish", "compileraddict"].contains(&login)
@@ -195,34 +226,66 @@ impl Bot {
         }
     }
 
-    async fn join(&mut self, name: String, prefix: Option<String>) -> Result<&'static str> {
+    async fn join(&mut self, name: String, prefix: Option<String>, reply_to: &str) -> Result<Option<&'static str>> {
         if name == self.config.main_channel {
-            Ok("Can't join main channel")
-        } else {
-            match self.channels.get_mut(&name) {
-                Some(channel) if channel.joined == 1 => Ok("Channel already joined"),
-                Some(channel) => {
-                    channel.joined = 1;
-                    if let Some(prefix) = prefix {
-                        channel.prefix = prefix;
-                    }
-                    channel.save(&mut self.db).await?;
-                    // TODO: actually check if bot joins the channel
-                    self.tmi_sender.lock().await.join(&name).await?;
-                    Ok("Channel joined successfully")
-                }
-                _ => {
-                    let mut channel = db::Channel::new(name, prefix);
-                    channel.save(&mut self.db).await?;
-                    self.tmi_sender.lock().await.join(&channel.name).await?;
-                    self.channels.insert(channel.name.clone(), channel);
-                    Ok("Channel joined successfully")
-                }
+            return Ok(Some("Can't join main channel"));
+        }
+        if let Some(channel) = self.channels.get(&name) {
+            if channel.joined == 1 {
+                return Ok(Some("Channel already joined"));
+            }
+        }
+        let confirmation = self.tmi_sender.join_confirmed(&name).await?;
+        let membership = self.membership_sender.clone();
+        let reply_to = reply_to.to_string();
+        tokio::spawn(async move {
+            let result = confirmation.await;
+            let _ = membership
+                .send(Membership::Joined {
+                    name,
+                    prefix,
+                    reply_to,
+                    result,
+                })
+                .await;
+        });
+        Ok(None)
+    }
+
+    async fn joined(
+        &mut self,
+        name: String,
+        prefix: Option<String>,
+        result: twitch::conn::Result<()>,
+    ) -> Result<&'static str> {
+        let (joined, response) = match result {
+            Ok(()) => (1, "Channel joined successfully"),
+            Err(twitch::conn::Error::JoinFailed(reason)) => {
+                log::error!("Failed to join {}: {:?}", name, reason);
+                (0, "Can't join channel, it doesn't exist or the bot is banned there")
+            }
+            Err(err) => {
+                log::error!("Failed to join {}: {}", name, err);
+                (0, "Can't join channel, Twitch didn't confirm it")
             }
+        };
+        if joined == 0 {
+            // don't rejoin it after a reconnect
+            self.tmi_sender.part(&name).await?;
+        }
+        let channel = self
+            .channels
+            .entry(name.clone())
+            .or_insert_with(|| db::Channel::new(name, None));
+        channel.joined = joined;
+        if let Some(prefix) = prefix {
+            channel.prefix = prefix;
         }
+        channel.save(&mut self.db).await?;
+        Ok(response)
     }
 
-    async fn leave(&mut self, which: &str) -> Result<Option<&'static str>> {
+    async fn leave(&mut self, which: &str, reply_to: &str) -> Result<Option<&'static str>> {
         if which == self.config.main_channel {
             Ok(Some("Can't leave the main channel"))
         } else {
@@ -230,9 +293,13 @@ impl Bot {
                 Some(channel) if channel.joined == 1 => {
                     channel.joined = 0;
                     channel.save(&mut self.db).await?;
-                    // TODO: actually check if this is true
-                    // for now it just leaves and doesn't care if it doesn't work
-                    self.tmi_sender.lock().await.part(which).await?;
+                    let confirmation = self.tmi_sender.part_confirmed(which).await?;
+                    let membership = self.membership_sender.clone();
+                    let (name, reply_to) = (which.to_string(), reply_to.to_string());
+                    tokio::spawn(async move {
+                        let result = confirmation.await;
+                        let _ = membership.send(Membership::Left { name, reply_to, result }).await;
+                    });
                     Ok(None)
                 }
                 _ => Ok(Some("Channel not joined")),
@@ -240,6 +307,27 @@ impl Bot {
         }
     }
 
+    async fn handle_membership(&mut self, membership: Membership) -> Result<()> {
+        match membership {
+            Membership::Joined {
+                name,
+                prefix,
+                reply_to,
+                result,
+            } => {
+                let res = self.joined(name, prefix, result).await?;
+                respond!(self, &reply_to, "{}", res);
+            }
+            Membership::Left { name, reply_to, result } => {
+                if let Err(err) = result {
+                    log::error!("Failed to leave {}: {}", name, err);
+                    respond!(self, &reply_to, "Left channel, but Twitch didn't confirm it");
+                }
+            }
+        }
+        Ok(())
+    }
+
     async fn prefix(&mut self, which: &str, prefix: String) -> Result<&'static str> {
         if which == self.config.main_channel {
             Ok("Can't change main channel prefix")
@@ -298,8 +386,10 @@ impl Bot {
                     if args.len() > 0 {
                         let name = args.remove(0);
                         let prefix = if args.len() > 0 { Some(args.remove(0)) } else { None };
-                        let res = self.join(name, prefix).await?;
-                        respond!(self, message.channel(), "{}", res);
+                        let res = self.join(name, prefix, message.channel()).await?;
+                        if let Some(res) = res {
+                            respond!(self, message.channel(), "{}", res);
+                        }
                     } else {
                         respond!(self, message.channel(), "Usage: !join <channel> [prefix]");
                     }
@@ -311,7 +401,7 @@ impl Bot {
                     if !args.is_empty() {
                         let name = args.remove(0);
                         let which = if &name == "this" { message.channel() } else { &name };
-                        let res = self.leave(which).await?;
+                        let res = self.leave(which, message.channel()).await?;
                         if let Some(res) = res {
                             respond!(self, message.channel(), "{}", res);
                         }
@@ -353,34 +443,22 @@ impl Bot {
                     break Ok(());
                 },
                 msg = self.tmi_reader.next() => match msg {
-                    Ok(msg) => {
+                    Some(Ok(msg)) => {
                         match msg {
-                        twitch::Message::Ping(ping) => {
-                            self.tmi_sender.lock().await.pong(ping.arg()).await?;
-                        },
                         twitch::Message::Privmsg(message) => self.handle_msg(message).await?,
+                        twitch::Message::Reconnected => log::info!("Reconnected to TMI"),
+                        twitch::Message::Malformed { raw, error } => log::warn!("Malformed message ({}): {}", error, raw),
                         other => log::info!("{:?}", other)
                     }},
-                    Err(err) => match err {
-                        twitch::conn::Error::StreamClosed => {
-                            log::info!("Disconnected, attempting to reconnect...");
-                            let mut success = false;
-                            let mut attempts = 0;
-                            while attempts < 10 {
-                                match self.reconnect().await {
-                                    Ok(_) => { success = true; break; },
-                                    Err(err) => log::error!("Failed to reconnect after attemp #{} ({}), retrying...", attempts, err)
-                                }
-                                tokio::time::sleep(Duration::from_secs(attempts * 3)).await;
-                                attempts += 1;
-                            }
-                            if !success {
-                                panic!("Failed to reconnect");
-                            }
-                        },
-                        err => panic!("{}", err)
+                    // the line is skipped, the stream keeps going
+                    Some(Err(twitch::conn::Error::Parse(err))) => log::warn!("{}", err),
+                    Some(Err(err)) => panic!("{}", err),
+                    None => {
+                        log::info!("TMI stream ended");
+                        break Ok(());
                     }
-                }
+                },
+                Some(membership) = self.membership_receiver.recv() => self.handle_membership(membership).await?,
             }
         }
     }
@@ -5,7 +5,6 @@ use std::{
 
 use anyhow::Result;
 use async_channel as mpmc;
-use tokio::sync::Mutex;
 use tokio::{runtime::Handle, sync::mpsc};
 
 use crate::{config::Config, util};
@@ -41,7 +40,7 @@ pub struct Worker {
     ctx: script::Context,
     inst_receiver: mpsc::Receiver<Instruction>,
     msg_receiver: Arc<mpmc::Receiver<Command>>,
-    tmi_sender: Arc<Mutex<twitch::conn::Sender>>,
+    tmi_sender: twitch::conn::pool::Sender,
 }
 
 unsafe impl Send for Worker {}
@@ -52,7 +51,7 @@ impl Worker {
         config: Config,
         inst_receiver: mpsc::Receiver<Instruction>,
         msg_receiver: Arc<mpmc::Receiver<Command>>,
-        tmi_sender: Arc<Mutex<twitch::conn::Sender>>,
+        tmi_sender: twitch::conn::pool::Sender,
     ) -> JoinHandle<()> {
         let tokio_handle = Handle::current();
         thread::spawn(move || {
@@ -69,7 +68,7 @@ impl Worker {
         config: Config,
         inst_receiver: mpsc::Receiver<Instruction>,
         msg_receiver: Arc<mpmc::Receiver<Command>>,
-        tmi_sender: Arc<Mutex<twitch::conn::Sender>>,
+        tmi_sender: twitch::conn::pool::Sender,
     ) -> Worker {
         let script_config = config.script();
         Worker {
@@ -127,7 +126,8 @@ impl Worker {
         match result {
             Ok(r) => {
                 log::info!("[Worker #{}] -> {}", self.id, r);
-                if let Err(err) = self.tmi_sender.lock().await.privmsg(command.source.channel(), &r).await {
+                let source = &command.source;
+                if let Err(err) = self.tmi_sender.reply(source.channel(), source.id(), &r).await {
                     // TODO: may need to properly handle some errors
                     log::error!("[Worker #{}] Error while writing to TMI: {}", self.id, err);
                 }
@@ -22,7 +22,9 @@ async fn main() {
             },
             result = conn.reader.next() => match result {
                 Ok(message) => match message {
-                    Message::Ping(ping) => conn.sender.pong(ping.arg()).await.unwrap(),
+                    Message::Ping(ping) => {
+                        conn.sender.pong(ping.arg()).await.unwrap();
+                    }
                     Message::Privmsg(message) => {
                         log::info!("#{} {} ({}): {}", message.channel(), message.user.name, message.user.id(), message.text());
                         if message.text().starts_with("!stop") {
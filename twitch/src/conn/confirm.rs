@@ -0,0 +1,377 @@
+//! Confirmations of sent commands
+//!
+//! Twitch doesn't answer commands directly, it echoes a JOIN or PART, sends
+//! the new ROOMSTATE, or a NOTICE explaining why a command failed. A
+//! [`Confirmation`] waits for the message which settles one command, as
+//! recognized by the [`Reader`] of the same connection.
+//!
+//! [`Reader`]: super::Reader
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::ready;
+use tokio::{sync::oneshot, time::Sleep};
+
+use super::{Error, Result};
+use crate::tmi::{parse::NoticeId, Message};
+
+/// The outcome of a command, resolves once Twitch confirms or rejects it.
+///
+/// Fails with [`Error::Timeout`] if neither happens in time, see
+/// [`Config::confirmation_timeout`], and with [`Error::StreamClosed`] if the
+/// connection is closed first. It's only settled while the connection's
+/// [`Reader`] is being read.
+///
+/// [`Config::confirmation_timeout`]: super::Config::confirmation_timeout
+pub struct Confirmation<T> {
+    receiver: oneshot::Receiver<Result<T>>,
+    timeout: Pin<Box<Sleep>>,
+}
+
+impl<T> Future for Confirmation<T> {
+    type Output = Result<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<T>> {
+        if let Poll::Ready(result) = Pin::new(&mut self.receiver).poll(cx) {
+            return Poll::Ready(result.unwrap_or(Err(Error::StreamClosed)));
+        }
+        ready!(self.timeout.as_mut().poll(cx));
+        Poll::Ready(Err(Error::Timeout))
+    }
+}
+
+enum Status {
+    Waiting,
+    Resolved,
+    /// The [`Confirmation`] was dropped, e.g. after it timed out
+    Dropped,
+}
+
+/// Checks a message against one waiting [`Confirmation`], `None` only checks
+/// whether it was dropped
+type Waiter = Box<dyn FnMut(Option<&Message>) -> Status + Send>;
+
+/// Commands waiting for a confirmation, oldest first
+#[derive(Default)]
+pub(crate) struct Pending(Mutex<Vec<Waiter>>);
+
+impl Pending {
+    /// Registers a command, which is settled by the first message for which
+    /// `check` returns `Some`
+    pub(crate) fn wait<T, F>(&self, timeout: Duration, check: F) -> Confirmation<T>
+    where
+        T: Send + 'static,
+        F: Fn(&Message) -> Option<Result<T>> + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let mut sender = Some(sender);
+        self.0.lock().unwrap().push(Box::new(move |message| {
+            let closed = sender.as_ref().map(|sender| sender.is_closed()).unwrap_or(true);
+            if closed {
+                return Status::Dropped;
+            }
+            match message.and_then(&check) {
+                Some(result) => {
+                    let _ = sender.take().unwrap().send(result);
+                    Status::Resolved
+                }
+                None => Status::Waiting,
+            }
+        }));
+        Confirmation {
+            receiver,
+            timeout: Box::pin(tokio::time::sleep(timeout)),
+        }
+    }
+
+    /// Settles the oldest command which `message` confirms or rejects.
+    ///
+    /// Each message settles at most one command, so that the replies to a
+    /// repeated command are matched in order.
+    pub(crate) fn resolve(&self, message: &Message) {
+        let mut waiters = self.0.lock().unwrap();
+        let mut resolved = false;
+        let mut i = 0;
+        while i < waiters.len() {
+            match (waiters[i])(if resolved { None } else { Some(message) }) {
+                Status::Waiting => i += 1,
+                status => {
+                    resolved |= matches!(status, Status::Resolved);
+                    drop(waiters.remove(i));
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize { self.0.lock().unwrap().len() }
+}
+
+/// A moderation command, which Twitch answers with a NOTICE in the same
+/// channel
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Command {
+    Clear,
+    Timeout,
+    Untimeout,
+    Ban,
+    Unban,
+    RoomMode,
+    Delete,
+    Mod,
+    Unmod,
+    Vip,
+    Unvip,
+    Unraid,
+    Commercial,
+    Color,
+}
+
+impl Command {
+    /// `Some` if `id` answers this command, with whether it succeeded.
+    ///
+    /// Most answers only fit one command, but some failures, e.g.
+    /// `no_permission`, answer any of them. Those settle the oldest pending
+    /// command in the channel, which relies on Twitch answering the commands
+    /// in one channel in the order they were sent.
+    fn answered_by(self, id: &NoticeId) -> Option<bool> {
+        use NoticeId::*;
+        let success = match self {
+            // answered with a CLEARCHAT instead
+            Command::Clear => false,
+            Command::Timeout => matches!(id, TimeoutSuccess),
+            Command::Untimeout => matches!(id, UntimeoutSuccess),
+            Command::Ban => matches!(id, BanSuccess),
+            Command::Unban => matches!(id, UnbanSuccess),
+            Command::RoomMode => matches!(
+                id,
+                EmoteOnlyOff
+                    | EmoteOnlyOn
+                    | FollowersOff
+                    | FollowersOn
+                    | FollowersOnzero
+                    | R9kOff
+                    | R9kOn
+                    | SlowOff
+                    | SlowOn
+                    | SubsOff
+                    | SubsOn
+            ),
+            Command::Delete => matches!(id, DeleteMessageSuccess),
+            Command::Mod => matches!(id, ModSuccess),
+            Command::Unmod => matches!(id, UnmodSuccess),
+            Command::Vip => matches!(id, VipSuccess),
+            Command::Unvip => matches!(id, UnvipSuccess),
+            Command::Unraid => matches!(id, UnraidSuccess),
+            Command::Commercial => matches!(id, CommercialSuccess),
+            Command::Color => matches!(id, ColorChanged),
+        };
+        if success {
+            return Some(true);
+        }
+        let failure = matches!(
+            id,
+            NoPermission | InvalidUser | UnrecognizedCmd | UnsupportedChatroomsCmd
+        ) || match self {
+            Command::Clear => matches!(id, UsageClear),
+            Command::Timeout => matches!(
+                id,
+                BadTimeoutAdmin
+                    | BadTimeoutAnon
+                    | BadTimeoutBroadcaster
+                    | BadTimeoutDuration
+                    | BadTimeoutGlobalMod
+                    | BadTimeoutMod
+                    | BadTimeoutSelf
+                    | BadTimeoutStaff
+                    | UsageTimeout
+            ),
+            Command::Untimeout => matches!(id, TimeoutNoTimeout | UntimeoutBanned | UsageUntimeout),
+            Command::Ban => matches!(
+                id,
+                AlreadyBanned
+                    | BadBanAdmin
+                    | BadBanAnon
+                    | BadBanBroadcaster
+                    | BadBanGlobalMod
+                    | BadBanMod
+                    | BadBanSelf
+                    | BadBanStaff
+                    | UsageBan
+            ),
+            Command::Unban => matches!(id, BadUnbanNoBan | UsageUnban),
+            Command::RoomMode => matches!(
+                id,
+                AlreadyEmoteOnlyOff
+                    | AlreadyEmoteOnlyOn
+                    | AlreadyR9kOff
+                    | AlreadyR9kOn
+                    | AlreadySubsOff
+                    | AlreadySubsOn
+                    | BadSlowDuration
+                    | UsageEmoteOnlyOff
+                    | UsageEmoteOnlyOn
+                    | UsageFollowersOff
+                    | UsageFollowersOn
+                    | UsageR9kOff
+                    | UsageR9kOn
+                    | UsageSlowOff
+                    | UsageSlowOn
+                    | UsageSubsOff
+                    | UsageSubsOn
+            ),
+            Command::Delete => matches!(
+                id,
+                BadDeleteMessageBroadcaster | BadDeleteMessageError | BadDeleteMessageMod | UsageDelete
+            ),
+            Command::Mod => matches!(id, BadModBanned | BadModMod | UsageMod),
+            Command::Unmod => matches!(id, BadUnmodMod | UsageUnmod),
+            Command::Vip => matches!(
+                id,
+                BadVipAchievementIncomplete
+                    | BadVipGranteeAlreadyVip
+                    | BadVipGranteeBanned
+                    | BadVipMaxVipsReached
+                    | UsageVip
+            ),
+            Command::Unvip => matches!(id, BadUnvipGranteeNotVip | UsageUnvip),
+            Command::Unraid => matches!(id, UnraidErrorNoActiveRaid | UnraidErrorUnexpected | UsageUnraid),
+            Command::Commercial => matches!(id, BadCommercialError | UsageCommercial),
+            Command::Color => matches!(id, TurboOnlyColor | UsageColor),
+        };
+        if failure {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// Settles the command sent to `channel` if `message` answers it, a
+    /// failure is returned as [`Error::CommandFailed`]
+    pub(crate) fn outcome(self, channel: &str, message: &Message) -> Option<Result<()>> {
+        match message {
+            Message::Notice(notice) if notice.channel().map(|c| c.eq_ignore_ascii_case(channel)) == Some(true) => {
+                let id = notice.id.as_ref()?;
+                match self.answered_by(id)? {
+                    true => Some(Ok(())),
+                    false => Some(Err(Error::CommandFailed(id.clone()))),
+                }
+            }
+            Message::Clearchat(clear)
+                if self == Command::Clear
+                    && clear.channel().eq_ignore_ascii_case(channel)
+                    && clear.target().is_none() =>
+            {
+                Some(Ok(()))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notice(channel: &str, id: &str) -> Message {
+        Message::parse(format!("@msg-id={} :tmi.twitch.tv NOTICE #{} :-", id, channel)).unwrap()
+    }
+
+    fn notice_in(channel: &'static str) -> impl Fn(&Message) -> Option<Result<String>> {
+        move |message| match message {
+            Message::Notice(notice) if notice.channel() == Some(channel) => Some(Ok(format!("{:?}", notice.id))),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn oldest_matching_command_is_settled() {
+        let pending = Pending::default();
+        let timeout = Duration::from_secs(10);
+        let first = pending.wait(timeout, notice_in("forsen"));
+        let other = pending.wait(timeout, notice_in("pajlada"));
+        let second = pending.wait(timeout, notice_in("forsen"));
+
+        pending.resolve(&notice("forsen", "already_banned"));
+        pending.resolve(&notice("forsen", "ban_success"));
+        assert_eq!(pending.len(), 1);
+        assert_eq!(first.await.unwrap(), "Some(AlreadyBanned)");
+        assert_eq!(second.await.unwrap(), "Some(BanSuccess)");
+
+        drop(other);
+        pending.resolve(&notice("forsen", "ban_success"));
+        assert_eq!(pending.len(), 0);
+    }
+
+    #[test]
+    fn moderation_outcomes() {
+        let ban = |message: &Message| Command::Ban.outcome("forsen", message);
+        assert!(matches!(ban(&notice("forsen", "ban_success")), Some(Ok(()))));
+        assert!(matches!(
+            ban(&notice("forsen", "bad_ban_broadcaster")),
+            Some(Err(Error::CommandFailed(NoticeId::BadBanBroadcaster)))
+        ));
+        assert!(matches!(
+            ban(&notice("forsen", "no_permission")),
+            Some(Err(Error::CommandFailed(NoticeId::NoPermission)))
+        ));
+        assert!(ban(&notice("forsen", "timeout_success")).is_none());
+        assert!(ban(&notice("pajlada", "ban_success")).is_none());
+
+        let clear =
+            Message::parse("@room-id=1;tmi-sent-ts=1594545155039 :tmi.twitch.tv CLEARCHAT #forsen".into()).unwrap();
+        assert!(matches!(Command::Clear.outcome("forsen", &clear), Some(Ok(()))));
+        assert!(matches!(
+            Command::RoomMode.outcome("forsen", &notice("forsen", "already_r9k_on")),
+            Some(Err(Error::CommandFailed(NoticeId::AlreadyR9kOn)))
+        ));
+        assert!(matches!(
+            Command::Vip.outcome("forsen", &notice("forsen", "vip_success")),
+            Some(Ok(()))
+        ));
+    }
+
+    #[tokio::test]
+    async fn different_commands_get_their_own_answers() {
+        let pending = Pending::default();
+        let timeout = Duration::from_secs(10);
+        let ban = pending.wait(timeout, |message| Command::Ban.outcome("forsen", message));
+        let timed_out = pending.wait(timeout, |message| Command::Timeout.outcome("forsen", message));
+        let untimed_out = pending.wait(timeout, |message| Command::Untimeout.outcome("forsen", message));
+
+        // answered in order, the generic failure goes to the oldest command
+        // which is still waiting
+        pending.resolve(&notice("forsen", "bad_ban_broadcaster"));
+        pending.resolve(&notice("forsen", "no_permission"));
+        pending.resolve(&notice("forsen", "untimeout_banned"));
+        assert!(matches!(
+            ban.await,
+            Err(Error::CommandFailed(NoticeId::BadBanBroadcaster))
+        ));
+        assert!(matches!(
+            timed_out.await,
+            Err(Error::CommandFailed(NoticeId::NoPermission))
+        ));
+        assert!(matches!(
+            untimed_out.await,
+            Err(Error::CommandFailed(NoticeId::UntimeoutBanned))
+        ));
+    }
+
+    #[tokio::test]
+    async fn unconfirmed_commands_time_out() {
+        let pending = Pending::default();
+        let confirmation = pending.wait(Duration::from_millis(10), notice_in("forsen"));
+        pending.resolve(&notice("pajlada", "ban_success"));
+        assert!(matches!(confirmation.await, Err(Error::Timeout)));
+
+        let confirmation = pending.wait(Duration::from_secs(10), notice_in("forsen"));
+        drop(pending);
+        assert!(matches!(confirmation.await, Err(Error::StreamClosed)));
+    }
+}
@@ -0,0 +1,259 @@
+//! Typed subscriptions to messages
+//!
+//! Instead of matching on every [`Message`], each part of a bot can subscribe
+//! to the kinds of messages it cares about, optionally only in one channel.
+//! Every subscriber receives its own copy of each matching message.
+//!
+//! ```no_run
+//! # async fn test() -> twitch::conn::Result<()> {
+//! use futures::StreamExt;
+//! use twitch::{conn::Dispatcher, tmi::parse::Raid, Privmsg};
+//!
+//! let (_sender, reader) = twitch::conn::connect(Default::default()).await?.split();
+//! let mut dispatcher = Dispatcher::new();
+//! let mut messages = dispatcher.subscribe_in::<Privmsg>("forsen");
+//! let mut raids = dispatcher.subscribe::<Raid>();
+//! tokio::spawn(async move { dispatcher.run(reader).await });
+//!
+//! while let Some(message) = messages.next().await {
+//!     println!("{}: {}", message.user.login(), message.text());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::StreamExt;
+use tokio::sync::mpsc;
+
+use super::{Error, Result};
+use crate::tmi::{parse::*, Message};
+
+/// A kind of message which can be subscribed to
+pub trait Event: Clone + Send + 'static {
+    /// `Some` if `message` is of this kind
+    fn from_message(message: &Message) -> Option<Self>;
+}
+
+impl Event for Message {
+    fn from_message(message: &Message) -> Option<Self> { Some(message.clone()) }
+}
+
+macro_rules! impl_event {
+    ($($ty:ident),*) => {$(
+        impl Event for $ty {
+            fn from_message(message: &Message) -> Option<Self> {
+                match message {
+                    Message::$ty(message) => Some(message.clone()),
+                    _ => None,
+                }
+            }
+        }
+    )*};
+}
+
+macro_rules! impl_user_notice_event {
+    ($($ty:ident),*) => {$(
+        impl Event for $ty {
+            fn from_message(message: &Message) -> Option<Self> {
+                match message {
+                    Message::UserNotice(UserNotice::$ty(notice)) => Some(notice.clone()),
+                    _ => None,
+                }
+            }
+        }
+    )*};
+}
+
+impl_event!(
+    Ping,
+    Pong,
+    Join,
+    Part,
+    Privmsg,
+    Whisper,
+    Clearchat,
+    Clearmsg,
+    GlobalUserState,
+    HostTarget,
+    Notice,
+    Reconnect,
+    RoomState,
+    UserNotice,
+    UserState,
+    Capability
+);
+impl_user_notice_event!(
+    Sub,
+    SubGift,
+    SubMysteryGift,
+    GiftPaidUpgrade,
+    RewardGift,
+    Raid,
+    Unraid,
+    Ritual,
+    BitsBadgeTier,
+    Announcement,
+    PrimePaidUpgrade,
+    ViewerMilestone,
+    PayForward,
+    CharityDonation
+);
+
+/// Forwards a message to one subscriber, returns `false` once the
+/// subscription was dropped
+type Handler = Box<dyn Fn(&Message) -> bool + Send + Sync>;
+
+/// Sends each message to every subscription which matches it
+#[derive(Default)]
+pub struct Dispatcher {
+    handlers: Vec<Handler>,
+}
+
+impl Dispatcher {
+    pub fn new() -> Dispatcher { Dispatcher::default() }
+
+    /// Subscribes to every `E`, in any channel
+    pub fn subscribe<E: Event>(&mut self) -> Subscription<E> { self.add(None) }
+
+    /// Subscribes to every `E` sent to `channel`
+    pub fn subscribe_in<E: Event>(&mut self, channel: &str) -> Subscription<E> { self.add(Some(channel.to_string())) }
+
+    fn add<E: Event>(&mut self, channel: Option<String>) -> Subscription<E> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.handlers.push(Box::new(move |message| {
+            if channel.is_some() && message.channel() != channel.as_deref() {
+                return !sender.is_closed();
+            }
+            match E::from_message(message) {
+                Some(event) => sender.send(event).is_ok(),
+                None => !sender.is_closed(),
+            }
+        }));
+        Subscription { receiver }
+    }
+
+    /// Number of subscriptions which weren't dropped yet
+    pub fn subscriptions(&self) -> usize { self.handlers.len() }
+
+    /// Sends `message` to all matching subscriptions
+    pub fn dispatch(&mut self, message: &Message) { self.handlers.retain(|handler| handler(message)); }
+
+    /// Dispatches every message of `messages`, until it ends or fails.
+    ///
+    /// Messages which couldn't be parsed are logged and skipped.
+    pub async fn run<S>(&mut self, mut messages: S) -> Result<()>
+    where
+        S: futures::Stream<Item = Result<Message>> + Unpin,
+    {
+        while let Some(message) = messages.next().await {
+            match message {
+                Ok(message) => self.dispatch(&message),
+                Err(Error::Parse(err)) => log::error!("Failed to parse message: {}", err),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Messages of one kind, see [`Dispatcher::subscribe`].
+///
+/// Messages are buffered until they're read. Dropping the subscription
+/// unsubscribes.
+pub struct Subscription<E> {
+    receiver: mpsc::UnboundedReceiver<E>,
+}
+
+impl<E> Subscription<E> {
+    /// Waits for the next message, `None` once the [`Dispatcher`] is dropped
+    pub async fn next(&mut self) -> Option<E> { self.receiver.recv().await }
+}
+
+impl<E> futures::Stream for Subscription<E> {
+    type Item = E;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<E>> { self.receiver.poll_recv(cx) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn privmsg(channel: &str, text: &str) -> Message {
+        Message::parse(format!(
+            "@badge-info=;badges=;color=;display-name=randers;emotes=;id=1;room-id=2;tmi-sent-ts=1594545155039;\
+             user-id=3 :randers!randers@randers.tmi.twitch.tv PRIVMSG #{} :{}",
+            channel, text
+        ))
+        .unwrap()
+    }
+
+    fn raid(channel: &str) -> Message {
+        Message::parse(format!(
+            "@badge-info=;badges=;color=;display-name=Pajlada;emotes=;id=1;login=pajlada;msg-id=raid;\
+             msg-param-displayName=Pajlada;msg-param-login=pajlada;msg-param-viewerCount=9;room-id=2;\
+             system-msg=9\\sraiders;tmi-sent-ts=1594545155039;user-id=3 :tmi.twitch.tv USERNOTICE #{}",
+            channel
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn subscriptions_are_typed_and_filtered() {
+        let mut dispatcher = Dispatcher::new();
+        let all = dispatcher.subscribe::<Message>();
+        let mut privmsgs = dispatcher.subscribe::<Privmsg>();
+        let mut forsen = dispatcher.subscribe_in::<Privmsg>("forsen");
+        let mut raids = dispatcher.subscribe::<Raid>();
+
+        let messages: Vec<Result<Message>> = vec![
+            Ok(privmsg("pajlada", "a")),
+            Ok(raid("forsen")),
+            Ok(privmsg("forsen", "b")),
+        ];
+        dispatcher.run(futures::stream::iter(messages)).await.unwrap();
+        drop(dispatcher);
+
+        assert_eq!(all.collect::<Vec<_>>().await.len(), 3);
+        assert_eq!(privmsgs.next().await.unwrap().text(), "a");
+        assert_eq!(privmsgs.next().await.unwrap().text(), "b");
+        assert_eq!(forsen.next().await.unwrap().text(), "b");
+        assert!(forsen.next().await.is_none());
+        assert_eq!(raids.next().await.unwrap().source_login(), "pajlada");
+    }
+
+    #[tokio::test]
+    async fn dispatch_from_a_connection() {
+        let mut server = crate::testing::Server::new();
+        let (sender, reader) = crate::conn::connect_with(Default::default(), server.transport())
+            .await
+            .unwrap()
+            .split();
+        let mut client = server.accept().await;
+
+        let mut dispatcher = Dispatcher::new();
+        let mut messages = dispatcher.subscribe_in::<Privmsg>("forsen");
+        tokio::spawn(async move { dispatcher.run(reader).await });
+
+        sender.join("forsen").await.unwrap();
+        client.wait_for("JOIN #forsen").await;
+        client.privmsg("forsen", "someone", "hello");
+        assert_eq!(messages.next().await.unwrap().text(), "hello");
+    }
+
+    #[tokio::test]
+    async fn dropped_subscriptions_are_removed() {
+        let mut dispatcher = Dispatcher::new();
+        let first = dispatcher.subscribe::<Privmsg>();
+        let _second = dispatcher.subscribe_in::<Raid>("forsen");
+        assert_eq!(dispatcher.subscriptions(), 2);
+
+        drop(first);
+        dispatcher.dispatch(&privmsg("pajlada", "a"));
+        assert_eq!(dispatcher.subscriptions(), 1);
+    }
+}
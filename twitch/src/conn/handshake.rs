@@ -0,0 +1,260 @@
+//! Connection handshake
+//!
+//! Each capability is requested on its own, and the credentials are sent
+//! right after, without waiting for the answers. TMI then answers, in no
+//! guaranteed order, with a CAP ACK or NAK per request, the welcome numerics
+//! `001` to `376`, and, for a regular login, a GLOBALUSERSTATE describing the
+//! bot. [`Handshake`] consumes these until the connection is ready, or fails
+//! with a typed error.
+
+use futures::StreamExt;
+use tokio::io::{split, AsyncWriteExt};
+
+use super::{lines::Line, Config, Error, Lines, Login, Result, Transport, Writer};
+use crate::{
+    irc,
+    tmi::{
+        parse::{self, CapabilitySubCmd, GlobalUserState},
+        write, Message,
+    },
+};
+
+/// Capabilities which the crate doesn't work without
+const REQUIRED_CAPABILITIES: &[&str] = &["twitch.tv/commands", "twitch.tv/tags"];
+const MEMBERSHIP_CAPABILITY: &str = "twitch.tv/membership";
+
+/// Who the bot is logged in as, as told by TMI during the handshake
+#[derive(Clone, Debug, PartialEq)]
+pub struct Identity {
+    /// The login TMI greeted the bot with, `justinfan<number>` for anonymous
+    /// logins
+    pub login: String,
+    /// Capabilities which TMI acknowledged
+    pub capabilities: Vec<String>,
+    /// The bot's global chat settings, `None` for anonymous logins
+    pub user_state: Option<GlobalUserState>,
+}
+
+impl Identity {
+    pub fn has_capability(&self, capability: &str) -> bool { self.capabilities.iter().any(|c| c == capability) }
+}
+
+/// Tracks the answers to the handshake, see the [module](self) docs
+#[derive(Debug)]
+struct Handshake {
+    /// Requested capabilities which weren't answered yet
+    pending: Vec<String>,
+    anonymous: bool,
+    /// `001` was received
+    welcomed: bool,
+    /// `376`, the end of the welcome numerics, was received
+    motd_done: bool,
+    identity: Identity,
+}
+
+impl Handshake {
+    fn new(capabilities: &[&str], anonymous: bool) -> Handshake {
+        Handshake {
+            pending: capabilities.iter().map(|c| c.to_string()).collect(),
+            anonymous,
+            welcomed: false,
+            motd_done: false,
+            identity: Identity {
+                login: String::new(),
+                capabilities: vec![],
+                user_state: None,
+            },
+        }
+    }
+
+    /// Applies the next message, returns `true` once the handshake is done
+    fn update(&mut self, message: Message) -> Result<bool> {
+        match message {
+            Message::Capability(capability) => {
+                for name in capability.which().split_whitespace() {
+                    self.pending.retain(|pending| pending != name);
+                    match capability.subcmd {
+                        CapabilitySubCmd::ACK => self.identity.capabilities.push(name.to_string()),
+                        CapabilitySubCmd::NAK if REQUIRED_CAPABILITIES.contains(&name) => {
+                            return Err(Error::CapabilityRejected(name.to_string()));
+                        }
+                        CapabilitySubCmd::NAK => log::warn!("Capability {} was rejected", name),
+                        CapabilitySubCmd::LS => (),
+                    }
+                }
+            }
+            Message::Unknown(message) => match &message.cmd {
+                irc::Command::Unknown(command) if command.as_str() == "001" => {
+                    self.welcomed = true;
+                    if let Some(params) = &message.params {
+                        let login = params.raw().split_whitespace().next().unwrap_or_default();
+                        self.identity.login = login.to_string();
+                    }
+                }
+                irc::Command::Unknown(command) if command.as_str() == "376" => self.motd_done = true,
+                _ => log::debug!("Ignored during handshake: {}", message.source),
+            },
+            // TMI doesn't tell failed logins apart with a msg-id
+            Message::Notice(notice) if notice.channel().is_none() && !self.welcomed => {
+                return Err(Error::AuthenticationFailed(notice.message().to_string()));
+            }
+            Message::GlobalUserState(state) => self.identity.user_state = Some(state),
+            other => log::debug!("Ignored during handshake: {:?}", other),
+        }
+        Ok(self.is_done())
+    }
+
+    fn is_done(&self) -> bool {
+        // GLOBALUSERSTATE is only sent to regular logins, with the commands
+        // capability
+        let user_state_done =
+            self.anonymous || self.identity.user_state.is_some() || !self.identity.has_capability("twitch.tv/commands");
+        self.pending.is_empty() && self.welcomed && self.motd_done && user_state_done
+    }
+}
+
+/// Connects to TMI, requests capabilities and authenticates
+pub(super) async fn handshake(config: &Config, transport: &dyn Transport) -> Result<(Lines, Writer, Identity)> {
+    log::debug!("Connecting to TMI");
+    let connection = tokio::time::timeout(config.connect_timeout, transport.connect())
+        .await
+        .or(Err(Error::Timeout))??;
+    let (reader, mut writer) = split(connection);
+    let mut reader = Lines::new(reader, config.max_line_length);
+    let mut buffer = String::with_capacity(512);
+
+    // 1. request capabilities, one at a time so that each one is ACK'd or
+    // NAK'd on its own
+    let mut capabilities = REQUIRED_CAPABILITIES.to_vec();
+    if config.membership_data {
+        capabilities.push(MEMBERSHIP_CAPABILITY);
+    }
+    log::debug!("Requesting capabilities: {}", capabilities.join(", "));
+    for capability in &capabilities {
+        write::cap_req(&mut buffer, capability)?;
+        writer.write_all(buffer.as_bytes()).await?;
+    }
+
+    // 2. authenticate
+    let anonymous = match &config.credentials {
+        Login::Anonymous => {
+            use rand::Rng;
+            let login = format!("justinfan{}", rand::thread_rng().gen_range(10000..99999));
+            log::debug!("Authenticating as {}", login);
+            // don't need PASS here
+            write::nick(&mut buffer, &login)?;
+            writer.write_all(buffer.as_bytes()).await?;
+            true
+        }
+        Login::Regular { login, token } => {
+            log::debug!("Authenticating as {}", login);
+            write::pass(&mut buffer, token)?;
+            writer.write_all(buffer.as_bytes()).await?;
+            write::nick(&mut buffer, login)?;
+            writer.write_all(buffer.as_bytes()).await?;
+            false
+        }
+    };
+    writer.flush().await?;
+
+    // 3. wait for the answers
+    let mut handshake = Handshake::new(&capabilities, anonymous);
+    let answers = async {
+        while let Some(line) = reader.next().await {
+            let line = match line? {
+                Line::Complete(line) => line,
+                Line::TooLong(_) => return Err(parse::Error::LineTooLong(config.max_line_length).into()),
+            };
+            if handshake.update(Message::parse(line)?)? {
+                return Ok(());
+            }
+        }
+        Err(Error::StreamClosed)
+    };
+    tokio::time::timeout(config.handshake_timeout, answers)
+        .await
+        .or(Err(Error::Timeout))??;
+    log::debug!("Connection successful, logged in as {}", handshake.identity.login);
+
+    Ok((reader, writer, handshake.identity))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(handshake: &mut Handshake, lines: &[&str]) -> Result<bool> {
+        let mut done = false;
+        for line in lines {
+            done = handshake.update(Message::parse(line.to_string()).unwrap())?;
+        }
+        Ok(done)
+    }
+
+    const WELCOME: &[&str] = &[
+        ":tmi.twitch.tv 001 bot :Welcome, GLHF!",
+        ":tmi.twitch.tv 002 bot :Your host is tmi.twitch.tv",
+        ":tmi.twitch.tv 375 bot :-",
+        ":tmi.twitch.tv 372 bot :You are in a maze of twisty passages, all alike.",
+        ":tmi.twitch.tv 376 bot :>",
+    ];
+
+    #[test]
+    fn regular_login_waits_for_globaluserstate() {
+        let mut handshake = Handshake::new(&["twitch.tv/commands", "twitch.tv/tags"], false);
+        assert!(!run(&mut handshake, WELCOME).unwrap());
+        // the ACKs may come in any order, and after the welcome
+        assert!(!run(&mut handshake, &[":tmi.twitch.tv CAP * ACK :twitch.tv/tags"]).unwrap());
+        assert!(!run(&mut handshake, &[":tmi.twitch.tv CAP * ACK :twitch.tv/commands"]).unwrap());
+        assert!(run(
+            &mut handshake,
+            &[
+                "@badge-info=;badges=;color=#0000FF;display-name=Bot;emote-sets=0;user-id=1;user-type= \
+               :tmi.twitch.tv GLOBALUSERSTATE"
+            ]
+        )
+        .unwrap());
+
+        let identity = handshake.identity;
+        assert_eq!(identity.login, "bot");
+        assert!(identity.has_capability("twitch.tv/commands"));
+        assert_eq!(identity.user_state.unwrap().user_id(), "1");
+    }
+
+    #[test]
+    fn optional_capability_may_be_rejected() {
+        let mut handshake = Handshake::new(&["twitch.tv/commands", "twitch.tv/tags", "twitch.tv/membership"], true);
+        run(
+            &mut handshake,
+            &[
+                ":tmi.twitch.tv CAP * ACK :twitch.tv/commands",
+                ":tmi.twitch.tv CAP * ACK :twitch.tv/tags",
+                ":tmi.twitch.tv CAP * NAK :twitch.tv/membership",
+            ],
+        )
+        .unwrap();
+        assert!(run(&mut handshake, WELCOME).unwrap());
+        assert!(!handshake.identity.has_capability("twitch.tv/membership"));
+
+        let mut handshake = Handshake::new(&["twitch.tv/commands", "twitch.tv/tags"], true);
+        assert!(matches!(
+            run(&mut handshake, &[":tmi.twitch.tv CAP * NAK :twitch.tv/tags"]),
+            Err(Error::CapabilityRejected(capability)) if capability == "twitch.tv/tags"
+        ));
+    }
+
+    #[test]
+    fn failed_login_is_reported() {
+        let mut handshake = Handshake::new(&["twitch.tv/commands", "twitch.tv/tags"], false);
+        assert!(matches!(
+            run(
+                &mut handshake,
+                &[
+                    ":tmi.twitch.tv CAP * ACK :twitch.tv/commands twitch.tv/tags",
+                    ":tmi.twitch.tv NOTICE * :Login authentication failed",
+                ]
+            ),
+            Err(Error::AuthenticationFailed(reason)) if reason == "Login authentication failed"
+        ));
+    }
+}
@@ -0,0 +1,153 @@
+//! Splits the incoming byte stream into lines
+//!
+//! Unlike [`AsyncBufReadExt::lines`], invalid UTF-8 doesn't fail the whole
+//! connection, it's replaced with `U+FFFD`, and a line can't grow without
+//! bounds, anything past the limit is dropped.
+//!
+//! [`AsyncBufReadExt::lines`]: tokio::io::AsyncBufReadExt::lines
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{ready, Stream};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// How much is read from the connection at once
+const CHUNK_SIZE: usize = 4096;
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Line {
+    Complete(String),
+    /// A line which was longer than the limit, only its start is kept
+    TooLong(String),
+}
+
+pub(crate) struct Lines<R> {
+    reader: R,
+    max_length: usize,
+    /// Bytes which were read, but not returned yet
+    buffer: Vec<u8>,
+    /// Dropping the rest of a line which was too long
+    discarding: bool,
+    eof: bool,
+}
+
+impl<R> Lines<R> {
+    /// Lines longer than `max_length` bytes, not counting the line ending,
+    /// are returned as [`Line::TooLong`]
+    pub(crate) fn new(reader: R, max_length: usize) -> Lines<R> {
+        Lines {
+            reader,
+            max_length,
+            buffer: Vec::new(),
+            discarding: false,
+            eof: false,
+        }
+    }
+}
+
+fn decode(line: &[u8]) -> String {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    String::from_utf8_lossy(line).into_owned()
+}
+
+impl<R: AsyncRead + Unpin> Stream for Lines<R> {
+    type Item = io::Result<Line>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<Line>>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(end) = this.buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = this.buffer.drain(..=end).collect();
+                if this.discarding {
+                    this.discarding = false;
+                    continue;
+                }
+                // `\r\n` doesn't count against the limit
+                if line.len() - 1 <= this.max_length + 1 {
+                    let line = decode(&line);
+                    if line.len() <= this.max_length {
+                        return Poll::Ready(Some(Ok(Line::Complete(line))));
+                    }
+                }
+                return Poll::Ready(Some(Ok(Line::TooLong(decode(&line[..this.max_length])))));
+            }
+            if this.discarding {
+                this.buffer.clear();
+            } else if this.buffer.len() > this.max_length + 1 {
+                let start = decode(&this.buffer[..this.max_length]);
+                this.buffer.clear();
+                this.discarding = true;
+                return Poll::Ready(Some(Ok(Line::TooLong(start))));
+            }
+            if this.eof {
+                // the last line may not be terminated
+                if this.buffer.is_empty() || this.discarding {
+                    return Poll::Ready(None);
+                }
+                let line = decode(&std::mem::take(&mut this.buffer));
+                return Poll::Ready(Some(Ok(Line::Complete(line))));
+            }
+
+            let mut chunk = [0u8; CHUNK_SIZE];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.reader).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                this.eof = true;
+            }
+            this.buffer.extend_from_slice(chunk.filled());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+
+    async fn read_all(input: &[u8], max_length: usize) -> Vec<Line> {
+        Lines::new(input, max_length).map(|line| line.unwrap()).collect().await
+    }
+
+    fn complete(line: &str) -> Line { Line::Complete(line.into()) }
+
+    #[tokio::test]
+    async fn line_endings() {
+        assert_eq!(
+            read_all(b"PING :a\r\nPING :b\n\r\nPING :c", 100).await,
+            vec![
+                complete("PING :a"),
+                complete("PING :b"),
+                complete(""),
+                complete("PING :c")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_utf8_is_replaced() {
+        assert_eq!(
+            read_all(b"PRIVMSG #forsen :a\xffb\r\nPING\r\n", 100).await,
+            vec![complete("PRIVMSG #forsen :a\u{fffd}b"), complete("PING")]
+        );
+    }
+
+    #[tokio::test]
+    async fn long_lines_are_cut() {
+        let long = format!("PING :{}\r\n", "a".repeat(CHUNK_SIZE * 3));
+        let input = format!("PING :12345\r\n{}PING :123456\r\nPING :b\r\n", long);
+        assert_eq!(
+            read_all(input.as_bytes(), 11).await,
+            vec![
+                complete("PING :12345"),
+                Line::TooLong("PING :aaaaa".into()),
+                Line::TooLong("PING :12345".into()),
+                complete("PING :b"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,1414 @@
+pub mod confirm;
+pub mod dispatch;
+mod handshake;
+mod lines;
+pub mod pool;
+pub mod queue;
+pub mod rate;
+pub mod record;
+pub mod state;
+pub mod transport;
+mod websocket;
+
+use std::{
+    collections::HashSet,
+    pin::Pin,
+    sync::{Arc, Weak},
+    task::{Context, Poll},
+    time::Duration as StdDuration,
+};
+
+use chrono::Duration;
+pub use confirm::Confirmation;
+use confirm::{Command, Pending};
+pub use dispatch::{Dispatcher, Subscription};
+use futures::{future::BoxFuture, ready, StreamExt};
+use handshake::handshake;
+pub use handshake::Identity;
+use lines::Line;
+pub use queue::{Delivery, Priority};
+use queue::{Limit, Queue};
+pub use rate::{Limits, RateLimiter};
+pub use record::{Recorder, Recording, Replay};
+pub use state::ChannelState;
+use thiserror::Error;
+use tmi::write;
+use tokio::{
+    io::{AsyncWriteExt, ReadHalf, WriteHalf},
+    sync::watch,
+    time::Instant,
+};
+pub use transport::Transport;
+use transport::{Endpoint, Stream};
+pub use write::Mode;
+
+use crate::{
+    irc,
+    tmi::{self, parse::NoticeId, Message},
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Login {
+    Anonymous,
+    Regular { login: String, token: String },
+}
+
+impl Default for Login {
+    fn default() -> Self { Login::Anonymous }
+}
+
+/// How to reconnect after the connection is lost
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Reconnect {
+    /// Delay before the second attempt, the first one is immediate. Doubled
+    /// after each failed attempt.
+    pub initial_delay: StdDuration,
+    /// Upper bound for the delay between two attempts
+    pub max_delay: StdDuration,
+    /// `None` = keep trying forever
+    pub max_attempts: Option<u32>,
+}
+
+impl Reconnect {
+    /// Delay before the zero-based `attempt`, with jitter
+    fn delay(&self, attempt: u32) -> StdDuration {
+        if attempt == 0 {
+            return StdDuration::from_secs(0);
+        }
+        let delay = self
+            .initial_delay
+            .checked_mul(1 << std::cmp::min(attempt - 1, 16))
+            .map(|delay| std::cmp::min(delay, self.max_delay))
+            .unwrap_or(self.max_delay);
+        // keep at least half of the delay, randomize the rest
+        let half = delay / 2;
+        half + half.mul_f64(rand::random::<f64>())
+    }
+}
+
+/// When to check whether the connection is still alive
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Keepalive {
+    /// Send a PING once nothing was read for this long
+    pub interval: StdDuration,
+    /// Consider the connection dead if nothing is read for this long after
+    /// the PING
+    pub timeout: StdDuration,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Keepalive {
+            interval: StdDuration::from_secs(60),
+            timeout: StdDuration::from_secs(20),
+        }
+    }
+}
+
+impl Default for Reconnect {
+    fn default() -> Self {
+        Reconnect {
+            initial_delay: StdDuration::from_secs(1),
+            max_delay: StdDuration::from_secs(60),
+            max_attempts: None,
+        }
+    }
+}
+
+/// What the [`Reader`] does with a line which fails to parse
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OnMalformed {
+    /// Return [`Error::Parse`], the connection stays usable
+    Error,
+    /// Return [`Message::Malformed`]
+    Message,
+    /// Log the line and read the next one
+    Log,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub membership_data: bool,
+    pub credentials: Login,
+    pub rate_limits: Limits,
+    /// `None` = don't reconnect, [`Reader::next`] returns an error once the
+    /// connection is lost
+    pub reconnect: Option<Reconnect>,
+    /// `None` = only answer PINGs from TMI, a connection which silently
+    /// stalls is never detected
+    pub keepalive: Option<Keepalive>,
+    /// Maximum number of channels joined over a single connection, only used
+    /// by [`pool::connect`]
+    pub channels_per_connection: usize,
+    /// Where to connect, only used by [`connect`] and [`pool::connect`]
+    pub endpoint: Endpoint,
+    /// Messages with more characters are split into multiple messages
+    pub max_message_length: usize,
+    /// Maximum number of messages a single message may be split into, any
+    /// parts after that are dropped. `None` = no limit
+    pub max_message_parts: Option<usize>,
+    /// Messages below [`Priority::High`] which couldn't be sent within this
+    /// time are dropped. `None` = keep them until they're sent
+    pub max_queue_time: Option<StdDuration>,
+    /// Maximum number of messages below [`Priority::High`] waiting to be
+    /// sent, queueing more waits until one is sent. `None` = no limit
+    pub max_queued: Option<usize>,
+    /// How long a [`Confirmation`] waits for Twitch to confirm a command,
+    /// counted from when the command is queued
+    pub confirmation_timeout: StdDuration,
+    /// How long to wait for the transport to connect
+    pub connect_timeout: StdDuration,
+    /// How long to wait for TMI to finish the handshake once connected
+    pub handshake_timeout: StdDuration,
+    /// Records every line read from TMI, see [`record`]. `None` = don't record
+    pub record: Option<Recording>,
+    /// Lines from TMI with more bytes are cut, and fail to parse with
+    /// [`parse::Error::LineTooLong`](tmi::parse::Error::LineTooLong)
+    pub max_line_length: usize,
+    pub on_malformed: OnMalformed,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            membership_data: false,
+            credentials: Login::default(),
+            rate_limits: Limits::default(),
+            reconnect: Some(Reconnect::default()),
+            keepalive: Some(Keepalive::default()),
+            channels_per_connection: 50,
+            endpoint: Endpoint::default(),
+            max_message_length: write::MAX_MESSAGE_LENGTH,
+            max_message_parts: None,
+            max_queue_time: None,
+            max_queued: Some(256),
+            confirmation_timeout: StdDuration::from_secs(15),
+            connect_timeout: StdDuration::from_secs(5),
+            handshake_timeout: StdDuration::from_secs(10),
+            record: None,
+            max_line_length: 32 * 1024,
+            on_malformed: OnMalformed::Error,
+        }
+    }
+}
+
+#[allow(clippy::clippy::upper_case_acronyms)]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Connection to Twitch IRC server failed")]
+    ConnectionFailed,
+    #[error("Encountered an I/O error: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("Encountered an error while parsing: {0}")]
+    Parse(#[from] tmi::parse::Error),
+    #[error(transparent)]
+    Generic(#[from] anyhow::Error),
+    #[error("Timed out")]
+    Timeout,
+    #[error("Stream closed")]
+    StreamClosed,
+    #[error("Internal buffer is not large enough for message")]
+    Formatting(#[from] std::fmt::Error),
+    #[error("Failed to join channel: {0:?}")]
+    JoinFailed(tmi::parse::NoticeId),
+    #[error("Command failed: {0:?}")]
+    CommandFailed(tmi::parse::NoticeId),
+    #[error("Authentication failed: {0}")]
+    AuthenticationFailed(String),
+    #[error("Capability was rejected: {0}")]
+    CapabilityRejected(String),
+    #[error("Message was dropped before it was sent")]
+    Dropped,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+type Lines = lines::Lines<ReadHalf<Box<dyn Stream>>>;
+type Writer = WriteHalf<Box<dyn Stream>>;
+
+/// State shared by the [`Sender`] and [`Reader`] of one connection, which
+/// outlives the underlying TLS stream in case of a reconnect.
+struct Shared {
+    config: Config,
+    transport: Arc<dyn Transport>,
+    rate: RateLimiter,
+    /// Channels which should be rejoined after reconnecting
+    channels: std::sync::Mutex<HashSet<String>>,
+    /// Writer of a new connection, until the writer task picks it up
+    writer: std::sync::Mutex<Option<Writer>>,
+    /// Bumped after a reconnect left its writer in `writer`
+    connected: watch::Sender<u64>,
+    /// Messages waiting to be written by the writer task
+    queue: Arc<Queue>,
+    /// Shared by all clones of the [`Sender`], so that they alternate
+    smb: std::sync::Mutex<write::SameMessageBypass>,
+    /// Commands waiting to be confirmed by a message read by the [`Reader`]
+    pending: Pending,
+    /// Who the bot is logged in as, updated on every reconnect
+    identity: std::sync::Mutex<Identity>,
+    recorder: Option<Recorder>,
+    /// Round-trip time of the last PING sent by the [`Reader`]
+    latency: std::sync::Mutex<Option<StdDuration>>,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) { self.queue.close() }
+}
+
+impl Shared {
+    /// Whether `nick` is the bot's own, as TMI greeted it in the last handshake
+    fn is_bot(&self, nick: &str) -> bool { self.identity.lock().unwrap().login.eq_ignore_ascii_case(nick) }
+    /// Establishes a new connection, and joins all the channels which were
+    /// joined on the previous one.
+    async fn reconnect(&self) -> Result<(Lines, Writer)> {
+        let (reader, mut writer, identity) = handshake(&self.config, &*self.transport).await?;
+        self.rate.channels().set_login(&identity.login);
+        *self.identity.lock().unwrap() = identity;
+        let channels = self.channels.lock().unwrap().iter().cloned().collect::<Vec<_>>();
+        let mut buffer = String::with_capacity(512);
+        for channel in channels {
+            log::info!("Rejoining {}", channel);
+            write::join(&mut buffer, &channel)?;
+            self.rate.until_ready(rate::Target::Join).await;
+            writer.write_all(buffer.as_bytes()).await?;
+            writer.flush().await?;
+        }
+        Ok((reader, writer))
+    }
+}
+
+/// Reads messages from one connection.
+///
+/// Also a [`futures::Stream`] of messages, which ends after the first error
+/// which makes the connection unusable.
+pub struct Reader {
+    shared: Arc<Shared>,
+    /// `None` while a read is in progress
+    state: Option<ReadState>,
+    /// The read in progress, which hands the state back once it's done
+    pending: Option<BoxFuture<'static, (ReadState, Result<Message>)>>,
+    closed: bool,
+}
+struct ReadState {
+    stream: Lines,
+    shared: Arc<Shared>,
+    reconnect_pending: bool,
+    /// When the last line was read
+    last_read: Instant,
+    /// The argument of the last PING sent, and when it was sent, until it's
+    /// answered
+    ping: Option<(String, Instant)>,
+}
+impl Reader {
+    fn new(stream: Lines, shared: Arc<Shared>) -> Reader {
+        Reader {
+            state: Some(ReadState {
+                stream,
+                shared: shared.clone(),
+                reconnect_pending: false,
+                last_read: Instant::now(),
+                ping: None,
+            }),
+            shared,
+            pending: None,
+            closed: false,
+        }
+    }
+    /// State of the joined channels, as of the last message read
+    pub fn channels(&self) -> &ChannelState { self.shared.rate.channels() }
+    /// Who the bot is logged in as, see [`Sender::identity`]
+    pub fn identity(&self) -> Identity { self.shared.identity.lock().unwrap().clone() }
+    /// Round-trip time of the last keepalive PING, `None` until one is
+    /// answered, see [`Config::keepalive`]
+    pub fn latency(&self) -> Option<StdDuration> { *self.shared.latency.lock().unwrap() }
+    /// Reads the next message.
+    ///
+    /// In case the connection is lost, or Twitch asks us to reconnect, this
+    /// transparently reconnects (if configured to), rejoins all channels, and
+    /// then returns [`Message::Reconnected`]. The [`Sender`] of this
+    /// connection keeps working afterwards.
+    ///
+    /// Cancelling this doesn't lose a message, the next call resumes the
+    /// same read.
+    pub async fn next(&mut self) -> Result<Message> { futures::future::poll_fn(|cx| self.poll_message(cx)).await }
+
+    fn poll_message(&mut self, cx: &mut Context<'_>) -> Poll<Result<Message>> {
+        if self.closed {
+            return Poll::Ready(Err(Error::StreamClosed));
+        }
+        let state = &mut self.state;
+        let pending = self.pending.get_or_insert_with(|| {
+            let mut state = state.take().expect("There is no read in progress");
+            Box::pin(async move {
+                let message = state.next().await;
+                (state, message)
+            })
+        });
+        let (state, message) = ready!(pending.as_mut().poll(cx));
+        self.pending = None;
+        self.state = Some(state);
+        // the line was consumed, the connection is still usable
+        self.closed = matches!(message, Err(ref err) if !matches!(err, Error::Parse(_)));
+        Poll::Ready(message)
+    }
+}
+impl futures::Stream for Reader {
+    type Item = Result<Message>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.closed {
+            return Poll::Ready(None);
+        }
+        self.poll_message(cx).map(Some)
+    }
+}
+impl ReadState {
+    async fn next(&mut self) -> Result<Message> {
+        while !self.reconnect_pending {
+            let can_reconnect = self.shared.config.reconnect.is_some();
+            match self.read_line().await {
+                Some(Ok(line)) => match self.parse(line)? {
+                    Some(message) => return Ok(self.handle(message)),
+                    None => continue,
+                },
+                Some(Err(err)) if !can_reconnect => return Err(err),
+                None if !can_reconnect => return Err(Error::StreamClosed),
+                Some(Err(err)) => log::error!("Connection lost: {}", err),
+                None => log::error!("Connection closed"),
+            }
+            self.reconnect_pending = true;
+        }
+        self.reconnect().await?;
+        self.reconnect_pending = false;
+        Ok(Message::Reconnected)
+    }
+
+    /// Parses a line, one which fails to parse is handled according to
+    /// [`Config::on_malformed`]. `None` = the line was skipped.
+    fn parse(&self, line: Line) -> Result<Option<Message>> {
+        let on_malformed = self.shared.config.on_malformed;
+        let (raw, result) = match line {
+            Line::Complete(line) => {
+                log::debug!("{}", line);
+                if let Some(recorder) = &self.shared.recorder {
+                    recorder.record(&line);
+                }
+                // only kept around if it's needed in case of an error
+                let raw = match on_malformed {
+                    OnMalformed::Error => String::new(),
+                    _ => line.clone(),
+                };
+                (raw, Message::parse(line))
+            }
+            Line::TooLong(start) => (
+                start,
+                Err(tmi::parse::Error::LineTooLong(self.shared.config.max_line_length)),
+            ),
+        };
+        match result {
+            Ok(message) => Ok(Some(message)),
+            Err(error) => match on_malformed {
+                OnMalformed::Error => Err(error.into()),
+                OnMalformed::Message => Ok(Some(Message::Malformed { raw, error })),
+                OnMalformed::Log => {
+                    log::warn!("Skipped a malformed message ({}): {}", error, raw);
+                    Ok(None)
+                }
+            },
+        }
+    }
+
+    /// Updates the connection's state with a message which was just read
+    fn handle(&mut self, message: Message) -> Message {
+        self.shared.rate.update(&message);
+        self.shared.pending.resolve(&message);
+        if let Message::Part(part) = &message {
+            if self.shared.is_bot(part.nick()) {
+                self.shared.channels.lock().unwrap().remove(part.channel());
+                self.shared.rate.channels().remove(part.channel());
+            }
+        }
+        if let Message::GlobalUserState(state) = &message {
+            self.shared.identity.lock().unwrap().user_state = Some(state.clone());
+        }
+        if let Message::Pong(pong) = &message {
+            self.answered(pong.arg());
+        }
+        if let Message::Reconnect(_) = message {
+            // let the caller see the RECONNECT first
+            self.reconnect_pending = self.shared.config.reconnect.is_some();
+        }
+        message
+    }
+
+    /// Reads the next line, and sends a PING once the connection is idle.
+    ///
+    /// Fails with [`Error::Timeout`] if the PING isn't followed by any
+    /// traffic in time, see [`Config::keepalive`].
+    async fn read_line(&mut self) -> Option<Result<Line>> {
+        let keepalive = match self.shared.config.keepalive {
+            Some(keepalive) => keepalive,
+            None => return self.stream.next().await.map(|line| line.map_err(Error::from)),
+        };
+        loop {
+            let deadline = match &self.ping {
+                // nothing was read since the PING
+                Some((_, sent)) if *sent >= self.last_read => *sent + keepalive.timeout,
+                _ => self.last_read + keepalive.interval,
+            };
+            match tokio::time::timeout_at(deadline, self.stream.next()).await {
+                Ok(line) => {
+                    self.last_read = Instant::now();
+                    return line.map(|line| line.map_err(Error::from));
+                }
+                Err(_) if matches!(&self.ping, Some((_, sent)) if *sent >= self.last_read) => {
+                    return Some(Err(Error::Timeout));
+                }
+                Err(_) => {
+                    let arg = rand::random::<u32>().to_string();
+                    let mut buffer = String::with_capacity(32);
+                    if let Err(err) = write::ping(&mut buffer, &arg) {
+                        return Some(Err(err.into()));
+                    }
+                    log::debug!("Queued message: {}", buffer.trim_end());
+                    self.shared.queue.push(Priority::Urgent, Limit::None, buffer).await;
+                    self.ping = Some((arg, Instant::now()));
+                }
+            }
+        }
+    }
+
+    /// Measures the latency if `arg` is the one of the last PING sent
+    fn answered(&mut self, arg: Option<&str>) {
+        if let Some((sent_arg, sent)) = &self.ping {
+            if arg == Some(sent_arg.as_str()) {
+                *self.shared.latency.lock().unwrap() = Some(sent.elapsed());
+                self.ping = None;
+            }
+        }
+    }
+
+    async fn reconnect(&mut self) -> Result<()> {
+        let policy = self.shared.config.reconnect.unwrap_or_default();
+        let mut attempt = 0;
+        loop {
+            tokio::time::sleep(policy.delay(attempt)).await;
+            attempt += 1;
+            log::info!("Reconnecting (attempt #{})", attempt);
+            match self.shared.reconnect().await {
+                Ok((stream, writer)) => {
+                    *self.shared.writer.lock().unwrap() = Some(writer);
+                    let connection = *self.shared.connected.borrow();
+                    let _ = self.shared.connected.send(connection + 1);
+                    self.stream = stream;
+                    self.last_read = Instant::now();
+                    self.ping = None;
+                    return Ok(());
+                }
+                Err(err) => {
+                    log::error!("Failed to reconnect: {}", err);
+                    if policy.max_attempts.map(|max| attempt >= max).unwrap_or(false) {
+                        return Err(err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Size of the buffer which outgoing messages are written into, enough for a
+/// message of [`write::MAX_MESSAGE_LENGTH`] 4-byte characters
+const BUFFER_CAPACITY: usize = 4096;
+
+fn new_buffer() -> String { String::with_capacity(BUFFER_CAPACITY) }
+
+/// Whether `nick` is the bot's own, `false` once the connection is gone
+fn is_bot(shared: &Weak<Shared>, nick: &str) -> bool { shared.upgrade().map_or(false, |shared| shared.is_bot(nick)) }
+
+/// Sends messages over one connection.
+///
+/// Messages are only queued, and written in order of their [`Priority`] once
+/// the rate limits allow, see [`queue`]. The methods return as soon as the
+/// message is queued, with a [`Delivery`] which resolves once it's written.
+/// They wait for room if the queue is full, see [`Config::max_queued`].
+///
+/// Cloning a `Sender` is cheap, all clones queue to the same connection.
+#[derive(Clone)]
+pub struct Sender {
+    shared: Arc<Shared>,
+}
+impl Sender {
+    fn new(shared: Arc<Shared>) -> Sender { Sender { shared } }
+    /// Who the bot is logged in as, as of the last handshake.
+    ///
+    /// The [`GlobalUserState`](tmi::parse::GlobalUserState) is also updated
+    /// whenever the [`Reader`] reads a new one.
+    pub fn identity(&self) -> Identity { self.shared.identity.lock().unwrap().clone() }
+    /// Round-trip time of the last keepalive PING, see [`Reader::latency`]
+    pub fn latency(&self) -> Option<StdDuration> { *self.shared.latency.lock().unwrap() }
+    /// Splits `message` into the parts which are sent as separate messages
+    fn split<'a>(&self, message: &'a str) -> Vec<&'a str> {
+        let config = &self.shared.config;
+        // leave room for the same message bypass
+        let limit = std::cmp::max(config.max_message_length.saturating_sub(1), 1);
+        let mut parts = write::split(message, limit);
+        if let Some(max) = config.max_message_parts {
+            if parts.len() > max {
+                log::warn!("Message was split into {} parts, only sending {}", parts.len(), max);
+                parts.truncate(max);
+            }
+        }
+        parts
+    }
+    /// Queues `line`, and returns its [`Delivery`]
+    async fn queue(&self, line: String, priority: Priority, limit: Limit) -> Result<Delivery> {
+        Ok(Delivery::new(vec![self.shared.queue.push(priority, limit, line).await]))
+    }
+    /// State of the joined channels, as of the last message read by the
+    /// [`Reader`]
+    pub fn channels(&self) -> &ChannelState { self.shared.rate.channels() }
+    /// Removes all messages to `channel` which weren't sent yet, returns how
+    /// many were removed
+    pub fn purge(&self, channel: &str) -> usize { self.shared.queue.purge(channel) }
+    /// Number of messages which weren't sent yet
+    pub fn queued(&self) -> usize { self.shared.queue.len() }
+    /// Number of messages to `channel` which weren't sent yet
+    pub fn queued_in(&self, channel: &str) -> usize { self.shared.queue.len_in(channel) }
+    /// Sends a raw `message` to twitch.
+    ///
+    /// `message` must be terminated with `\r\n`. It counts against the global
+    /// message rate limit.
+    ///
+    /// Use at your own risk.
+    pub async fn send(&self, message: &str) -> Result<Delivery> {
+        log::debug!("Queued message: {}", message.trim_end());
+        self.queue(message.to_string(), Priority::Normal, Limit::Global).await
+    }
+    /// Sends an IRC `message`, e.g. one built with [`irc::Message::build`].
+    ///
+    /// It counts against the global message rate limit.
+    pub async fn send_message(&self, message: &irc::Message) -> Result<Delivery> {
+        self.send(&format!("{}\r\n", message)).await
+    }
+    pub async fn pong(&self, arg: Option<&str>) -> Result<Delivery> {
+        let mut buffer = new_buffer();
+        write::pong(&mut buffer, arg)?;
+        log::debug!("Queued message: {}", buffer.trim_end());
+        self.queue(buffer, Priority::Urgent, Limit::None).await
+    }
+    /// Sends a capability request
+    pub async fn cap(&self, with_membership: bool) -> Result<Delivery> {
+        let mut buffer = new_buffer();
+        write::cap(&mut buffer, with_membership)?;
+        log::debug!("Queued message: {}", buffer.trim_end());
+        self.queue(buffer, Priority::Urgent, Limit::None).await
+    }
+    /// Sends a `PASS oauth:<token>` message
+    pub async fn pass(&self, token: &str) -> Result<Delivery> {
+        let mut buffer = new_buffer();
+        write::pass(&mut buffer, token)?;
+        log::debug!("Queued message: PASS oauth:<...>");
+        self.queue(buffer, Priority::Urgent, Limit::None).await
+    }
+    /// Sends a `NICK <login>` message
+    pub async fn nick(&self, login: &str) -> Result<Delivery> {
+        let mut buffer = new_buffer();
+        write::nick(&mut buffer, login)?;
+        log::debug!("Queued message: {}", buffer.trim_end());
+        self.queue(buffer, Priority::Urgent, Limit::None).await
+    }
+    /// Join `channel`
+    ///
+    /// The channel is rejoined automatically after a reconnect.
+    pub async fn join(&self, channel: &str) -> Result<Delivery> {
+        let mut buffer = new_buffer();
+        write::join(&mut buffer, channel)?;
+        self.shared.channels.lock().unwrap().insert(channel.to_string());
+        log::debug!("Queued message: {}", buffer.trim_end());
+        self.queue(buffer, Priority::Normal, Limit::Join).await
+    }
+    /// Leave `channel`
+    ///
+    /// The channel is forgotten, and isn't rejoined anymore, once Twitch
+    /// echoes the PART.
+    pub async fn part(&self, channel: &str) -> Result<Delivery> {
+        let mut buffer = new_buffer();
+        write::part(&mut buffer, channel)?;
+        log::debug!("Queued message: {}", buffer.trim_end());
+        self.queue(buffer, Priority::Normal, Limit::None).await
+    }
+    /// Join `channel`, like [`Sender::join`], and wait for Twitch to confirm
+    /// it with the JOIN echo or the channel's ROOMSTATE.
+    ///
+    /// The confirmation fails with [`Error::JoinFailed`] if the bot is banned
+    /// from the channel, or the channel is suspended or doesn't exist.
+    pub async fn join_confirmed(&self, channel: &str) -> Result<Confirmation<()>> {
+        let shared = Arc::downgrade(&self.shared);
+        let name = channel.to_string();
+        let confirmation = self
+            .shared
+            .pending
+            .wait(self.shared.config.confirmation_timeout, move |message| match message {
+                Message::RoomState(state) if state.channel().eq_ignore_ascii_case(&name) => Some(Ok(())),
+                Message::Join(join) if join.channel().eq_ignore_ascii_case(&name) && is_bot(&shared, join.nick()) => {
+                    Some(Ok(()))
+                }
+                Message::Notice(notice) if notice.channel().map(|c| c.eq_ignore_ascii_case(&name)) == Some(true) => {
+                    match &notice.id {
+                        Some(id)
+                            if matches!(
+                                id,
+                                NoticeId::MsgBanned | NoticeId::MsgChannelSuspended | NoticeId::TosBan
+                            ) =>
+                        {
+                            Some(Err(Error::JoinFailed(id.clone())))
+                        }
+                        _ => None,
+                    }
+                }
+                _ => None,
+            });
+        self.join(channel).await?;
+        Ok(confirmation)
+    }
+    /// Leave `channel`, like [`Sender::part`], and wait for Twitch to confirm
+    /// it with the PART echo
+    pub async fn part_confirmed(&self, channel: &str) -> Result<Confirmation<()>> {
+        let shared = Arc::downgrade(&self.shared);
+        let name = channel.to_string();
+        let confirmation = self
+            .shared
+            .pending
+            .wait(self.shared.config.confirmation_timeout, move |message| match message {
+                Message::Part(part) if part.channel().eq_ignore_ascii_case(&name) && is_bot(&shared, part.nick()) => {
+                    Some(Ok(()))
+                }
+                _ => None,
+            });
+        self.part(channel).await?;
+        Ok(confirmation)
+    }
+    /// Sends `message` to `channel`
+    ///
+    /// Long messages are split into multiple ones, see
+    /// [`Config::max_message_length`].
+    pub async fn privmsg(&self, channel: &str, message: &str) -> Result<Delivery> {
+        self.privmsg_with_priority(channel, message, Priority::Normal).await
+    }
+    /// Sends `message` to `channel` with a `priority` other than the default
+    /// [`Priority::Normal`], e.g. [`Priority::Low`] for timers
+    pub async fn privmsg_with_priority(&self, channel: &str, message: &str, priority: Priority) -> Result<Delivery> {
+        let mut parts = vec![];
+        for part in self.split(message) {
+            let mut buffer = new_buffer();
+            write::privmsg(&mut buffer, channel, &mut self.shared.smb.lock().unwrap(), part)?;
+            log::debug!("Queued message: {}", buffer.trim_end());
+            parts.push(
+                self.shared
+                    .queue
+                    .push(priority, Limit::Channel(channel.into()), buffer)
+                    .await,
+            );
+        }
+        Ok(Delivery::new(parts))
+    }
+    /// Sends `message` to `channel` as a reply to the message with the id
+    /// `parent`, see [`Privmsg::id`](crate::Privmsg::id)
+    ///
+    /// Long messages are split into multiple replies.
+    pub async fn reply(&self, channel: &str, parent: &str, message: &str) -> Result<Delivery> {
+        let mut parts = vec![];
+        for part in self.split(message) {
+            let mut buffer = new_buffer();
+            write::reply(&mut buffer, channel, parent, &mut self.shared.smb.lock().unwrap(), part)?;
+            log::debug!("Queued message: {}", buffer.trim_end());
+            parts.push(
+                self.shared
+                    .queue
+                    .push(Priority::Normal, Limit::Channel(channel.into()), buffer)
+                    .await,
+            );
+        }
+        Ok(Delivery::new(parts))
+    }
+    /// Send `message` directly to `user`
+    ///
+    /// Long messages are split into multiple whispers.
+    pub async fn whisper(&self, user: &str, message: &str) -> Result<Delivery> {
+        let mut parts = vec![];
+        for part in self.split(message) {
+            let mut buffer = new_buffer();
+            write::whisper(&mut buffer, user, part)?;
+            log::debug!("Queued message: {}", buffer.trim_end());
+            parts.push(self.shared.queue.push(Priority::Normal, Limit::Whisper, buffer).await);
+        }
+        Ok(Delivery::new(parts))
+    }
+    /// Equivalent to `/me <message>`
+    pub async fn me(&self, channel: &str, message: &str) -> Result<Delivery> {
+        let mut parts = vec![];
+        for part in self.split(message) {
+            let mut buffer = new_buffer();
+            write::me(&mut buffer, channel, part)?;
+            log::debug!("Queued message: {}", buffer.trim_end());
+            parts.push(
+                self.shared
+                    .queue
+                    .push(Priority::Normal, Limit::Channel(channel.into()), buffer)
+                    .await,
+            );
+        }
+        Ok(Delivery::new(parts))
+    }
+    /// Queues the moderation `command` in `buffer`, and waits for Twitch's
+    /// answer
+    async fn moderate(&self, buffer: String, channel: &str, command: Command) -> Result<Confirmation<()>> {
+        let name = channel.to_string();
+        let confirmation = self
+            .shared
+            .pending
+            .wait(self.shared.config.confirmation_timeout, move |message| {
+                command.outcome(&name, message)
+            });
+        log::debug!("Queued message: {}", buffer.trim_end());
+        self.queue(buffer, Priority::High, Limit::Channel(channel.into()))
+            .await?;
+        Ok(confirmation)
+    }
+    /// Clears chat in `channel`
+    ///
+    /// Like all moderation commands, this returns a [`Confirmation`] which
+    /// fails with [`Error::CommandFailed`] if Twitch refuses the command,
+    /// e.g. with [`NoticeId::NoPermission`].
+    pub async fn clear(&self, channel: &str) -> Result<Confirmation<()>> {
+        let mut buffer = new_buffer();
+        write::clear(&mut buffer, channel)?;
+        self.moderate(buffer, channel, Command::Clear).await
+    }
+    /// Timeout `user` in `channel` for `duration`
+    ///
+    /// Maximum timeout is 2 weeks. In case `duration` is `None`, default is 10
+    /// minutes.
+    pub async fn timeout(&self, channel: &str, user: &str, duration: Option<Duration>) -> Result<Confirmation<()>> {
+        let mut buffer = new_buffer();
+        write::timeout(&mut buffer, channel, user, duration)?;
+        self.moderate(buffer, channel, Command::Timeout).await
+    }
+    /// Removes `user`'s timeout in `channel`
+    pub async fn untimeout(&self, channel: &str, user: &str) -> Result<Confirmation<()>> {
+        let mut buffer = new_buffer();
+        write::untimeout(&mut buffer, channel, user)?;
+        self.moderate(buffer, channel, Command::Untimeout).await
+    }
+    /// Ban `user` in `channel`
+    pub async fn ban(&self, channel: &str, user: &str) -> Result<Confirmation<()>> {
+        let mut buffer = new_buffer();
+        write::ban(&mut buffer, channel, user)?;
+        self.moderate(buffer, channel, Command::Ban).await
+    }
+    /// Unban `user` in `channel`
+    pub async fn unban(&self, channel: &str, user: &str) -> Result<Confirmation<()>> {
+        let mut buffer = new_buffer();
+        write::unban(&mut buffer, channel, user)?;
+        self.moderate(buffer, channel, Command::Unban).await
+    }
+    /// For changing the room state, e.g. slow mode, emote-only mode, etc.
+    pub async fn roomstate(&self, channel: &str, mode: Mode, state: bool) -> Result<Confirmation<()>> {
+        let mut buffer = new_buffer();
+        write::roomstate(&mut buffer, channel, mode, state)?;
+        self.moderate(buffer, channel, Command::RoomMode).await
+    }
+    /// Deletes the message with the id `message_id` in `channel`
+    pub async fn delete(&self, channel: &str, message_id: &str) -> Result<Confirmation<()>> {
+        let mut buffer = new_buffer();
+        write::delete(&mut buffer, channel, message_id)?;
+        self.moderate(buffer, channel, Command::Delete).await
+    }
+    /// Makes `user` a moderator of `channel`
+    pub async fn mod_user(&self, channel: &str, user: &str) -> Result<Confirmation<()>> {
+        let mut buffer = new_buffer();
+        write::mod_user(&mut buffer, channel, user)?;
+        self.moderate(buffer, channel, Command::Mod).await
+    }
+    /// Removes `user` from the moderators of `channel`
+    pub async fn unmod_user(&self, channel: &str, user: &str) -> Result<Confirmation<()>> {
+        let mut buffer = new_buffer();
+        write::unmod_user(&mut buffer, channel, user)?;
+        self.moderate(buffer, channel, Command::Unmod).await
+    }
+    /// Makes `user` a VIP of `channel`
+    pub async fn vip(&self, channel: &str, user: &str) -> Result<Confirmation<()>> {
+        let mut buffer = new_buffer();
+        write::vip(&mut buffer, channel, user)?;
+        self.moderate(buffer, channel, Command::Vip).await
+    }
+    /// Removes `user` from the VIPs of `channel`
+    pub async fn unvip(&self, channel: &str, user: &str) -> Result<Confirmation<()>> {
+        let mut buffer = new_buffer();
+        write::unvip(&mut buffer, channel, user)?;
+        self.moderate(buffer, channel, Command::Unvip).await
+    }
+    /// Raids `target` from `channel`
+    ///
+    /// Twitch only answers a failed raid, so unlike the other commands this
+    /// isn't confirmed.
+    pub async fn raid(&self, channel: &str, target: &str) -> Result<Delivery> {
+        let mut buffer = new_buffer();
+        write::raid(&mut buffer, channel, target)?;
+        log::debug!("Queued message: {}", buffer.trim_end());
+        self.queue(buffer, Priority::High, Limit::Channel(channel.into())).await
+    }
+    /// Cancels the raid in progress in `channel`
+    pub async fn unraid(&self, channel: &str) -> Result<Confirmation<()>> {
+        let mut buffer = new_buffer();
+        write::unraid(&mut buffer, channel)?;
+        self.moderate(buffer, channel, Command::Unraid).await
+    }
+    /// Runs a commercial in `channel`, for 30 seconds if `length` is `None`
+    pub async fn commercial(&self, channel: &str, length: Option<Duration>) -> Result<Confirmation<()>> {
+        let mut buffer = new_buffer();
+        write::commercial(&mut buffer, channel, length)?;
+        self.moderate(buffer, channel, Command::Commercial).await
+    }
+    /// Adds a stream marker in `channel`, which isn't confirmed by Twitch
+    pub async fn marker(&self, channel: &str, description: Option<&str>) -> Result<Delivery> {
+        let mut buffer = new_buffer();
+        write::marker(&mut buffer, channel, description)?;
+        log::debug!("Queued message: {}", buffer.trim_end());
+        self.queue(buffer, Priority::Normal, Limit::Channel(channel.into()))
+            .await
+    }
+    /// Sends `message` to `channel` as a highlighted announcement
+    ///
+    /// Long messages are split into multiple announcements.
+    pub async fn announce(&self, channel: &str, message: &str) -> Result<Delivery> {
+        let mut parts = vec![];
+        for part in self.split(message) {
+            let mut buffer = new_buffer();
+            write::announce(&mut buffer, channel, part)?;
+            log::debug!("Queued message: {}", buffer.trim_end());
+            parts.push(
+                self.shared
+                    .queue
+                    .push(Priority::Normal, Limit::Channel(channel.into()), buffer)
+                    .await,
+            );
+        }
+        Ok(Delivery::new(parts))
+    }
+    /// Changes the bot's name color, the command is sent to `channel`
+    pub async fn color(&self, channel: &str, color: &str) -> Result<Confirmation<()>> {
+        let mut buffer = new_buffer();
+        write::color(&mut buffer, channel, color)?;
+        self.moderate(buffer, channel, Command::Color).await
+    }
+}
+
+pub struct Connection {
+    pub sender: Sender,
+    pub reader: Reader,
+}
+
+impl Connection {
+    /// Who the bot is logged in as, see [`Sender::identity`]
+    pub fn identity(&self) -> Identity { self.sender.identity() }
+    /// Round-trip time of the last keepalive PING, see [`Reader::latency`]
+    pub fn latency(&self) -> Option<StdDuration> { self.sender.latency() }
+    pub fn split(self) -> (Sender, Reader) { (self.sender, self.reader) }
+    pub fn join(sender: Sender, reader: Reader) -> Connection { Connection { sender, reader } }
+}
+
+impl From<Connection> for (Sender, Reader) {
+    fn from(value: Connection) -> (Sender, Reader) { value.split() }
+}
+impl From<(Sender, Reader)> for Connection {
+    fn from(value: (Sender, Reader)) -> Connection { Connection::join(value.0, value.1) }
+}
+
+/// Connects to TMI at [`Config::endpoint`]
+pub async fn connect(config: Config) -> Result<Connection> {
+    let endpoint = config.endpoint.clone();
+    connect_with(config, endpoint).await
+}
+
+/// Connects to TMI over a custom `transport`
+pub async fn connect_with(config: Config, transport: impl Transport + 'static) -> Result<Connection> {
+    let rate = RateLimiter::new(config.rate_limits);
+    let recorder = config.record.clone().map(Recorder::open).transpose()?;
+    open(config, Arc::new(transport), rate, recorder).await
+}
+
+/// Opens a connection which counts against an existing `rate` limiter, e.g.
+/// one shared with other connections of the same account, and records into
+/// an existing `recorder`.
+pub(crate) async fn open(
+    config: Config,
+    transport: Arc<dyn Transport>,
+    rate: RateLimiter,
+    recorder: Option<Recorder>,
+) -> Result<Connection> {
+    let (reader, writer, identity) = handshake(&config, &*transport).await?;
+    rate.channels().set_login(&identity.login);
+    let (connected, connected_rx) = watch::channel(0);
+    let queue = Arc::new(Queue::new(config.max_queued));
+    let shared = Arc::new(Shared {
+        rate,
+        config,
+        transport,
+        channels: std::sync::Mutex::new(HashSet::new()),
+        writer: std::sync::Mutex::new(None),
+        connected,
+        queue,
+        smb: std::sync::Mutex::default(),
+        pending: Pending::default(),
+        identity: std::sync::Mutex::new(identity),
+        recorder,
+        latency: std::sync::Mutex::new(None),
+    });
+    queue::spawn_writer(
+        Arc::downgrade(&shared),
+        writer,
+        shared.queue.clone(),
+        shared.rate.clone(),
+        shared.config.max_queue_time,
+        connected_rx,
+    );
+
+    Ok(Connection::join(
+        Sender::new(shared.clone()),
+        Reader::new(reader, shared),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Server;
+
+    fn config() -> Config {
+        Config {
+            credentials: Login::Regular {
+                login: "test".into(),
+                token: "token".into(),
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Skips messages until one matches `predicate`
+    async fn next_matching(reader: &mut Reader, predicate: impl Fn(&Message) -> bool) -> Message {
+        loop {
+            let message = reader.next().await.unwrap();
+            if predicate(&message) {
+                return message;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn fake_server_roundtrip() {
+        let mut server = Server::new();
+        let (sender, mut reader) = connect_with(config(), server.transport()).await.unwrap().split();
+        let mut client = server.accept().await;
+
+        sender.join("forsen").await.unwrap();
+        client.wait_for("JOIN #forsen").await;
+        next_matching(&mut reader, |m| matches!(m, Message::RoomState(_))).await;
+
+        client.privmsg("forsen", "someone", "hello");
+        match next_matching(&mut reader, |m| matches!(m, Message::Privmsg(_))).await {
+            Message::Privmsg(message) => {
+                assert_eq!(message.channel(), "forsen");
+                assert_eq!(message.user.login(), "someone");
+                assert_eq!(message.text(), "hello");
+            }
+            _ => unreachable!(),
+        }
+
+        sender.privmsg("forsen", "hi").await.unwrap();
+        client.wait_for("PRIVMSG #forsen :hi").await;
+    }
+
+    /// Accepts connections, but never answers
+    #[derive(Default)]
+    struct Silent(std::sync::Mutex<Vec<tokio::io::DuplexStream>>);
+
+    impl Transport for Silent {
+        fn connect(&self) -> BoxFuture<'_, Result<Box<dyn Stream>>> {
+            let (client, server) = tokio::io::duplex(4096);
+            self.0.lock().unwrap().push(server);
+            Box::pin(async move { Ok(Box::new(client) as Box<dyn Stream>) })
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_times_out() {
+        let config = Config {
+            handshake_timeout: StdDuration::from_millis(50),
+            ..config()
+        };
+        assert!(matches!(
+            connect_with(config, Silent::default()).await,
+            Err(Error::Timeout)
+        ));
+    }
+
+    #[tokio::test]
+    async fn handshake_exposes_identity() {
+        let server = Server::new();
+        let config = Config {
+            membership_data: true,
+            ..config()
+        };
+        let connection = connect_with(config, server.transport()).await.unwrap();
+        let identity = connection.identity();
+        assert_eq!(identity.login, "test");
+        assert!(identity.has_capability("twitch.tv/membership"));
+        assert_eq!(identity.user_state.unwrap().display_name, "test");
+
+        let connection = connect_with(Config::default(), server.transport()).await.unwrap();
+        let identity = connection.identity();
+        assert!(identity.login.starts_with("justinfan"));
+        assert!(identity.user_state.is_none());
+    }
+
+    #[tokio::test]
+    async fn invalid_token_fails_to_connect() {
+        let server = Server::new();
+        let config = Config {
+            credentials: Login::Regular {
+                login: "test".into(),
+                token: crate::testing::INVALID_TOKEN.into(),
+            },
+            ..Default::default()
+        };
+        assert!(matches!(
+            connect_with(config, server.transport()).await,
+            Err(Error::AuthenticationFailed(_))
+        ));
+    }
+
+    fn keepalive_config() -> Config {
+        Config {
+            keepalive: Some(Keepalive {
+                interval: StdDuration::from_millis(50),
+                timeout: StdDuration::from_millis(100),
+            }),
+            ..config()
+        }
+    }
+
+    #[tokio::test]
+    async fn idle_connection_is_pinged() {
+        let mut server = Server::new();
+        let (_sender, mut reader) = connect_with(keepalive_config(), server.transport())
+            .await
+            .unwrap()
+            .split();
+        let mut client = server.accept().await;
+        assert!(reader.latency().is_none());
+
+        next_matching(&mut reader, |m| matches!(m, Message::Pong(_))).await;
+        assert!(reader.latency().is_some());
+        loop {
+            match client.recv().await {
+                Some(line) if line.starts_with("PING :") => break,
+                Some(_) => continue,
+                None => panic!("Connection closed before the PING"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn stalled_connection_is_dead() {
+        let mut server = Server::new();
+        let (sender, mut reader) = connect_with(keepalive_config(), server.transport())
+            .await
+            .unwrap()
+            .split();
+        let client = server.accept().await;
+        client.freeze();
+        assert!(matches!(reader.next().await, Ok(Message::Reconnected)));
+        let mut client = server.accept().await;
+        sender.privmsg("forsen", "hi").await.unwrap();
+        client.wait_for("PRIVMSG #forsen :hi").await;
+
+        // without reconnecting, the error is returned
+        let config = Config {
+            reconnect: None,
+            ..keepalive_config()
+        };
+        let mut reader = connect_with(config, server.transport()).await.unwrap().reader;
+        let client = server.accept().await;
+        client.freeze();
+        assert!(matches!(reader.next().await, Err(Error::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn malformed_lines_dont_end_the_stream() {
+        const MISSING_CHANNEL: &str = "@badge-info= :tmi.twitch.tv ROOMSTATE";
+        let mut server = Server::new();
+        let transport = server.transport();
+        let connect = |on_malformed| {
+            let config = Config {
+                on_malformed,
+                max_line_length: 512,
+                ..config()
+            };
+            connect_with(config, transport.clone())
+        };
+
+        let mut reader = connect(OnMalformed::Message).await.unwrap().split().1;
+        let client = server.accept().await;
+        client.send(MISSING_CHANNEL);
+        client.send(format!("PING :{}", "a".repeat(1000)));
+        client.send("PING :after");
+        match reader.next().await.unwrap() {
+            Message::Malformed { raw, error } => {
+                assert_eq!(raw, MISSING_CHANNEL);
+                assert_eq!(error, tmi::parse::Error::MissingParam("channel".into()));
+            }
+            other => panic!("Unexpected message: {:?}", other),
+        }
+        match reader.next().await.unwrap() {
+            Message::Malformed { raw, error } => {
+                assert_eq!(raw.len(), 512);
+                assert_eq!(error, tmi::parse::Error::LineTooLong(512));
+            }
+            other => panic!("Unexpected message: {:?}", other),
+        }
+        assert!(matches!(reader.next().await.unwrap(), Message::Ping(ping) if ping.arg() == Some("after")));
+
+        let mut reader = connect(OnMalformed::Log).await.unwrap().split().1;
+        let client = server.accept().await;
+        client.send(MISSING_CHANNEL);
+        client.send("PING :after");
+        assert!(matches!(reader.next().await.unwrap(), Message::Ping(ping) if ping.arg() == Some("after")));
+
+        let mut reader = connect(OnMalformed::Error).await.unwrap().split().1;
+        let client = server.accept().await;
+        client.send(MISSING_CHANNEL);
+        client.send("PING :after");
+        assert!(matches!(reader.next().await, Err(Error::Parse(_))));
+        assert!(matches!(reader.next().await.unwrap(), Message::Ping(ping) if ping.arg() == Some("after")));
+    }
+
+    #[tokio::test]
+    async fn reconnect_rejoins_channels() {
+        let mut server = Server::new();
+        let (sender, mut reader) = connect_with(config(), server.transport()).await.unwrap().split();
+        let mut client = server.accept().await;
+        sender.join("forsen").await.unwrap();
+        client.wait_for("JOIN #forsen").await;
+
+        client.disconnect();
+        next_matching(&mut reader, |m| matches!(m, Message::Reconnected)).await;
+        let mut client = server.accept().await;
+        client.wait_for("JOIN #forsen").await;
+
+        // the sender uses the new connection
+        sender.privmsg("forsen", "hi").await.unwrap();
+        client.wait_for("PRIVMSG #forsen :hi").await;
+    }
+
+    #[tokio::test]
+    async fn writes_survive_a_reconnect() {
+        let mut server = Server::new();
+        let (sender, mut reader) = connect_with(config(), server.transport()).await.unwrap().split();
+        let client = server.accept().await;
+        sender.join("forsen").await.unwrap().await.unwrap();
+
+        // the reader doesn't reconnect until it's read again, so the write
+        // fails first
+        client.disconnect();
+        // queued from another task, over a clone of the sender
+        let delivery = tokio::spawn({
+            let sender = sender.clone();
+            async move { sender.privmsg("forsen", "hi").await.unwrap().await }
+        });
+        next_matching(&mut reader, |m| matches!(m, Message::Reconnected)).await;
+        let mut client = server.accept().await;
+        client.wait_for("PRIVMSG #forsen :hi").await;
+        delivery.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn stalled_write_doesnt_block_reconnect() {
+        let mut server = Server::new();
+        let (sender, mut reader) = connect_with(keepalive_config(), server.transport())
+            .await
+            .unwrap()
+            .split();
+        let stalled = server.accept().await;
+        stalled.freeze();
+        stalled.stall();
+        let delivery = sender.privmsg("forsen", "hi").await.unwrap();
+        next_matching(&mut reader, |m| matches!(m, Message::Reconnected)).await;
+        let mut client = server.accept().await;
+        client.wait_for("PRIVMSG #forsen :hi").await;
+        delivery.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn long_messages_are_split() {
+        let mut server = Server::new();
+        let config = Config {
+            max_message_length: 12,
+            max_message_parts: Some(2),
+            ..config()
+        };
+        let (sender, _reader) = connect_with(config, server.transport()).await.unwrap().split();
+        let mut client = server.accept().await;
+
+        sender
+            .privmsg("forsen", "first part second part third part")
+            .await
+            .unwrap();
+        client.wait_for("PRIVMSG #forsen :first part").await;
+        // the same message bypass is appended to every other message
+        client.wait_for("PRIVMSG #forsen :second part⠀").await;
+        sender.privmsg("forsen", "hi").await.unwrap();
+        // the third part is dropped
+        assert_eq!(client.recv().await.unwrap(), "PRIVMSG #forsen :hi");
+    }
+
+    #[tokio::test]
+    async fn long_whispers_are_split() {
+        let mut server = Server::new();
+        let config = Config {
+            max_message_length: 12,
+            ..config()
+        };
+        let (sender, _reader) = connect_with(config, server.transport()).await.unwrap().split();
+        let mut client = server.accept().await;
+
+        sender
+            .whisper("forsen", "first part second part")
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        client.wait_for("PRIVMSG #jtv :/w forsen first part").await;
+        assert_eq!(client.recv().await.unwrap(), "PRIVMSG #jtv :/w forsen second part");
+    }
+
+    #[tokio::test]
+    async fn long_announcements_are_split() {
+        let mut server = Server::new();
+        let config = Config {
+            max_message_length: 12,
+            ..config()
+        };
+        let (sender, _reader) = connect_with(config, server.transport()).await.unwrap().split();
+        let mut client = server.accept().await;
+
+        sender
+            .announce("forsen", "first part second part")
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        client.wait_for("PRIVMSG #forsen :/announce first part").await;
+        assert_eq!(client.recv().await.unwrap(), "PRIVMSG #forsen :/announce second part");
+    }
+
+    #[tokio::test]
+    async fn joins_and_parts_are_confirmed() {
+        let mut server = Server::new();
+        let (sender, mut reader) = connect_with(config(), server.transport()).await.unwrap().split();
+        let _client = server.accept().await;
+        tokio::spawn(async move { while reader.next().await.is_ok() {} });
+
+        let joined = sender.join_confirmed("forsen").await.unwrap();
+        joined.await.unwrap();
+        let parted = sender.part_confirmed("forsen").await.unwrap();
+        parted.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn other_anonymous_users_dont_confirm() {
+        let mut server = Server::new();
+        let config = Config {
+            credentials: Login::Anonymous,
+            ..config()
+        };
+        let (sender, mut reader) = connect_with(config, server.transport()).await.unwrap().split();
+        let mut client = server.accept().await;
+        let login = sender.identity().login;
+        sender.join("forsen").await.unwrap();
+        while !matches!(reader.next().await.unwrap(), Message::RoomState(_)) {}
+
+        client.freeze();
+        let mut parted = sender.part_confirmed("forsen").await.unwrap();
+        client.wait_for("PART #forsen").await;
+        client.thaw();
+        client.send(":justinfan1!justinfan1@justinfan1.tmi.twitch.tv PART #forsen");
+        assert!(matches!(reader.next().await.unwrap(), Message::Part(_)));
+        assert!(futures::FutureExt::now_or_never(&mut parted).is_none());
+        // the channel is only forgotten once the bot's own PART arrives
+        assert!(sender.channels().room("forsen").is_some());
+
+        client.send(format!(":{0}!{0}@{0}.tmi.twitch.tv PART #forsen", login));
+        assert!(matches!(reader.next().await.unwrap(), Message::Part(_)));
+        parted.await.unwrap();
+        assert!(sender.channels().room("forsen").is_none());
+    }
+
+    #[tokio::test]
+    async fn moderation_results_come_from_notices() {
+        let mut server = Server::new();
+        let (sender, mut reader) = connect_with(config(), server.transport()).await.unwrap().split();
+        let mut client = server.accept().await;
+        tokio::spawn(async move { while reader.next().await.is_ok() {} });
+
+        let banned = sender.ban("forsen", "forsen").await.unwrap();
+        client.wait_for("PRIVMSG #forsen :/ban forsen").await;
+        client.send("@msg-id=bad_ban_broadcaster :tmi.twitch.tv NOTICE #forsen :You cannot ban the broadcaster.");
+        assert!(matches!(
+            banned.await,
+            Err(Error::CommandFailed(NoticeId::BadBanBroadcaster))
+        ));
+
+        let timed_out = sender.timeout("forsen", "someone", None).await.unwrap();
+        client.wait_for("PRIVMSG #forsen :/timeout someone").await;
+        client.send("@msg-id=timeout_success :tmi.twitch.tv NOTICE #forsen :someone has been timed out.");
+        timed_out.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn moderation_jumps_the_queue() {
+        let mut server = Server::new();
+        let config = Config {
+            max_message_length: 12,
+            ..config()
+        };
+        let (sender, _reader) = connect_with(config, server.transport()).await.unwrap().split();
+        let mut client = server.accept().await;
+
+        sender
+            .privmsg("forsen", "first part second part third part")
+            .await
+            .unwrap();
+        client.wait_for("PRIVMSG #forsen :first part").await;
+        sender.ban("forsen", "someone").await.unwrap();
+        assert_eq!(sender.queued_in("forsen"), 3);
+        assert_eq!(client.recv().await.unwrap(), "PRIVMSG #forsen :/ban someone");
+
+        assert_eq!(sender.purge("forsen"), 2);
+        assert_eq!(sender.queued(), 0);
+        // the purged parts are never sent
+        sender.clear("forsen").await.unwrap();
+        assert_eq!(client.recv().await.unwrap(), "PRIVMSG #forsen :/clear");
+    }
+
+    #[test]
+    fn reconnect_delay_is_exponential() {
+        let policy = Reconnect::default();
+        assert_eq!(policy.delay(0), StdDuration::from_secs(0));
+        for (attempt, max) in [(1, 1), (2, 2), (3, 4), (4, 8), (8, 60), (100, 60)].iter() {
+            let delay = policy.delay(*attempt);
+            let max = StdDuration::from_secs(*max);
+            assert!(delay >= max / 2 && delay <= max, "attempt #{}: {:?}", attempt, delay);
+        }
+    }
+}
@@ -0,0 +1,482 @@
+//! Connection pool
+//!
+//! Spreads channels across multiple TMI connections of the same account, so
+//! that no single connection has to carry all of them. Channels are assigned
+//! to the least loaded connection, and a new one is opened once all of them
+//! hold [`Config::channels_per_connection`] channels.
+//!
+//! All connections share one [`RateLimiter`], because Twitch's limits are
+//! per-account, and their messages are merged into a single [`Reader`].
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Mutex as StdMutex, Weak},
+    task::{Context, Poll},
+};
+
+use chrono::Duration;
+use tokio::sync::{mpsc, Mutex};
+
+use super::{
+    open, ChannelState, Config, Confirmation, Delivery, Error, Mode, Priority, RateLimiter, Recorder, Result, Transport,
+};
+use crate::{irc, tmi::Message, util::ArcSlice};
+
+/// How many messages may be buffered in the merged stream before the
+/// connections stop reading
+const MESSAGE_BUFFER: usize = 256;
+
+/// Reads messages from all connections of the pool.
+///
+/// Also a [`futures::Stream`] of messages, which ends once all connections
+/// failed. A connection fails once it returns an error other than
+/// [`Error::Parse`], e.g. after it gave up reconnecting.
+pub struct Reader {
+    messages: mpsc::Receiver<Result<Message>>,
+}
+impl Reader {
+    /// Reads the next message from any of the connections.
+    ///
+    /// PINGs are answered by the pool, and never returned here. Each
+    /// connection reconnects on its own, so [`Message::Reconnected`] may be
+    /// returned once per connection.
+    pub async fn next(&mut self) -> Result<Message> { self.messages.recv().await.unwrap_or(Err(Error::StreamClosed)) }
+}
+impl futures::Stream for Reader {
+    type Item = Result<Message>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.messages.poll_recv(cx)
+    }
+}
+
+/// Which connection each channel was assigned to
+#[derive(Default)]
+struct Routing {
+    connections: Vec<super::Sender>,
+    /// Number of channels joined over each connection
+    load: Vec<usize>,
+    /// Channel -> index into `connections`
+    channels: HashMap<String, usize>,
+}
+
+struct Inner {
+    config: Config,
+    transport: Arc<dyn Transport>,
+    rate: RateLimiter,
+    /// Shared by all connections, so that they record into the same file
+    recorder: Option<Recorder>,
+    routing: StdMutex<Routing>,
+    /// Held while a channel is assigned to a connection, so that concurrent
+    /// joins don't open more connections than needed
+    assigning: Mutex<()>,
+    /// `None` once all connections failed, which ends the [`Reader`]
+    messages: StdMutex<Option<mpsc::Sender<Result<Message>>>>,
+}
+
+impl Inner {
+    /// Forgets a connection which failed, its channels aren't joined anymore
+    fn remove(&self, connection: &super::Sender) {
+        let mut routing = self.routing.lock().unwrap();
+        let index = match routing
+            .connections
+            .iter()
+            .position(|other| Arc::ptr_eq(&other.shared, &connection.shared))
+        {
+            Some(index) => index,
+            None => return,
+        };
+        routing.connections.remove(index);
+        routing.load.remove(index);
+        routing.channels.retain(|channel, other| {
+            if *other == index {
+                log::warn!("Connection failed, {} isn't joined anymore", channel);
+                return false;
+            }
+            if *other > index {
+                *other -= 1;
+            }
+            true
+        });
+        if routing.connections.is_empty() {
+            *self.messages.lock().unwrap() = None;
+        }
+    }
+}
+
+/// Sends messages over the connection which owns the target channel.
+///
+/// Cloning a `Sender` is cheap, all clones share the same connections. A
+/// connection which failed is removed from the pool. Once all of them failed,
+/// sending fails with [`Error::StreamClosed`].
+#[derive(Clone)]
+pub struct Sender {
+    inner: Arc<Inner>,
+}
+impl Sender {
+    /// Opens a new connection, and starts forwarding its messages to the
+    /// [`Reader`]
+    async fn open(&self) -> Result<super::Sender> {
+        let inner = &self.inner;
+        let messages = inner.messages.lock().unwrap().clone().ok_or(Error::StreamClosed)?;
+        log::info!("Opening connection #{}", self.connections() + 1);
+        let (sender, reader) = open(
+            inner.config.clone(),
+            inner.transport.clone(),
+            inner.rate.clone(),
+            inner.recorder.clone(),
+        )
+        .await?
+        .split();
+        forward(reader, sender.clone(), Arc::downgrade(inner), messages);
+        let mut routing = inner.routing.lock().unwrap();
+        routing.connections.push(sender.clone());
+        routing.load.push(0);
+        Ok(sender)
+    }
+    /// The connection which joined `channel`, or the first one if `channel`
+    /// wasn't joined
+    fn route(&self, channel: &str) -> Result<super::Sender> {
+        let routing = self.inner.routing.lock().unwrap();
+        let index = routing.channels.get(channel).copied().unwrap_or(0);
+        routing.connections.get(index).cloned().ok_or(Error::StreamClosed)
+    }
+    fn first(&self) -> Result<super::Sender> { self.route("") }
+    /// Number of open connections
+    pub fn connections(&self) -> usize { self.inner.routing.lock().unwrap().connections.len() }
+    /// State of the joined channels across all connections
+    pub fn channels(&self) -> &ChannelState { self.inner.rate.channels() }
+    /// Removes all messages to `channel` which weren't sent yet, returns how
+    /// many were removed
+    pub fn purge(&self, channel: &str) -> usize { self.route(channel).map_or(0, |sender| sender.purge(channel)) }
+    /// Number of messages which weren't sent yet, across all connections
+    pub fn queued(&self) -> usize {
+        let routing = self.inner.routing.lock().unwrap();
+        routing.connections.iter().map(super::Sender::queued).sum()
+    }
+    /// The highest round-trip time of the connections' last keepalive PINGs,
+    /// see [`Reader::latency`](super::Reader::latency)
+    pub fn latency(&self) -> Option<std::time::Duration> {
+        let routing = self.inner.routing.lock().unwrap();
+        routing.connections.iter().filter_map(super::Sender::latency).max()
+    }
+    /// Number of messages to `channel` which weren't sent yet
+    pub fn queued_in(&self, channel: &str) -> usize {
+        self.route(channel).map_or(0, |sender| sender.queued_in(channel))
+    }
+    /// Sends a raw `message` to twitch over the first connection.
+    ///
+    /// `message` must be terminated with `\r\n`. It counts against the global
+    /// message rate limit.
+    ///
+    /// Use at your own risk.
+    pub async fn send(&self, message: &str) -> Result<Delivery> { self.first()?.send(message).await }
+    /// Sends an IRC `message` over the connection which joined its channel.
+    ///
+    /// It counts against the global message rate limit.
+    pub async fn send_message(&self, message: &irc::Message) -> Result<Delivery> {
+        let channel = message.channel.as_ref().map(ArcSlice::as_str).unwrap_or_default();
+        self.route(channel)?.send_message(message).await
+    }
+    /// Join `channel` over the least loaded connection, opening a new one if
+    /// all of them are full.
+    ///
+    /// Joining a channel which was already joined sends the JOIN again over
+    /// the same connection.
+    pub async fn join(&self, channel: &str) -> Result<Delivery> { self.assign(channel).await?.join(channel).await }
+    /// Join `channel` like [`Sender::join`], and wait for Twitch to confirm
+    /// it, see [`super::Sender::join_confirmed`]
+    pub async fn join_confirmed(&self, channel: &str) -> Result<Confirmation<()>> {
+        self.assign(channel).await?.join_confirmed(channel).await
+    }
+    /// The connection which joined or should join `channel`
+    async fn assign(&self, channel: &str) -> Result<super::Sender> {
+        let _assigning = self.inner.assigning.lock().await;
+        {
+            let mut routing = self.inner.routing.lock().unwrap();
+            if let Some(index) = routing.channels.get(channel) {
+                return Ok(routing.connections[*index].clone());
+            }
+            if let Some(index) = least_loaded(&routing.load, self.inner.config.channels_per_connection) {
+                routing.channels.insert(channel.to_string(), index);
+                routing.load[index] += 1;
+                return Ok(routing.connections[index].clone());
+            }
+        }
+        let sender = self.open().await?;
+        let mut routing = self.inner.routing.lock().unwrap();
+        let index = routing.connections.len() - 1;
+        routing.channels.insert(channel.to_string(), index);
+        routing.load[index] += 1;
+        Ok(sender)
+    }
+    /// Leave `channel`
+    pub async fn part(&self, channel: &str) -> Result<Delivery> { self.unassign(channel)?.part(channel).await }
+    /// Leave `channel`, and wait for Twitch to confirm it, see
+    /// [`super::Sender::part_confirmed`]
+    pub async fn part_confirmed(&self, channel: &str) -> Result<Confirmation<()>> {
+        self.unassign(channel)?.part_confirmed(channel).await
+    }
+    /// The connection which joined `channel`, or the first one if it wasn't
+    /// joined
+    fn unassign(&self, channel: &str) -> Result<super::Sender> {
+        let mut routing = self.inner.routing.lock().unwrap();
+        let index = match routing.channels.remove(channel) {
+            Some(index) => {
+                routing.load[index] -= 1;
+                index
+            }
+            None => 0,
+        };
+        routing.connections.get(index).cloned().ok_or(Error::StreamClosed)
+    }
+    /// Sends `message` to `channel`
+    pub async fn privmsg(&self, channel: &str, message: &str) -> Result<Delivery> {
+        self.route(channel)?.privmsg(channel, message).await
+    }
+    /// Sends `message` to `channel` with a `priority` other than the default
+    pub async fn privmsg_with_priority(&self, channel: &str, message: &str, priority: Priority) -> Result<Delivery> {
+        self.route(channel)?
+            .privmsg_with_priority(channel, message, priority)
+            .await
+    }
+    /// Sends `message` to `channel` as a reply to the message with the id
+    /// `parent`
+    pub async fn reply(&self, channel: &str, parent: &str, message: &str) -> Result<Delivery> {
+        self.route(channel)?.reply(channel, parent, message).await
+    }
+    /// Send `message` directly to `user`
+    pub async fn whisper(&self, user: &str, message: &str) -> Result<Delivery> {
+        self.first()?.whisper(user, message).await
+    }
+    /// Equivalent to `/me <message>`
+    pub async fn me(&self, channel: &str, message: &str) -> Result<Delivery> {
+        self.route(channel)?.me(channel, message).await
+    }
+    /// Clears chat in `channel`, see [`super::Sender::clear`]
+    pub async fn clear(&self, channel: &str) -> Result<Confirmation<()>> { self.route(channel)?.clear(channel).await }
+    /// Timeout `user` in `channel` for `duration`
+    ///
+    /// Maximum timeout is 2 weeks. In case `duration` is `None`, default is 10
+    /// minutes.
+    pub async fn timeout(&self, channel: &str, user: &str, duration: Option<Duration>) -> Result<Confirmation<()>> {
+        self.route(channel)?.timeout(channel, user, duration).await
+    }
+    /// Removes `user`'s timeout in `channel`
+    pub async fn untimeout(&self, channel: &str, user: &str) -> Result<Confirmation<()>> {
+        self.route(channel)?.untimeout(channel, user).await
+    }
+    /// Ban `user` in `channel`
+    pub async fn ban(&self, channel: &str, user: &str) -> Result<Confirmation<()>> {
+        self.route(channel)?.ban(channel, user).await
+    }
+    /// Unban `user` in `channel`
+    pub async fn unban(&self, channel: &str, user: &str) -> Result<Confirmation<()>> {
+        self.route(channel)?.unban(channel, user).await
+    }
+    /// For changing the room state, e.g. slow mode, emote-only mode, etc.
+    pub async fn roomstate(&self, channel: &str, mode: Mode, state: bool) -> Result<Confirmation<()>> {
+        self.route(channel)?.roomstate(channel, mode, state).await
+    }
+    /// Deletes the message with the id `message_id` in `channel`
+    pub async fn delete(&self, channel: &str, message_id: &str) -> Result<Confirmation<()>> {
+        self.route(channel)?.delete(channel, message_id).await
+    }
+    /// Makes `user` a moderator of `channel`
+    pub async fn mod_user(&self, channel: &str, user: &str) -> Result<Confirmation<()>> {
+        self.route(channel)?.mod_user(channel, user).await
+    }
+    /// Removes `user` from the moderators of `channel`
+    pub async fn unmod_user(&self, channel: &str, user: &str) -> Result<Confirmation<()>> {
+        self.route(channel)?.unmod_user(channel, user).await
+    }
+    /// Makes `user` a VIP of `channel`
+    pub async fn vip(&self, channel: &str, user: &str) -> Result<Confirmation<()>> {
+        self.route(channel)?.vip(channel, user).await
+    }
+    /// Removes `user` from the VIPs of `channel`
+    pub async fn unvip(&self, channel: &str, user: &str) -> Result<Confirmation<()>> {
+        self.route(channel)?.unvip(channel, user).await
+    }
+    /// Raids `target` from `channel`, see [`super::Sender::raid`]
+    pub async fn raid(&self, channel: &str, target: &str) -> Result<Delivery> {
+        self.route(channel)?.raid(channel, target).await
+    }
+    /// Cancels the raid in progress in `channel`
+    pub async fn unraid(&self, channel: &str) -> Result<Confirmation<()>> { self.route(channel)?.unraid(channel).await }
+    /// Runs a commercial in `channel`, for 30 seconds if `length` is `None`
+    pub async fn commercial(&self, channel: &str, length: Option<Duration>) -> Result<Confirmation<()>> {
+        self.route(channel)?.commercial(channel, length).await
+    }
+    /// Adds a stream marker in `channel`
+    pub async fn marker(&self, channel: &str, description: Option<&str>) -> Result<Delivery> {
+        self.route(channel)?.marker(channel, description).await
+    }
+    /// Sends `message` to `channel` as a highlighted announcement
+    pub async fn announce(&self, channel: &str, message: &str) -> Result<Delivery> {
+        self.route(channel)?.announce(channel, message).await
+    }
+    /// Changes the bot's name color, the command is sent to `channel`
+    pub async fn color(&self, channel: &str, color: &str) -> Result<Confirmation<()>> {
+        self.route(channel)?.color(channel, color).await
+    }
+}
+
+/// Index of the least loaded connection which can take another channel
+fn least_loaded(load: &[usize], cap: usize) -> Option<usize> {
+    load.iter()
+        .enumerate()
+        .filter(|(_, load)| **load < std::cmp::max(cap, 1))
+        .min_by_key(|(_, load)| **load)
+        .map(|(index, _)| index)
+}
+
+/// Reads messages from one connection into the merged stream, until either
+/// the connection fails or the [`Reader`] is dropped. A failed connection is
+/// removed from the pool.
+fn forward(
+    mut reader: super::Reader,
+    sender: super::Sender,
+    pool: Weak<Inner>,
+    messages: mpsc::Sender<Result<Message>>,
+) {
+    tokio::spawn(async move {
+        loop {
+            let message = reader.next().await;
+            let ping = match &message {
+                Ok(Message::Ping(ping)) => Some(ping.arg().map(String::from)),
+                _ => None,
+            };
+            if let Some(arg) = ping {
+                if let Err(err) = sender.pong(arg.as_deref()).await {
+                    log::error!("Failed to respond to PING: {}", err);
+                }
+                continue;
+            }
+            let fatal = match &message {
+                // the line was consumed, the connection is still usable
+                Ok(_) | Err(Error::Parse(_)) => false,
+                Err(_) => true,
+            };
+            if fatal {
+                if let Some(pool) = pool.upgrade() {
+                    pool.remove(&sender);
+                }
+            }
+            if messages.send(message).await.is_err() || fatal {
+                break;
+            }
+        }
+    });
+}
+
+pub struct Pool {
+    pub sender: Sender,
+    pub reader: Reader,
+}
+
+impl Pool {
+    pub fn split(self) -> (Sender, Reader) { (self.sender, self.reader) }
+}
+
+impl From<Pool> for (Sender, Reader) {
+    fn from(value: Pool) -> (Sender, Reader) { value.split() }
+}
+
+/// Opens the first connection of a pool at [`Config::endpoint`].
+///
+/// More connections are opened as channels are joined.
+pub async fn connect(config: Config) -> Result<Pool> {
+    let endpoint = config.endpoint.clone();
+    connect_with(config, endpoint).await
+}
+
+/// Opens the first connection of a pool over a custom `transport`.
+///
+/// More connections are opened as channels are joined.
+pub async fn connect_with(config: Config, transport: impl Transport + 'static) -> Result<Pool> {
+    let (messages, receiver) = mpsc::channel(MESSAGE_BUFFER);
+    let sender = Sender {
+        inner: Arc::new(Inner {
+            rate: RateLimiter::new(config.rate_limits),
+            recorder: config.record.clone().map(Recorder::open).transpose()?,
+            transport: Arc::new(transport),
+            config,
+            routing: StdMutex::default(),
+            assigning: Mutex::new(()),
+            messages: StdMutex::new(Some(messages)),
+        }),
+    };
+    sender.open().await?;
+
+    Ok(Pool {
+        sender,
+        reader: Reader { messages: receiver },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Server;
+
+    #[tokio::test]
+    async fn channels_are_spread_across_connections() {
+        let mut server = Server::new();
+        let config = Config {
+            channels_per_connection: 1,
+            ..Default::default()
+        };
+        let (sender, _reader) = connect_with(config, server.transport()).await.unwrap().split();
+        let mut first = server.accept().await;
+
+        sender.join("a").await.unwrap();
+        sender.join("b").await.unwrap();
+        assert_eq!(sender.connections(), 2);
+        let mut second = server.accept().await;
+        first.wait_for("JOIN #a").await;
+        second.wait_for("JOIN #b").await;
+
+        sender.privmsg("b", "hi").await.unwrap();
+        second.wait_for("PRIVMSG #b :hi").await;
+    }
+
+    #[tokio::test]
+    async fn failed_connections_are_removed() {
+        let mut server = Server::new();
+        let config = Config {
+            channels_per_connection: 1,
+            reconnect: None,
+            ..Default::default()
+        };
+        let (sender, mut reader) = connect_with(config, server.transport()).await.unwrap().split();
+        let mut first = server.accept().await;
+        sender.join("a").await.unwrap();
+        sender.join("b").await.unwrap();
+        let second = server.accept().await;
+
+        drop(second);
+        while reader.next().await.is_ok() {}
+        assert_eq!(sender.connections(), 1);
+        // "b" isn't joined anymore, so it's sent over the first connection
+        sender.privmsg("b", "hi").await.unwrap();
+        first.wait_for("PRIVMSG #b :hi").await;
+
+        drop(first);
+        while reader.next().await.is_ok() {}
+        assert!(futures::StreamExt::next(&mut reader).await.is_none());
+        assert!(matches!(sender.privmsg("a", "hi").await, Err(Error::StreamClosed)));
+        assert!(matches!(sender.join("c").await, Err(Error::StreamClosed)));
+    }
+
+    #[test]
+    fn least_loaded_connection() {
+        assert_eq!(least_loaded(&[], 2), None);
+        assert_eq!(least_loaded(&[1, 0, 1], 2), Some(1));
+        assert_eq!(least_loaded(&[2, 1, 1], 2), Some(1));
+        assert_eq!(least_loaded(&[2, 2], 2), None);
+        // a cap of 0 still allows one channel per connection
+        assert_eq!(least_loaded(&[0], 0), Some(0));
+        assert_eq!(least_loaded(&[1], 0), None);
+    }
+}
@@ -0,0 +1,528 @@
+//! Outgoing message queue
+//!
+//! [`Sender`] only queues messages, they are written by one task per
+//! connection as soon as the rate limiter allows. The most important message
+//! which may be sent goes first, so a moderation action doesn't wait behind a
+//! long command response, and a message waiting for one channel's slow mode
+//! doesn't hold back messages to other channels. Messages of the same
+//! priority are sent in the order they were queued. While more important
+//! messages wait for their channel, less important ones leave them enough of
+//! the absolute rate limit.
+//!
+//! Once [`Config::max_queued`] messages below [`Priority::High`] are waiting,
+//! queueing another one waits for room. Each queued message comes with a
+//! [`Delivery`], which resolves once the message was written. A message which
+//! fails to be written is retried after the connection is re-established.
+//!
+//! [`Sender`]: super::Sender
+//! [`Config::max_queued`]: super::Config::max_queued
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex, Weak},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::ready;
+use tokio::{
+    io::AsyncWriteExt,
+    sync::{oneshot, watch, Notify, OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
+
+use super::{
+    rate::{RateLimiter, Target},
+    Error, Result, Shared, Writer,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Timers, announcements and anything else which may be delayed
+    Low,
+    /// Command responses and other chat messages
+    Normal,
+    /// Moderation actions, e.g. timeouts and bans
+    High,
+    /// Connection upkeep, e.g. PONGs, which is never rate limited
+    Urgent,
+}
+
+/// What a queued message counts against, see [`Target`]
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Limit {
+    /// Not rate limited
+    None,
+    Channel(String),
+    Whisper,
+    Join,
+    Global,
+}
+
+impl Limit {
+    fn target(&self) -> Option<Target<'_>> {
+        match self {
+            Limit::None => None,
+            Limit::Channel(channel) => Some(Target::Channel(channel)),
+            Limit::Whisper => Some(Target::Whisper),
+            Limit::Join => Some(Target::Join),
+            Limit::Global => Some(Target::Global),
+        }
+    }
+    /// Whether it counts against the absolute message limit
+    fn is_global(&self) -> bool { matches!(self, Limit::Channel(_) | Limit::Global) }
+}
+
+/// Resolves once all parts of a message were written to the connection.
+///
+/// Fails with [`Error::Dropped`] if a part was removed from the queue before
+/// it was sent, see [`Config::max_queue_time`] and [`Sender::purge`], and
+/// with [`Error::StreamClosed`] if the connection is closed first. Dropping it
+/// doesn't cancel the message.
+///
+/// [`Config::max_queue_time`]: super::Config::max_queue_time
+/// [`Sender::purge`]: super::Sender::purge
+#[derive(Debug, Default)]
+pub struct Delivery {
+    parts: VecDeque<oneshot::Receiver<Result<()>>>,
+}
+
+impl Delivery {
+    pub(crate) fn new(parts: Vec<oneshot::Receiver<Result<()>>>) -> Delivery { Delivery { parts: parts.into() } }
+}
+
+impl Future for Delivery {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        while let Some(part) = self.parts.front_mut() {
+            ready!(Pin::new(part).poll(cx)).unwrap_or(Err(Error::StreamClosed))?;
+            self.parts.pop_front();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[derive(Debug)]
+struct Queued {
+    priority: Priority,
+    limit: Limit,
+    line: String,
+    queued_at: Instant,
+    done: oneshot::Sender<Result<()>>,
+    /// Room in the queue, released once the message leaves it
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Queued {
+    /// Settles the message's [`Delivery`]
+    fn finish(self, result: Result<()>) { let _ = self.done.send(result); }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// One queue per [`Priority`], each in the order the messages were queued
+    levels: [VecDeque<Queued>; 4],
+    closed: bool,
+}
+
+impl State {
+    fn push(&mut self, message: Queued) { self.levels[message.priority as usize].push_back(message) }
+
+    fn iter(&self) -> impl Iterator<Item = &Queued> { self.levels.iter().flatten() }
+
+    fn len(&self) -> usize { self.levels.iter().map(VecDeque::len).sum() }
+
+    /// Removes and returns all messages which match `remove`
+    fn remove_where(&mut self, mut remove: impl FnMut(&Queued) -> bool) -> Vec<Queued> {
+        let mut removed = Vec::new();
+        for level in self.levels.iter_mut() {
+            let (matched, kept): (VecDeque<_>, _) =
+                std::mem::take(level).into_iter().partition(|message| remove(message));
+            *level = kept;
+            removed.extend(matched);
+        }
+        removed
+    }
+
+    /// Drops messages below [`Priority::High`] which were queued more than
+    /// `max_age` ago
+    fn expire(&mut self, max_age: Duration, now: Instant) {
+        let expired = self.remove_where(|message| {
+            message.priority < Priority::High && now.saturating_duration_since(message.queued_at) > max_age
+        });
+        for message in expired {
+            log::warn!(
+                "Dropped message after {:?} in queue: {}",
+                max_age,
+                message.line.trim_end()
+            );
+            message.finish(Err(Error::Dropped));
+        }
+    }
+
+    /// Removes and returns the most important message which may be sent at
+    /// `now`, otherwise returns how long to wait before trying again, `None`
+    /// if the queue is empty.
+    ///
+    /// Each waiting message which counts against the absolute limit keeps one
+    /// message of it free from less important messages.
+    fn next(&mut self, rate: &RateLimiter, now: Instant) -> std::result::Result<Queued, Option<Duration>> {
+        let mut wait = None;
+        let mut reserved = 0;
+        for level in self.levels.iter_mut().rev() {
+            let mut waiting = 0;
+            for i in 0..level.len() {
+                let ready = match level[i].limit.target() {
+                    Some(target) => rate.try_reserve(target, reserved, now),
+                    None => None,
+                };
+                match ready {
+                    None => return Ok(level.remove(i).unwrap()),
+                    Some(until) => wait = Some(wait.map_or(until, |wait| std::cmp::min(wait, until))),
+                }
+                if level[i].limit.is_global() {
+                    waiting += 1;
+                }
+            }
+            reserved += waiting;
+        }
+        Err(wait)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Queue {
+    state: Mutex<State>,
+    notify: Notify,
+    /// Room for messages below [`Priority::High`], `None` = no limit
+    room: Option<Arc<Semaphore>>,
+}
+
+impl Queue {
+    /// A queue which holds at most `capacity` messages below
+    /// [`Priority::High`], `None` = no limit
+    pub(crate) fn new(capacity: Option<usize>) -> Queue {
+        Queue {
+            state: Mutex::default(),
+            notify: Notify::new(),
+            room: capacity.map(|capacity| Arc::new(Semaphore::new(capacity))),
+        }
+    }
+
+    /// Queues `line`, waiting for room if the queue is full. Messages of
+    /// [`Priority::High`] and above never wait.
+    ///
+    /// The receiver is settled once the message was written, or dropped.
+    pub(crate) async fn push(&self, priority: Priority, limit: Limit, line: String) -> oneshot::Receiver<Result<()>> {
+        let (done, receiver) = oneshot::channel();
+        let permit = match &self.room {
+            Some(room) if priority < Priority::High => match room.clone().acquire_owned().await {
+                Ok(permit) => Some(permit),
+                // the queue was closed, dropping `done` fails the delivery
+                Err(_) => return receiver,
+            },
+            _ => None,
+        };
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return receiver;
+        }
+        state.push(Queued {
+            priority,
+            limit,
+            line,
+            queued_at: Instant::now(),
+            done,
+            _permit: permit,
+        });
+        drop(state);
+        self.notify.notify_one();
+        receiver
+    }
+
+    /// Removes all queued messages to `channel`, returns how many were
+    /// removed
+    pub(crate) fn purge(&self, channel: &str) -> usize {
+        let purged = self
+            .state
+            .lock()
+            .unwrap()
+            .remove_where(|message| matches!(&message.limit, Limit::Channel(c) if c == channel));
+        let count = purged.len();
+        for message in purged {
+            message.finish(Err(Error::Dropped));
+        }
+        count
+    }
+
+    pub(crate) fn len(&self) -> usize { self.state.lock().unwrap().len() }
+
+    pub(crate) fn len_in(&self, channel: &str) -> usize {
+        self.state
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|message| matches!(&message.limit, Limit::Channel(c) if c == channel))
+            .count()
+    }
+
+    /// Stops the writer task once it's done with the current message
+    pub(crate) fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        if let Some(room) = &self.room {
+            room.close();
+        }
+        self.notify.notify_one();
+    }
+
+    /// Waits until a message may be sent and returns it, `None` once the
+    /// queue is closed.
+    async fn pop(&self, rate: &RateLimiter, max_age: Option<Duration>) -> Option<Queued> {
+        loop {
+            let wait = {
+                let mut state = self.state.lock().unwrap();
+                if state.closed {
+                    return None;
+                }
+                let now = Instant::now();
+                if let Some(max_age) = max_age {
+                    state.expire(max_age, now);
+                }
+                match state.next(rate, now) {
+                    Ok(message) => return Some(message),
+                    Err(wait) => wait,
+                }
+            };
+            match wait {
+                Some(wait) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep(wait) => {},
+                        _ = self.notify.notified() => {},
+                    }
+                }
+                None => self.notify.notified().await,
+            }
+        }
+    }
+}
+
+/// Writes queued messages to the connection, until it's dropped.
+///
+/// The task owns the `writer` it's writing to, so a stalled write doesn't
+/// hold up a reconnect. The reconnect leaves the new writer in
+/// [`Shared::writer`] and bumps `connected`, a message which failed to be
+/// written is retried on the new writer.
+pub(super) fn spawn_writer(
+    shared: Weak<Shared>,
+    mut writer: Writer,
+    queue: Arc<Queue>,
+    rate: RateLimiter,
+    max_age: Option<Duration>,
+    mut connected: watch::Receiver<u64>,
+) {
+    tokio::spawn(async move {
+        while let Some(message) = queue.pop(&rate, max_age).await {
+            loop {
+                let connection = *connected.borrow();
+                match shared.upgrade() {
+                    Some(shared) => {
+                        if let Some(new) = shared.writer.lock().unwrap().take() {
+                            writer = new;
+                        }
+                    }
+                    None => return,
+                }
+                let write = async {
+                    writer.write_all(message.line.as_bytes()).await?;
+                    writer.flush().await
+                };
+                let result = tokio::select! {
+                    result = write => result,
+                    // the reader gave up on a stalled connection
+                    _ = reconnected(&mut connected, connection) => {
+                        Err(io::Error::new(io::ErrorKind::TimedOut, "the connection was replaced"))
+                    }
+                };
+                match result {
+                    Ok(()) => break,
+                    Err(err) => {
+                        // the reader notices the lost connection and reconnects
+                        log::error!("Failed to send message, retrying after reconnecting: {}", err);
+                        if !reconnected(&mut connected, connection).await {
+                            return;
+                        }
+                        // the failed write may have used up the slot, or not
+                        // have counted at all on the new connection
+                        if let Some(target) = message.limit.target() {
+                            rate.until_ready(target).await;
+                        }
+                    }
+                }
+            }
+            message.finish(Ok(()));
+        }
+    });
+}
+
+/// Waits until the connection after `connection` was established, `false`
+/// if that won't happen anymore.
+async fn reconnected(connected: &mut watch::Receiver<u64>, connection: u64) -> bool {
+    while *connected.borrow() == connection {
+        if connected.changed().await.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::rate::Limits, *};
+
+    fn queue(messages: &[(Priority, Limit, &str)]) -> State {
+        let now = Instant::now();
+        let mut state = State::default();
+        for (priority, limit, line) in messages {
+            state.push(Queued {
+                priority: *priority,
+                limit: limit.clone(),
+                line: line.to_string(),
+                queued_at: now,
+                done: oneshot::channel().0,
+                _permit: None,
+            });
+        }
+        state
+    }
+
+    /// The line of the next message which may be sent
+    fn next(state: &mut State, rate: &RateLimiter, now: Instant) -> std::result::Result<String, Option<Duration>> {
+        state.next(rate, now).map(|message| message.line)
+    }
+
+    fn channel(name: &str) -> Limit { Limit::Channel(name.into()) }
+
+    #[test]
+    fn most_important_first() {
+        let rate = RateLimiter::default();
+        let mut state = queue(&[
+            (Priority::Low, channel("a"), "timer"),
+            (Priority::Normal, channel("b"), "reply"),
+            (Priority::High, channel("c"), "timeout"),
+            (Priority::Normal, channel("d"), "second reply"),
+            (Priority::Urgent, Limit::None, "pong"),
+        ]);
+        let now = Instant::now();
+        let order: Vec<_> = std::iter::from_fn(|| next(&mut state, &rate, now).ok()).collect();
+        assert_eq!(order, vec!["pong", "timeout", "reply", "second reply", "timer"]);
+        assert_eq!(next(&mut state, &rate, now), Err(None));
+    }
+
+    #[test]
+    fn waiting_channel_doesnt_block_others() {
+        let rate = RateLimiter::default();
+        let mut state = queue(&[
+            (Priority::High, channel("a"), "first"),
+            (Priority::High, channel("a"), "second"),
+            (Priority::Normal, channel("b"), "other"),
+        ]);
+        let now = Instant::now();
+        assert_eq!(next(&mut state, &rate, now), Ok("first".into()));
+        // "second" has to wait for the global slow mode of #a
+        assert_eq!(next(&mut state, &rate, now), Ok("other".into()));
+        assert_eq!(next(&mut state, &rate, now), Err(Some(Duration::from_secs(1))));
+        assert_eq!(
+            next(&mut state, &rate, now + Duration::from_secs(1)),
+            Ok("second".into())
+        );
+    }
+
+    #[test]
+    fn old_messages_expire() {
+        let mut state = queue(&[
+            (Priority::Low, channel("a"), "timer"),
+            (Priority::Normal, channel("a"), "reply"),
+            (Priority::High, channel("a"), "timeout"),
+        ]);
+        state.expire(Duration::from_secs(10), Instant::now() + Duration::from_secs(5));
+        assert_eq!(state.len(), 3);
+        state.expire(Duration::from_secs(10), Instant::now() + Duration::from_secs(11));
+        assert_eq!(state.len(), 1);
+        assert_eq!(state.iter().next().unwrap().line, "timeout");
+    }
+
+    #[test]
+    fn waiting_messages_keep_global_budget() {
+        let rate = RateLimiter::new(Limits {
+            regular: 3,
+            ..Limits::default()
+        });
+        let mut state = queue(&[
+            (Priority::High, channel("a"), "first timeout"),
+            (Priority::High, channel("a"), "second timeout"),
+            (Priority::Low, channel("b"), "first timer"),
+            (Priority::Low, channel("c"), "second timer"),
+        ]);
+        let now = Instant::now();
+        assert_eq!(next(&mut state, &rate, now), Ok("first timeout".into()));
+        // "second timeout" waits for the global slow mode of #a, so one of the
+        // two remaining messages is kept for it
+        assert_eq!(next(&mut state, &rate, now), Ok("first timer".into()));
+        assert!(next(&mut state, &rate, now).is_err());
+        assert_eq!(
+            next(&mut state, &rate, now + Duration::from_secs(1)),
+            Ok("second timeout".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn purge_channel() {
+        let queue = Queue::new(None);
+        let purged = queue.push(Priority::Normal, channel("a"), "1".into()).await;
+        queue.push(Priority::High, channel("a"), "2".into()).await;
+        let kept = queue.push(Priority::Normal, channel("b"), "3".into()).await;
+        queue.push(Priority::Normal, Limit::Whisper, "4".into()).await;
+        assert_eq!(queue.len(), 4);
+        assert_eq!(queue.len_in("a"), 2);
+
+        assert_eq!(queue.purge("a"), 2);
+        assert_eq!(queue.len_in("a"), 0);
+        assert_eq!(queue.len(), 2);
+        assert!(matches!(Delivery::new(vec![purged]).await, Err(Error::Dropped)));
+
+        queue.close();
+        drop(queue);
+        assert!(matches!(Delivery::new(vec![kept]).await, Err(Error::StreamClosed)));
+    }
+
+    #[tokio::test]
+    async fn full_queue_waits() {
+        let queue = Arc::new(Queue::new(Some(2)));
+        let rate = RateLimiter::default();
+        let first = queue.push(Priority::Normal, channel("a"), "1".into()).await;
+        queue.push(Priority::Low, channel("b"), "2".into()).await;
+        // moderation actions skip the line
+        queue.push(Priority::High, channel("c"), "3".into()).await;
+
+        let waiting = tokio::spawn({
+            let queue = queue.clone();
+            async move {
+                queue.push(Priority::Normal, channel("d"), "4".into()).await;
+            }
+        });
+        assert_eq!(queue.pop(&rate, None).await.unwrap().line, "3");
+        // room is only made once a message was written
+        let sent = queue.pop(&rate, None).await.unwrap();
+        assert_eq!(sent.line, "1");
+        tokio::task::yield_now().await;
+        assert_eq!(queue.len(), 1);
+
+        sent.finish(Ok(()));
+        assert!(Delivery::new(vec![first]).await.is_ok());
+        waiting.await.unwrap();
+        assert_eq!(queue.len(), 2);
+    }
+}
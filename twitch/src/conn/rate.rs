@@ -0,0 +1,355 @@
+//! Outgoing rate limiting
+//!
+//! Twitch limits are per-account:
+//! * absolute (20 msg / 30s, or 100 msg / 30s in channels where the bot is a
+//!   moderator, VIP or the broadcaster)
+//! * per-channel (based on USERSTATE + ROOMSTATE)
+//!   * VIP/Mod/Broadcaster = can ignore
+//!   * Regular = global slow mode (1s) + room slow mode
+//! * whispers (3 / 1s, 100 / 60s)
+//! * joins (20 / 10s)
+//!
+//! Messages which go over these limits are silently dropped by Twitch, so
+//! it's better to wait here.
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use super::ChannelState;
+use crate::tmi::Message;
+
+/// Minimum time between two messages sent to the same channel by a regular
+/// user, even if the channel is not in slow mode.
+const GLOBAL_SLOW_MODE: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    /// Messages per 30 seconds, if the bot is a regular user in the channel
+    pub regular: u32,
+    /// Messages per 30 seconds, if the bot is a moderator, VIP or the
+    /// broadcaster in the channel
+    pub elevated: u32,
+    /// Whispers per second
+    pub whispers_per_second: u32,
+    /// Whispers per minute
+    pub whispers_per_minute: u32,
+    /// Joins per 10 seconds
+    pub joins: u32,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            regular: 20,
+            elevated: 100,
+            whispers_per_second: 3,
+            whispers_per_minute: 100,
+            joins: 20,
+        }
+    }
+}
+
+/// What a message counts against
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Target<'a> {
+    /// A PRIVMSG (or command) sent to a channel
+    Channel(&'a str),
+    /// A whisper
+    Whisper,
+    /// A JOIN
+    Join,
+    /// Anything else which still counts against the global message limit
+    Global,
+}
+
+/// Sliding window of timestamps of sent messages
+#[derive(Debug)]
+struct Window {
+    period: Duration,
+    sent: VecDeque<Instant>,
+}
+
+impl Window {
+    fn new(period: Duration) -> Window {
+        Window {
+            period,
+            sent: VecDeque::new(),
+        }
+    }
+
+    /// Returns how long to wait before another message may be sent, or `None`
+    /// if it may be sent right now.
+    fn check(&mut self, limit: u32, now: Instant) -> Option<Duration> {
+        while let Some(sent) = self.sent.front() {
+            if now.saturating_duration_since(*sent) >= self.period {
+                self.sent.pop_front();
+            } else {
+                break;
+            }
+        }
+        let limit = limit as usize;
+        if self.sent.len() < limit {
+            None
+        } else if limit == 0 {
+            Some(self.period)
+        } else {
+            // wait until enough messages leave the window
+            let oldest = self.sent[self.sent.len() - limit];
+            Some((oldest + self.period).saturating_duration_since(now))
+        }
+    }
+
+    fn record(&mut self, now: Instant) { self.sent.push_back(now); }
+}
+
+#[derive(Debug)]
+struct State {
+    limits: Limits,
+    messages: Window,
+    whispers_per_second: Window,
+    whispers_per_minute: Window,
+    joins: Window,
+    /// Room slow mode and the bot's role in each channel
+    channels: ChannelState,
+    /// When the last message was sent to each channel
+    last: HashMap<String, Instant>,
+}
+
+impl State {
+    fn new(limits: Limits, channels: ChannelState) -> State {
+        State {
+            limits,
+            messages: Window::new(Duration::from_secs(30)),
+            whispers_per_second: Window::new(Duration::from_secs(1)),
+            whispers_per_minute: Window::new(Duration::from_secs(60)),
+            joins: Window::new(Duration::from_secs(10)),
+            channels,
+            last: HashMap::new(),
+        }
+    }
+
+    /// Records the message as sent and returns `None` if it may be sent at
+    /// `now`, otherwise returns how long to wait before trying again.
+    fn reserve(&mut self, target: Target<'_>, now: Instant) -> Option<Duration> { self.reserve_keeping(target, 0, now) }
+
+    /// Like [`State::reserve`], but keeps `reserved` messages of the absolute
+    /// limit free for others
+    fn reserve_keeping(&mut self, target: Target<'_>, reserved: u32, now: Instant) -> Option<Duration> {
+        match target {
+            Target::Channel(channel) => {
+                let (privileged, slow) = match self.channels.room(channel) {
+                    Some(room) => (
+                        room.is_privileged(),
+                        Duration::from_secs(std::cmp::max(room.slow, 0) as u64),
+                    ),
+                    None => (false, Duration::from_secs(0)),
+                };
+                let min_interval = std::cmp::max(slow, GLOBAL_SLOW_MODE);
+                let last = self.last.get(channel).copied();
+                let limit = if privileged {
+                    self.limits.elevated
+                } else {
+                    self.limits.regular
+                };
+                let global = self.messages.check(limit.saturating_sub(reserved), now);
+                let local = match last {
+                    Some(last) if !privileged => {
+                        let wait = (last + min_interval).saturating_duration_since(now);
+                        if wait > Duration::from_secs(0) {
+                            Some(wait)
+                        } else {
+                            None
+                        }
+                    }
+                    _ => None,
+                };
+                match std::cmp::max(global, local) {
+                    Some(wait) => Some(wait),
+                    None => {
+                        self.messages.record(now);
+                        self.last.insert(channel.to_string(), now);
+                        None
+                    }
+                }
+            }
+            Target::Whisper => {
+                let per_second = self.whispers_per_second.check(self.limits.whispers_per_second, now);
+                let per_minute = self.whispers_per_minute.check(self.limits.whispers_per_minute, now);
+                match std::cmp::max(per_second, per_minute) {
+                    Some(wait) => Some(wait),
+                    None => {
+                        self.whispers_per_second.record(now);
+                        self.whispers_per_minute.record(now);
+                        None
+                    }
+                }
+            }
+            Target::Join => match self.joins.check(self.limits.joins, now) {
+                Some(wait) => Some(wait),
+                None => {
+                    self.joins.record(now);
+                    None
+                }
+            },
+            Target::Global => match self.messages.check(self.limits.regular.saturating_sub(reserved), now) {
+                Some(wait) => Some(wait),
+                None => {
+                    self.messages.record(now);
+                    None
+                }
+            },
+        }
+    }
+}
+
+/// Per-account outgoing rate limiter.
+///
+/// Cheap to clone, all clones share the same state. The bot's role and the
+/// slow mode in each channel are read from its [`ChannelState`], which the
+/// [`Reader`] keeps up to date.
+///
+/// [`Reader`]: crate::conn::Reader
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    state: Arc<Mutex<State>>,
+    channels: ChannelState,
+}
+
+impl RateLimiter {
+    pub fn new(limits: Limits) -> RateLimiter {
+        let channels = ChannelState::new();
+        RateLimiter {
+            state: Arc::new(Mutex::new(State::new(limits, channels.clone()))),
+            channels,
+        }
+    }
+
+    /// The channel state which the limits are based on
+    pub fn channels(&self) -> &ChannelState { &self.channels }
+
+    /// Waits until a message to `target` may be sent, and records it as sent.
+    pub async fn until_ready(&self, target: Target<'_>) {
+        loop {
+            let wait = self.state.lock().unwrap().reserve(target, Instant::now());
+            match wait {
+                Some(wait) => tokio::time::sleep(wait).await,
+                None => break,
+            }
+        }
+    }
+
+    /// Records a message to `target` as sent if it may be sent at `now`,
+    /// otherwise returns how long to wait. `reserved` messages of the absolute
+    /// limit are kept free, e.g. for more important messages which are still
+    /// waiting for their channel.
+    pub(crate) fn try_reserve(&self, target: Target<'_>, reserved: u32, now: Instant) -> Option<Duration> {
+        self.state.lock().unwrap().reserve_keeping(target, reserved, now)
+    }
+
+    /// Updates the per-channel state, see [`ChannelState::update`]
+    pub fn update(&self, message: &Message) { self.channels.update(message) }
+
+    /// Whether the bot is a moderator, VIP or the broadcaster in `channel`
+    pub fn is_privileged(&self, channel: &str) -> bool { self.channels.is_privileged(channel) }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self { RateLimiter::new(Limits::default()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn userstate(channel: &str, badges: &str) -> Message {
+        Message::parse(format!(
+            "@badge-info=;badges={};color=#FF0000;display-name=test;emote-sets=0;mod=0;subscriber=0;user-type= \
+             :tmi.twitch.tv USERSTATE #{}",
+            badges, channel
+        ))
+        .unwrap()
+    }
+
+    fn roomstate(channel: &str, slow: i64) -> Message {
+        Message::parse(format!("@slow={} :tmi.twitch.tv ROOMSTATE #{}", slow, channel)).unwrap()
+    }
+
+    #[test]
+    fn regular_global_limit() {
+        let mut state = State::new(Limits::default(), ChannelState::new());
+        let now = Instant::now();
+        // different channels, so that the global slow mode doesn't apply
+        for i in 0..20 {
+            assert_eq!(state.reserve(Target::Channel(&i.to_string()), now), None);
+        }
+        assert_eq!(state.reserve(Target::Channel("20"), now), Some(Duration::from_secs(30)));
+        assert_eq!(
+            state.reserve(Target::Channel("20"), now + Duration::from_secs(30)),
+            None
+        );
+    }
+
+    #[test]
+    fn elevated_global_limit() {
+        let mut state = State::new(Limits::default(), ChannelState::new());
+        state.channels.update(&userstate("test", "moderator/1"));
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert_eq!(state.reserve(Target::Channel("test"), now), None);
+        }
+        assert!(state.reserve(Target::Channel("test"), now).is_some());
+        // the bucket is shared, so regular channels have to wait as well
+        assert!(state.reserve(Target::Channel("other"), now).is_some());
+    }
+
+    #[test]
+    fn channel_slow_mode() {
+        let mut state = State::new(Limits::default(), ChannelState::new());
+        let now = Instant::now();
+        assert_eq!(state.reserve(Target::Channel("test"), now), None);
+        assert_eq!(state.reserve(Target::Channel("test"), now), Some(GLOBAL_SLOW_MODE));
+
+        state.channels.update(&roomstate("test", 10));
+        assert_eq!(
+            state.reserve(Target::Channel("test"), now + Duration::from_secs(2)),
+            Some(Duration::from_secs(8))
+        );
+        assert_eq!(
+            state.reserve(Target::Channel("test"), now + Duration::from_secs(10)),
+            None
+        );
+    }
+
+    #[test]
+    fn privileged_ignores_slow_mode() {
+        let mut state = State::new(Limits::default(), ChannelState::new());
+        state.channels.update(&roomstate("test", 10));
+        state.channels.update(&userstate("test", "vip/1"));
+        let now = Instant::now();
+        assert_eq!(state.reserve(Target::Channel("test"), now), None);
+        assert_eq!(state.reserve(Target::Channel("test"), now), None);
+
+        // losing the badge re-applies slow mode
+        state.channels.update(&userstate("test", ""));
+        assert_eq!(
+            state.reserve(Target::Channel("test"), now),
+            Some(Duration::from_secs(10))
+        );
+    }
+
+    #[test]
+    fn whisper_budget_is_separate() {
+        let mut state = State::new(Limits::default(), ChannelState::new());
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(state.reserve(Target::Whisper, now), None);
+        }
+        assert_eq!(state.reserve(Target::Whisper, now), Some(Duration::from_secs(1)));
+        // whispers don't count against channel messages
+        assert_eq!(state.reserve(Target::Channel("test"), now), None);
+    }
+}
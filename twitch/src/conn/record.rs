@@ -0,0 +1,475 @@
+//! Recording and replaying raw TMI traffic
+//!
+//! A [`Recorder`] writes every line read by a [`Reader`] to a file, along
+//! with the time it was received, so that a problem seen in production can be
+//! reproduced offline by feeding the file back through a [`Replay`].
+//!
+//! Each entry of a recording is one line, either plain text, where the
+//! RFC 3339 timestamp and the raw line are separated by a space:
+//!
+//! ```text
+//! 2021-03-06T12:00:00.000Z PING :tmi.twitch.tv
+//! ```
+//!
+//! or a JSON object:
+//!
+//! ```text
+//! {"time":"2021-03-06T12:00:00.000Z","line":"PING :tmi.twitch.tv"}
+//! ```
+//!
+//! [`Replay`] reads both, even mixed in one file.
+//!
+//! [`Reader`]: super::Reader
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::{ready, Future, Stream};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::AsyncRead,
+    time::{Instant, Sleep},
+};
+
+use super::{
+    lines::{Line, Lines},
+    ChannelState, Error, Result,
+};
+use crate::tmi::Message;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// `<timestamp> <line>`
+    Plain,
+    /// `{"time":"<timestamp>","line":"<line>"}`
+    Json,
+}
+
+/// Where and how to record, see [`Config::record`]
+///
+/// [`Config::record`]: super::Config::record
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recording {
+    /// The file to record into, which is appended to if it exists. Rotated
+    /// files get a `.1`, `.2`, ... suffix, `.1` being the newest.
+    pub path: PathBuf,
+    pub format: Format,
+    /// Rotate once the file would grow past this many bytes, `None` = never
+    /// rotate
+    pub max_file_size: Option<u64>,
+    /// How many rotated files to keep, in addition to the current one
+    pub max_files: usize,
+}
+
+impl Recording {
+    /// Plain text recording into `path`, rotated every 64 MiB
+    pub fn new(path: impl Into<PathBuf>) -> Recording {
+        Recording {
+            path: path.into(),
+            format: Format::Plain,
+            max_file_size: Some(64 * 1024 * 1024),
+            max_files: 4,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Output {
+    recording: Recording,
+    file: BufWriter<File>,
+    /// Size of the current file in bytes
+    size: u64,
+}
+
+impl Output {
+    fn write(&mut self, time: DateTime<Utc>, line: &str) -> io::Result<()> {
+        let entry = format_entry(self.recording.format, time, line);
+        if let Some(max) = self.recording.max_file_size {
+            if self.size > 0 && self.size + entry.len() as u64 > max {
+                self.rotate()?;
+            }
+        }
+        self.file.write_all(entry.as_bytes())?;
+        // keep everything up to a crash
+        self.file.flush()?;
+        self.size += entry.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let path = &self.recording.path;
+        for i in (1..self.recording.max_files).rev() {
+            match fs::rename(rotated(path, i), rotated(path, i + 1)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => (),
+            }
+        }
+        if self.recording.max_files > 0 {
+            fs::rename(path, rotated(path, 1))?;
+        }
+        self.file = BufWriter::new(File::create(path)?);
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// `path` with a `.<n>` suffix
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    name.into()
+}
+
+/// Writes raw lines into a [`Recording`].
+///
+/// Cheap to clone, all clones write into the same file, e.g. the recorder of
+/// a [`pool`](super::pool) is shared by all of its connections.
+#[derive(Clone, Debug)]
+pub struct Recorder(Arc<Mutex<Output>>);
+
+impl Recorder {
+    pub fn open(recording: Recording) -> io::Result<Recorder> {
+        let file = OpenOptions::new().create(true).append(true).open(&recording.path)?;
+        let size = file.metadata()?.len();
+        Ok(Recorder(Arc::new(Mutex::new(Output {
+            recording,
+            file: BufWriter::new(file),
+            size,
+        }))))
+    }
+
+    /// Records `line` as received right now. Failing to record is only
+    /// logged, so that it doesn't interrupt reading.
+    pub fn record(&self, line: &str) { self.record_at(Utc::now(), line) }
+
+    pub fn record_at(&self, time: DateTime<Utc>, line: &str) {
+        if let Err(err) = self.0.lock().unwrap().write(time, line) {
+            log::error!("Failed to record message: {}", err);
+        }
+    }
+}
+
+/// An entry of a [`Format::Json`] recording
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    /// RFC 3339 timestamp
+    time: String,
+    line: String,
+}
+
+fn format_entry(format: Format, time: DateTime<Utc>, line: &str) -> String {
+    let time = time.to_rfc3339_opts(SecondsFormat::Millis, true);
+    match format {
+        Format::Plain => format!("{} {}\n", time, line),
+        Format::Json => {
+            let entry = Entry {
+                time,
+                line: line.to_string(),
+            };
+            // a struct of strings always serializes
+            let mut entry = serde_json::to_string(&entry).unwrap();
+            entry.push('\n');
+            entry
+        }
+    }
+}
+
+/// Parses one entry of a recording into the time the line was received, and
+/// the line itself
+fn parse_entry(entry: &str) -> Option<(DateTime<Utc>, String)> {
+    let Entry { time, line } = if entry.starts_with('{') {
+        serde_json::from_str(entry).ok()?
+    } else {
+        let (time, line) = entry.split_once(' ')?;
+        Entry {
+            time: time.to_string(),
+            line: line.to_string(),
+        }
+    };
+    let time = DateTime::parse_from_rfc3339(&time).ok()?.with_timezone(&Utc);
+    Some((time, line))
+}
+
+/// How fast a [`Replay`] goes through a recording
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Speed {
+    /// With the same delays between messages as when they were recorded
+    Original,
+    /// Faster by a positive factor, e.g. `2.0` is twice as fast. Factors
+    /// which aren't positive and finite replay like [`Speed::Unlimited`].
+    Accelerated(f64),
+    /// Without any delays
+    Unlimited,
+}
+
+/// Entries with more bytes are cut and returned as [`Error::Generic`], this is
+/// well above [`Config::max_line_length`] to leave room for the timestamp and
+/// JSON escapes.
+///
+/// [`Config::max_line_length`]: super::Config::max_line_length
+const MAX_ENTRY_LENGTH: usize = 128 * 1024;
+
+type Source = Lines<Box<dyn AsyncRead + Send + Unpin>>;
+
+/// Reads messages from a recording, see the [module](self) docs.
+///
+/// Mirrors the [`Reader`](super::Reader), including being a
+/// [`futures::Stream`] of messages, which ends with the recording.
+pub struct Replay {
+    lines: Source,
+    speed: Speed,
+    channels: ChannelState,
+    /// When the first entry was recorded, and when it was replayed
+    start: Option<(DateTime<Utc>, Instant)>,
+    /// The next message, which is returned once the delay is over
+    next: Option<(Result<Message>, Pin<Box<Sleep>>)>,
+    closed: bool,
+}
+
+impl Replay {
+    pub fn new(source: impl AsyncRead + Send + Unpin + 'static, speed: Speed) -> Replay {
+        let source: Box<dyn AsyncRead + Send + Unpin> = Box::new(source);
+        Replay {
+            lines: Lines::new(source, MAX_ENTRY_LENGTH),
+            speed: match speed {
+                Speed::Accelerated(factor) if !factor.is_finite() || factor <= 0.0 => Speed::Unlimited,
+                speed => speed,
+            },
+            channels: ChannelState::new(),
+            start: None,
+            next: None,
+            closed: false,
+        }
+    }
+
+    /// Replays the recording at `path`
+    pub async fn open(path: impl AsRef<Path>, speed: Speed) -> Result<Replay> {
+        let file = tokio::fs::File::open(path).await?;
+        Ok(Replay::new(file, speed))
+    }
+
+    /// State of the recorded channels, as of the last message read
+    pub fn channels(&self) -> &ChannelState { &self.channels }
+
+    /// Reads the next message, once it's due.
+    ///
+    /// Entries which aren't valid or are too long are returned as
+    /// [`Error::Generic`], and lines which fail to parse as [`Error::Parse`],
+    /// the replay goes on after both. Invalid UTF-8 is replaced with `U+FFFD`.
+    /// Fails with [`Error::StreamClosed`] at the end of the recording.
+    pub async fn next(&mut self) -> Result<Message> {
+        futures::future::poll_fn(|cx| self.poll_message(cx))
+            .await
+            .unwrap_or(Err(Error::StreamClosed))
+    }
+
+    /// When the entry recorded at `time` is due, `None` if right away
+    fn due(&mut self, time: DateTime<Utc>) -> Option<Instant> {
+        let factor = match self.speed {
+            Speed::Original => 1.0,
+            Speed::Accelerated(factor) => factor,
+            Speed::Unlimited => return None,
+        };
+        let (first, started) = *self.start.get_or_insert((time, Instant::now()));
+        // entries out of order are replayed right away
+        let offset = (time - first).to_std().unwrap_or_default();
+        Some(started + offset.div_f64(factor))
+    }
+
+    fn poll_message(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Message>>> {
+        loop {
+            if let Some((_, delay)) = &mut self.next {
+                ready!(delay.as_mut().poll(cx));
+                let (message, _) = self.next.take().unwrap();
+                if let Ok(message) = &message {
+                    self.channels.update(message);
+                }
+                return Poll::Ready(Some(message));
+            }
+            if self.closed {
+                return Poll::Ready(None);
+            }
+            let entry = match ready!(Pin::new(&mut self.lines).poll_next(cx)) {
+                Some(Ok(Line::Complete(entry))) => entry,
+                Some(Ok(Line::TooLong(start))) => {
+                    let error = anyhow::anyhow!("Entry longer than {} bytes: {}...", MAX_ENTRY_LENGTH, start);
+                    return Poll::Ready(Some(Err(Error::Generic(error))));
+                }
+                Some(Err(err)) => {
+                    self.closed = true;
+                    return Poll::Ready(Some(Err(err.into())));
+                }
+                None => {
+                    self.closed = true;
+                    return Poll::Ready(None);
+                }
+            };
+            if entry.trim().is_empty() {
+                continue;
+            }
+            let (time, line) = match parse_entry(&entry) {
+                Some(entry) => entry,
+                None => return Poll::Ready(Some(Err(Error::Generic(anyhow::anyhow!("Invalid entry: {}", entry))))),
+            };
+            let message = Message::parse(line).map_err(Error::from);
+            let delay = match self.due(time) {
+                Some(due) => tokio::time::sleep_until(due),
+                None => tokio::time::sleep(Default::default()),
+            };
+            self.next = Some((message, Box::pin(delay)));
+        }
+    }
+}
+
+impl Stream for Replay {
+    type Item = Result<Message>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> { self.poll_message(cx) }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn time(millis: i64) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2021-03-06T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+            + chrono::Duration::milliseconds(millis)
+    }
+
+    #[test]
+    fn entries_roundtrip() {
+        let line = r#"@badge-info=;display-name=a\sb :a!a@a.tmi.twitch.tv PRIVMSG #forsen :"quoted" \ 🦀"#;
+        for format in [Format::Plain, Format::Json].iter() {
+            let entry = format_entry(*format, time(123), line);
+            assert_eq!(parse_entry(entry.trim_end()), Some((time(123), line.to_string())));
+        }
+        assert_eq!(
+            format_entry(Format::Json, time(0), "PING :tmi.twitch.tv"),
+            "{\"time\":\"2021-03-06T12:00:00.000Z\",\"line\":\"PING :tmi.twitch.tv\"}\n"
+        );
+        // escapes written by other tools
+        assert_eq!(
+            parse_entry(r#"{ "line": "a\u0001🦀", "time": "2021-03-06T12:00:00Z" }"#),
+            Some((time(0), "a\u{1}🦀".to_string()))
+        );
+        assert_eq!(parse_entry("not an entry"), None);
+        assert_eq!(parse_entry(r#"{"time":"2021-03-06T12:00:00Z"}"#), None);
+    }
+
+    #[test]
+    fn files_are_rotated() {
+        let dir = std::env::temp_dir().join(format!("twitch-record-{}", rand::random::<u64>()));
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("tmi.log");
+        let recorder = Recorder::open(Recording {
+            max_file_size: Some(100),
+            max_files: 2,
+            ..Recording::new(&path)
+        })
+        .unwrap();
+        // each entry is 72 bytes, so only one fits into a file
+        for i in 0..4 {
+            recorder.record_at(time(i), &format!("PRIVMSG #forsen :message #{:0>20}", i));
+        }
+        let read = |n| fs::read_to_string(if n == 0 { path.clone() } else { rotated(&path, n) }).unwrap();
+        assert!(read(0).contains("#00000000000000000003"));
+        assert!(read(1).contains("#00000000000000000002"));
+        assert!(read(2).contains("#00000000000000000001"));
+        assert!(!rotated(&path, 3).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn connection_records_what_it_reads() {
+        let path = std::env::temp_dir().join(format!("twitch-record-{}.log", rand::random::<u64>()));
+        let mut server = crate::testing::Server::new();
+        let config = super::super::Config {
+            record: Some(Recording {
+                format: Format::Json,
+                ..Recording::new(&path)
+            }),
+            ..Default::default()
+        };
+        let (sender, mut reader) = super::super::connect_with(config, server.transport())
+            .await
+            .unwrap()
+            .split();
+        let client = server.accept().await;
+        sender.join("forsen").await.unwrap();
+        while !matches!(reader.next().await.unwrap(), Message::RoomState(_)) {}
+        drop(client);
+
+        let mut replay = Replay::open(&path, Speed::Unlimited).await.unwrap();
+        assert!(matches!(replay.next().await, Ok(Message::Join(_))));
+        assert!(matches!(replay.next().await, Ok(Message::RoomState(_))));
+        assert!(replay.channels().room("forsen").is_some());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replay_keeps_going_after_errors() {
+        let recording = [
+            format_entry(Format::Plain, time(0), ":tmi.twitch.tv ROOMSTATE #forsen"),
+            "garbage\n".to_string(),
+            format_entry(
+                Format::Json,
+                time(10),
+                "@room-id=1;tmi-sent-ts=1594545155039 :tmi.twitch.tv CLEARCHAT #forsen",
+            ),
+            format_entry(Format::Plain, time(20), "@badge-info= :tmi.twitch.tv ROOMSTATE"),
+            "\n".to_string(),
+            format_entry(Format::Plain, time(25), &"a".repeat(MAX_ENTRY_LENGTH)),
+            format_entry(Format::Plain, time(30), "PING :tmi.twitch.tv"),
+        ]
+        .concat();
+        let mut recording = recording.into_bytes();
+        recording.extend_from_slice(b"2021-03-06T12:00:00.040Z PING :\xff\n");
+        let mut replay = Replay::new(std::io::Cursor::new(recording), Speed::Unlimited);
+        assert!(matches!(replay.next().await, Ok(Message::RoomState(_))));
+        assert!(matches!(replay.next().await, Err(Error::Generic(_))));
+        assert!(matches!(replay.next().await, Ok(Message::Clearchat(_))));
+        assert!(matches!(replay.next().await, Err(Error::Parse(_))));
+        assert!(matches!(replay.next().await, Err(Error::Generic(_))));
+        assert!(matches!(replay.next().await, Ok(Message::Ping(_))));
+        assert!(matches!(replay.next().await, Ok(Message::Ping(_))));
+        assert!(matches!(replay.next().await, Err(Error::StreamClosed)));
+        assert!(futures::StreamExt::next(&mut replay).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn replay_speed() {
+        let recording = [
+            format_entry(Format::Plain, time(0), "PING :a"),
+            format_entry(Format::Plain, time(200), "PING :b"),
+        ]
+        .concat();
+        let elapsed = |speed| {
+            let recording = recording.clone();
+            async move {
+                let mut replay = Replay::new(std::io::Cursor::new(recording.into_bytes()), speed);
+                let start = Instant::now();
+                replay.next().await.unwrap();
+                replay.next().await.unwrap();
+                start.elapsed()
+            }
+        };
+        assert!(elapsed(Speed::Original).await >= Duration::from_millis(200));
+        let accelerated = elapsed(Speed::Accelerated(4.0)).await;
+        assert!(accelerated >= Duration::from_millis(50) && accelerated < Duration::from_millis(200));
+        assert!(elapsed(Speed::Unlimited).await < Duration::from_millis(50));
+        for factor in [0.0, -1.0, f64::NAN, f64::INFINITY].iter() {
+            assert!(elapsed(Speed::Accelerated(*factor)).await < Duration::from_millis(50));
+        }
+    }
+}
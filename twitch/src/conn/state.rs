@@ -0,0 +1,363 @@
+//! Merged per-channel state
+//!
+//! Twitch only sends the full ROOMSTATE once, right after joining a channel,
+//! and partial updates after that. USERSTATE describes the bot in one channel,
+//! and JOIN/PART (with [`Config::membership_data`]) or chat messages tell who
+//! is in the channel. [`ChannelState`] applies all of these, so that the
+//! current state can be looked up at any time.
+//!
+//! [`Config::membership_data`]: super::Config::membership_data
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::tmi::{
+    parse::{FollowerOnlyMode, UserState},
+    Message,
+};
+
+/// Chatters which weren't seen for this long may be forgotten
+const CHATTER_EXPIRY: i64 = 60 * 60;
+/// Number of chatters per channel above which expired ones are removed, and
+/// then the oldest ones if that wasn't enough
+const MAX_CHATTERS: usize = 10_000;
+
+/// Room modes of a channel, and the bot's role in it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Room {
+    pub emote_only: bool,
+    pub followers_only: FollowerOnlyMode,
+    pub r9k: bool,
+    /// Seconds a regular user has to wait between two messages, 0 if slow
+    /// mode is disabled
+    pub slow: i64,
+    pub subs_only: bool,
+    /// The bot's last USERSTATE in this channel, `None` for anonymous logins
+    pub user_state: Option<UserState>,
+}
+
+impl Default for Room {
+    fn default() -> Self {
+        Room {
+            emote_only: false,
+            followers_only: FollowerOnlyMode::Disabled,
+            r9k: false,
+            slow: 0,
+            subs_only: false,
+            user_state: None,
+        }
+    }
+}
+
+impl Room {
+    pub fn is_slow_mode(&self) -> bool { self.slow > 0 }
+    pub fn is_followers_only(&self) -> bool { self.followers_only != FollowerOnlyMode::Disabled }
+    /// Whether the bot is a moderator or the broadcaster
+    pub fn is_moderator(&self) -> bool {
+        self.user_state
+            .as_ref()
+            .map(|state| state.is_broadcaster() || state.is_moderator())
+            .unwrap_or(false)
+    }
+    /// Whether the bot is a moderator, VIP or the broadcaster, which lifts
+    /// most of the rate limits
+    pub fn is_privileged(&self) -> bool {
+        self.user_state
+            .as_ref()
+            .map(|state| state.is_broadcaster() || state.is_moderator() || state.is_vip())
+            .unwrap_or(false)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Channel {
+    room: Room,
+    /// Login -> when the chatter was last seen
+    chatters: HashMap<String, DateTime<Utc>>,
+}
+
+impl Channel {
+    fn seen(&mut self, login: &str, time: DateTime<Utc>) {
+        if self.chatters.len() >= MAX_CHATTERS && !self.chatters.contains_key(login) {
+            let expiry = time - Duration::seconds(CHATTER_EXPIRY);
+            self.chatters.retain(|_, seen| *seen > expiry);
+        }
+        if self.chatters.len() >= MAX_CHATTERS && !self.chatters.contains_key(login) {
+            // forget the oldest tenth, so that this doesn't happen for every
+            // new chatter
+            let mut times: Vec<_> = self.chatters.values().copied().collect();
+            let index = times.len() - MAX_CHATTERS * 9 / 10;
+            let (_, oldest, _) = times.select_nth_unstable(index);
+            let oldest = *oldest;
+            self.chatters.retain(|_, seen| *seen > oldest);
+        }
+        let seen = self.chatters.entry(login.to_string()).or_insert(time);
+        *seen = std::cmp::max(*seen, time);
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// The bot's login, its own JOINs aren't chatters
+    login: Option<String>,
+    channels: HashMap<String, Channel>,
+}
+
+impl State {
+    /// Anonymous users (`justinfan<number>`) can't chat, so their JOINs are
+    /// ignored as well, which covers the bot's own ones for anonymous logins
+    fn is_chatter(&self, login: &str) -> bool {
+        self.login.as_deref() != Some(login) && !login.starts_with("justinfan")
+    }
+}
+
+/// Per-account channel state.
+///
+/// Cheap to clone, all clones share the same state. The [`Reader`] keeps it
+/// up to date with every message it reads.
+///
+/// [`Reader`]: crate::conn::Reader
+#[derive(Clone, Debug, Default)]
+pub struct ChannelState(Arc<Mutex<State>>);
+
+impl ChannelState {
+    pub fn new() -> ChannelState { ChannelState::default() }
+
+    /// Sets the bot's `login`, so that its own JOINs aren't recorded as
+    /// chatters. Connections set it after every handshake.
+    pub fn set_login(&self, login: &str) { self.0.lock().unwrap().login = Some(login.to_string()); }
+
+    /// Applies a ROOMSTATE, USERSTATE, JOIN, PART, PRIVMSG or USERNOTICE.
+    ///
+    /// Any other message is ignored.
+    pub fn update(&self, message: &Message) {
+        let mut state = self.0.lock().unwrap();
+        if let Message::Join(join) = message {
+            if !state.is_chatter(join.nick()) {
+                return;
+            }
+        }
+        let channels = &mut state.channels;
+        match message {
+            Message::RoomState(state) => {
+                let room = &mut channels.entry(state.channel().to_string()).or_default().room;
+                if let Some(emote_only) = state.emote_only {
+                    room.emote_only = emote_only;
+                }
+                if let Some(followers_only) = state.followers_only {
+                    room.followers_only = followers_only;
+                }
+                if let Some(r9k) = state.r9k {
+                    room.r9k = r9k;
+                }
+                if let Some(slow) = state.slow {
+                    room.slow = slow;
+                }
+                if let Some(subs_only) = state.subs_only {
+                    room.subs_only = subs_only;
+                }
+            }
+            Message::UserState(state) => {
+                channels.entry(state.channel().to_string()).or_default().room.user_state = Some(state.clone());
+            }
+            Message::Join(join) => channels
+                .entry(join.channel().to_string())
+                .or_default()
+                .seen(join.nick(), Utc::now()),
+            Message::Part(part) => {
+                if let Some(channel) = channels.get_mut(part.channel()) {
+                    channel.chatters.remove(part.nick());
+                }
+            }
+            Message::Privmsg(message) => channels
+                .entry(message.channel().to_string())
+                .or_default()
+                .seen(message.user.login(), message.time),
+            Message::UserNotice(notice) => {
+                let base = notice.base();
+                channels
+                    .entry(base.channel().to_string())
+                    .or_default()
+                    .seen(base.user.login(), base.time)
+            }
+            _ => (),
+        }
+    }
+
+    /// Forgets everything about `channel`, e.g. after leaving it
+    pub fn remove(&self, channel: &str) { self.0.lock().unwrap().channels.remove(channel); }
+
+    /// Channels which state is known about
+    pub fn channels(&self) -> Vec<String> { self.0.lock().unwrap().channels.keys().cloned().collect() }
+
+    /// Current room modes of `channel`, `None` if nothing is known about it
+    pub fn room(&self, channel: &str) -> Option<Room> {
+        self.0
+            .lock()
+            .unwrap()
+            .channels
+            .get(channel)
+            .map(|channel| channel.room.clone())
+    }
+
+    /// Whether the bot is a moderator, VIP or the broadcaster in `channel`
+    pub fn is_privileged(&self, channel: &str) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .channels
+            .get(channel)
+            .map(|channel| channel.room.is_privileged())
+            .unwrap_or(false)
+    }
+
+    /// When `login` last joined or chatted in `channel`
+    pub fn last_seen(&self, channel: &str, login: &str) -> Option<DateTime<Utc>> {
+        self.0
+            .lock()
+            .unwrap()
+            .channels
+            .get(channel)
+            .and_then(|channel| channel.chatters.get(login).copied())
+    }
+
+    /// Logins which joined or chatted in `channel` during the last `within`
+    pub fn recent_chatters(&self, channel: &str, within: Duration) -> Vec<String> {
+        let since = Utc::now() - within;
+        self.0
+            .lock()
+            .unwrap()
+            .channels
+            .get(channel)
+            .map(|channel| {
+                channel
+                    .chatters
+                    .iter()
+                    .filter(|(_, seen)| **seen >= since)
+                    .map(|(login, _)| login.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: String) -> Message { Message::parse(line).unwrap() }
+
+    fn privmsg(channel: &str, login: &str, time: DateTime<Utc>) -> Message {
+        parse(format!(
+            "@badge-info=;badges=;color=;display-name={login};emotes=;id=1;room-id=2;tmi-sent-ts={time};user-id=3 \
+             :{login}!{login}@{login}.tmi.twitch.tv PRIVMSG #{channel} :hello",
+            login = login,
+            time = time.timestamp_millis(),
+            channel = channel
+        ))
+    }
+
+    #[test]
+    fn roomstate_updates_are_merged() {
+        let state = ChannelState::new();
+        assert_eq!(state.room("forsen"), None);
+
+        state.update(&parse(
+            "@emote-only=0;followers-only=-1;r9k=0;rituals=0;room-id=1;slow=0;subs-only=0 :tmi.twitch.tv ROOMSTATE \
+             #forsen"
+                .into(),
+        ));
+        assert_eq!(state.room("forsen"), Some(Room::default()));
+
+        state.update(&parse("@room-id=1;slow=10 :tmi.twitch.tv ROOMSTATE #forsen".into()));
+        state.update(&parse(
+            "@followers-only=0;room-id=1 :tmi.twitch.tv ROOMSTATE #forsen".into(),
+        ));
+        let room = state.room("forsen").unwrap();
+        assert!(room.is_slow_mode());
+        assert_eq!(room.slow, 10);
+        assert!(room.is_followers_only());
+        assert!(!room.subs_only);
+
+        state.remove("forsen");
+        assert_eq!(state.channels(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn userstate_sets_the_bots_role() {
+        let state = ChannelState::new();
+        let userstate = |badges: &str| {
+            parse(format!(
+                "@badge-info=;badges={};color=;display-name=bot;emote-sets=0;mod=0;subscriber=0;user-type= \
+                 :tmi.twitch.tv USERSTATE #forsen",
+                badges
+            ))
+        };
+        assert!(!state.is_privileged("forsen"));
+
+        state.update(&userstate("vip/1"));
+        assert!(state.is_privileged("forsen"));
+        assert!(!state.room("forsen").unwrap().is_moderator());
+
+        state.update(&userstate("moderator/1"));
+        assert!(state.room("forsen").unwrap().is_moderator());
+
+        state.update(&userstate(""));
+        assert!(!state.is_privileged("forsen"));
+    }
+
+    #[test]
+    fn chatters_are_tracked() {
+        let state = ChannelState::new();
+        let now = Utc::now();
+        state.update(&privmsg("forsen", "old", now - Duration::minutes(10)));
+        state.update(&privmsg("forsen", "new", now - Duration::seconds(5)));
+        state.update(&parse(":joined!joined@joined.tmi.twitch.tv JOIN #forsen".into()));
+
+        let mut recent = state.recent_chatters("forsen", Duration::minutes(1));
+        recent.sort();
+        assert_eq!(recent, vec!["joined", "new"]);
+        assert_eq!(
+            state.last_seen("forsen", "old").map(|time| time.timestamp_millis()),
+            Some((now - Duration::minutes(10)).timestamp_millis())
+        );
+
+        state.update(&parse(":joined!joined@joined.tmi.twitch.tv PART #forsen".into()));
+        assert_eq!(state.last_seen("forsen", "joined"), None);
+        assert_eq!(
+            state.recent_chatters("pajlada", Duration::minutes(1)),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn own_joins_arent_chatters() {
+        let state = ChannelState::new();
+        state.set_login("bot");
+        state.update(&parse(":bot!bot@bot.tmi.twitch.tv JOIN #forsen".into()));
+        state.update(&parse(
+            ":justinfan123!justinfan123@justinfan123.tmi.twitch.tv JOIN #forsen".into(),
+        ));
+        assert_eq!(
+            state.recent_chatters("forsen", Duration::minutes(1)),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn oldest_chatters_are_evicted() {
+        let state = ChannelState::new();
+        let now = Utc::now();
+        for i in 0..=MAX_CHATTERS {
+            let time = now - Duration::seconds((MAX_CHATTERS - i) as i64);
+            state.update(&privmsg("forsen", &format!("chatter{}", i), time));
+        }
+        let count = state.0.lock().unwrap().channels["forsen"].chatters.len();
+        assert!(count <= MAX_CHATTERS);
+        assert_eq!(state.last_seen("forsen", "chatter0"), None);
+        assert!(state.last_seen("forsen", &format!("chatter{}", MAX_CHATTERS)).is_some());
+    }
+}
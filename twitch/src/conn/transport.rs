@@ -0,0 +1,388 @@
+//! Transports which carry TMI's IRC lines
+//!
+//! A [`Transport`] opens a new [`Stream`] for the initial connection, and
+//! again every time the connection is re-established. By default, TMI is
+//! reached through the [`Endpoint`] in [`Config`], but anything which
+//! implements `AsyncRead` and `AsyncWrite` can be used instead, e.g. the
+//! in-memory streams of [`testing::Server`].
+//!
+//! [`Config`]: crate::conn::Config
+//! [`testing::Server`]: crate::testing::Server
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_rustls::{rustls::ClientConfig, webpki::DNSNameRef, TlsConnector};
+use tokio_socks::tcp::Socks5Stream;
+
+use super::{websocket::WebSocket, Result};
+
+pub const TMI_URL_HOST: &str = "irc.chat.twitch.tv";
+pub const TMI_TLS_PORT: u16 = 6697;
+pub const TMI_PLAINTEXT_PORT: u16 = 6667;
+pub const TMI_WS_HOST: &str = "irc-ws.chat.twitch.tv";
+pub const TMI_WSS_PORT: u16 = 443;
+
+/// A bidirectional byte stream
+pub trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Stream for T {}
+
+/// Opens streams to TMI
+pub trait Transport: Send + Sync {
+    fn connect(&self) -> BoxFuture<'_, Result<Box<dyn Stream>>>;
+}
+
+/// Where and how to connect over TCP
+#[derive(Clone, Debug, PartialEq)]
+pub struct Endpoint {
+    pub host: String,
+    pub port: u16,
+    /// `None` = plaintext
+    pub tls: Option<Tls>,
+    /// `None` = connect directly
+    pub proxy: Option<Proxy>,
+    pub protocol: Protocol,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    /// IRC lines directly over the stream
+    Irc,
+    /// IRC lines in WebSocket text frames
+    WebSocket,
+}
+
+impl Endpoint {
+    /// TMI without TLS
+    pub fn plaintext() -> Endpoint {
+        Endpoint {
+            port: TMI_PLAINTEXT_PORT,
+            tls: None,
+            ..Default::default()
+        }
+    }
+
+    /// TMI over WebSocket, which is reachable from networks where the IRC
+    /// ports are blocked
+    pub fn websocket() -> Endpoint {
+        Endpoint {
+            host: TMI_WS_HOST.into(),
+            port: TMI_WSS_PORT,
+            protocol: Protocol::WebSocket,
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for Endpoint {
+    fn default() -> Self {
+        Endpoint {
+            host: TMI_URL_HOST.into(),
+            port: TMI_TLS_PORT,
+            tls: Some(Tls::default()),
+            proxy: None,
+            protocol: Protocol::Irc,
+        }
+    }
+}
+
+impl Transport for Endpoint {
+    fn connect(&self) -> BoxFuture<'_, Result<Box<dyn Stream>>> {
+        Box::pin(async move {
+            let stream: Box<dyn Stream> = match &self.proxy {
+                Some(proxy) => proxy.connect(&self.host, self.port).await?,
+                None => Box::new(TcpStream::connect((&self.host[..], self.port)).await?),
+            };
+            let stream = match &self.tls {
+                Some(tls) => {
+                    let dnsname = DNSNameRef::try_from_ascii_str(&self.host).map_err(|err| anyhow::anyhow!(err))?;
+                    let stream = tls
+                        .connector()?
+                        .connect(dnsname, stream)
+                        .await
+                        .map_err(|err| anyhow::anyhow!(err))?;
+                    Box::new(stream)
+                }
+                None => stream,
+            };
+            match self.protocol {
+                Protocol::Irc => Ok(stream),
+                Protocol::WebSocket => {
+                    let scheme = if self.tls.is_some() { "wss" } else { "ws" };
+                    let url = format!("{}://{}:{}/", scheme, self.host, self.port);
+                    let (stream, _) = tokio_tungstenite::client_async(url, stream)
+                        .await
+                        .map_err(|err| anyhow::anyhow!(err))?;
+                    Ok(Box::new(WebSocket::new(stream)) as Box<dyn Stream>)
+                }
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Tls {
+    /// PEM-encoded root certificates, trusted instead of the native ones
+    pub root_certificates: Option<Vec<u8>>,
+}
+
+impl Tls {
+    fn connector(&self) -> Result<TlsConnector> {
+        let mut config = ClientConfig::new();
+        match &self.root_certificates {
+            Some(pem) => match config.root_store.add_pem_file(&mut &pem[..]) {
+                Ok((valid, _)) if valid > 0 => (),
+                _ => return Err(anyhow::anyhow!("No valid root certificates").into()),
+            },
+            None => {
+                config.root_store = match rustls_native_certs::load_native_certs() {
+                    Ok(store) => store,
+                    Err((Some(store), err)) => {
+                        log::warn!("Some native root certificates couldn't be loaded: {}", err);
+                        store
+                    }
+                    Err((None, err)) => {
+                        return Err(anyhow::anyhow!("Failed to load native root certificates: {}", err).into())
+                    }
+                }
+            }
+        }
+        Ok(TlsConnector::from(Arc::new(config)))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Proxy {
+    /// HTTP proxy which supports the `CONNECT` method
+    Http {
+        host: String,
+        port: u16,
+        credentials: Option<Credentials>,
+    },
+    Socks5 {
+        host: String,
+        port: u16,
+        credentials: Option<Credentials>,
+    },
+}
+
+impl Proxy {
+    /// Opens a tunnel to `host:port`
+    async fn connect(&self, host: &str, port: u16) -> Result<Box<dyn Stream>> {
+        match self {
+            Proxy::Http {
+                host: proxy_host,
+                port: proxy_port,
+                credentials,
+            } => {
+                log::debug!("Connecting through HTTP proxy {}:{}", proxy_host, proxy_port);
+                let mut stream = TcpStream::connect((&proxy_host[..], *proxy_port)).await?;
+                http_connect(&mut stream, host, port, credentials.as_ref()).await?;
+                Ok(Box::new(stream))
+            }
+            Proxy::Socks5 {
+                host: proxy_host,
+                port: proxy_port,
+                credentials,
+            } => {
+                log::debug!("Connecting through SOCKS5 proxy {}:{}", proxy_host, proxy_port);
+                let socket = TcpStream::connect((&proxy_host[..], *proxy_port)).await?;
+                let stream = match credentials {
+                    Some(Credentials { username, password }) => {
+                        Socks5Stream::connect_with_password_and_socket(socket, (host, port), username, password).await
+                    }
+                    None => Socks5Stream::connect_with_socket(socket, (host, port)).await,
+                }
+                .map_err(|err| anyhow::anyhow!(err))?;
+                Ok(Box::new(stream))
+            }
+        }
+    }
+}
+
+/// Maximum size of the proxy's response to `CONNECT`
+const MAX_HTTP_RESPONSE: usize = 8192;
+
+async fn http_connect(stream: &mut TcpStream, host: &str, port: u16, credentials: Option<&Credentials>) -> Result<()> {
+    let mut request = format!("CONNECT {0}:{1} HTTP/1.1\r\nHost: {0}:{1}\r\n", host, port);
+    if let Some(Credentials { username, password }) = credentials {
+        request += &format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64::encode(format!("{}:{}", username, password))
+        );
+    }
+    request += "\r\n";
+    stream.write_all(request.as_bytes()).await?;
+
+    // read byte by byte, so that nothing after the response is consumed
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_HTTP_RESPONSE {
+            return Err(anyhow::anyhow!("Proxy response is too long").into());
+        }
+        response.push(stream.read_u8().await?);
+    }
+    let response = String::from_utf8_lossy(&response);
+    let status = response.lines().next().unwrap_or_default();
+    match status.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(anyhow::anyhow!("Proxy refused to connect: {}", status).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::AsyncBufReadExt, net::TcpListener};
+
+    use super::*;
+
+    async fn listen() -> (TcpListener, u16) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        (listener, port)
+    }
+
+    /// Reads the proxy request up to the empty line which ends it
+    async fn read_request(stream: &mut TcpStream) -> String {
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            request.push(stream.read_u8().await.unwrap());
+        }
+        String::from_utf8(request).unwrap()
+    }
+
+    async fn read_line(stream: &mut TcpStream) -> String {
+        let mut line = String::new();
+        tokio::io::BufReader::new(stream).read_line(&mut line).await.unwrap();
+        line
+    }
+
+    #[tokio::test]
+    async fn plaintext() {
+        let (listener, port) = listen().await;
+        let endpoint = Endpoint {
+            host: "127.0.0.1".into(),
+            port,
+            tls: None,
+            proxy: None,
+            protocol: Protocol::Irc,
+        };
+        let mut stream = endpoint.connect().await.unwrap();
+        stream.write_all(b"PING\r\n").await.unwrap();
+
+        let (mut server, _) = listener.accept().await.unwrap();
+        assert_eq!(read_line(&mut server).await, "PING\r\n");
+    }
+
+    #[tokio::test]
+    async fn http_proxy() {
+        let (listener, port) = listen().await;
+        let endpoint = Endpoint {
+            proxy: Some(Proxy::Http {
+                host: "127.0.0.1".into(),
+                port,
+                credentials: Some(Credentials {
+                    username: "user".into(),
+                    password: "pass".into(),
+                }),
+            }),
+            ..Endpoint::plaintext()
+        };
+        let proxy = tokio::spawn(async move {
+            let (mut server, _) = listener.accept().await.unwrap();
+            let request = read_request(&mut server).await;
+            server
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await
+                .unwrap();
+            (request, read_line(&mut server).await)
+        });
+
+        let mut stream = endpoint.connect().await.unwrap();
+        stream.write_all(b"PING\r\n").await.unwrap();
+        let (request, line) = proxy.await.unwrap();
+        assert_eq!(
+            request,
+            "CONNECT irc.chat.twitch.tv:6667 HTTP/1.1\r\nHost: irc.chat.twitch.tv:6667\r\nProxy-Authorization: Basic \
+             dXNlcjpwYXNz\r\n\r\n"
+        );
+        assert_eq!(line, "PING\r\n");
+    }
+
+    #[tokio::test]
+    async fn http_proxy_refused() {
+        let (listener, port) = listen().await;
+        let endpoint = Endpoint {
+            proxy: Some(Proxy::Http {
+                host: "127.0.0.1".into(),
+                port,
+                credentials: None,
+            }),
+            ..Endpoint::plaintext()
+        };
+        tokio::spawn(async move {
+            let (mut server, _) = listener.accept().await.unwrap();
+            read_request(&mut server).await;
+            server
+                .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                .await
+                .unwrap();
+        });
+
+        assert!(endpoint.connect().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn socks5_proxy() {
+        let (listener, port) = listen().await;
+        let endpoint = Endpoint {
+            proxy: Some(Proxy::Socks5 {
+                host: "127.0.0.1".into(),
+                port,
+                credentials: None,
+            }),
+            ..Endpoint::plaintext()
+        };
+        let proxy = tokio::spawn(async move {
+            let (mut server, _) = listener.accept().await.unwrap();
+            // greeting: version, number of methods, methods
+            let mut greeting = [0u8; 2];
+            server.read_exact(&mut greeting).await.unwrap();
+            let mut methods = vec![0u8; greeting[1] as usize];
+            server.read_exact(&mut methods).await.unwrap();
+            server.write_all(&[5, 0]).await.unwrap();
+            // request: version, CONNECT, reserved, domain name, length
+            let mut request = [0u8; 5];
+            server.read_exact(&mut request).await.unwrap();
+            assert_eq!(request[..4], [5, 1, 0, 3]);
+            let mut target = vec![0u8; request[4] as usize + 2];
+            server.read_exact(&mut target).await.unwrap();
+            server.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).await.unwrap();
+            (target, read_line(&mut server).await)
+        });
+
+        let mut stream = endpoint.connect().await.unwrap();
+        stream.write_all(b"PING\r\n").await.unwrap();
+        let (target, line) = proxy.await.unwrap();
+        assert_eq!(&target[..target.len() - 2], TMI_URL_HOST.as_bytes());
+        assert_eq!(target[target.len() - 2..], TMI_PLAINTEXT_PORT.to_be_bytes());
+        assert_eq!(line, "PING\r\n");
+    }
+
+    #[test]
+    fn invalid_root_certificate() {
+        let tls = Tls {
+            root_certificates: Some(b"not a certificate".to_vec()),
+        };
+        assert!(tls.connector().is_err());
+    }
+}
@@ -0,0 +1,189 @@
+//! IRC lines over WebSocket
+//!
+//! TMI sends one or more lines per text frame, and expects exactly one line
+//! per frame from the client. [`WebSocket`] turns the frames back into a byte
+//! stream, so that it can be used like any other [`Stream`].
+//!
+//! [`Stream`]: super::transport::Stream
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{ready, Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::{
+    tungstenite::{Error as WsError, Message},
+    WebSocketStream,
+};
+
+pub(crate) struct WebSocket<S> {
+    inner: WebSocketStream<S>,
+    /// Received data which wasn't read yet
+    incoming: Vec<u8>,
+    read: usize,
+    /// Written data which doesn't form a complete line yet
+    outgoing: Vec<u8>,
+}
+
+impl<S> WebSocket<S> {
+    pub(crate) fn new(inner: WebSocketStream<S>) -> WebSocket<S> {
+        WebSocket {
+            inner,
+            incoming: Vec::new(),
+            read: 0,
+            outgoing: Vec::new(),
+        }
+    }
+}
+
+fn into_io(err: WsError) -> io::Error {
+    match err {
+        WsError::Io(err) => err,
+        err => io::Error::new(io::ErrorKind::Other, err),
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocket<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.read == this.incoming.len() {
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Text(text))) if !text.is_empty() => {
+                    this.incoming = text.into_bytes();
+                    if !this.incoming.ends_with(b"\n") {
+                        this.incoming.extend_from_slice(b"\r\n");
+                    }
+                    this.read = 0;
+                }
+                // the connection is closed, which is the end of the stream
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                // control frames are handled by tungstenite
+                Some(Ok(_)) => continue,
+                Some(Err(err)) => return Poll::Ready(Err(into_io(err))),
+            }
+        }
+        let len = std::cmp::min(buf.remaining(), this.incoming.len() - this.read);
+        buf.put_slice(&this.incoming[this.read..this.read + len]);
+        this.read += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocket<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(Pin::new(&mut this.inner).poll_ready(cx)).map_err(into_io)?;
+        match buf.iter().position(|b| *b == b'\n') {
+            Some(end) => {
+                this.outgoing.extend_from_slice(&buf[..end]);
+                let line = String::from_utf8_lossy(&this.outgoing)
+                    .trim_end_matches('\r')
+                    .to_string();
+                this.outgoing.clear();
+                Pin::new(&mut this.inner)
+                    .start_send(Message::Text(line))
+                    .map_err(into_io)?;
+                Poll::Ready(Ok(end + 1))
+            }
+            None => {
+                this.outgoing.extend_from_slice(buf);
+                Poll::Ready(Ok(buf.len()))
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx).map_err(into_io)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx).map_err(into_io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::SinkExt;
+    use tokio::{
+        io::{AsyncBufReadExt, BufReader},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::{
+        conn::{
+            transport::{Endpoint, Protocol},
+            Config, Login,
+        },
+        testing::Client,
+        tmi,
+    };
+
+    async fn listen() -> (TcpListener, u16) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        (listener, port)
+    }
+
+    #[tokio::test]
+    async fn multiple_lines_per_frame() {
+        let (listener, port) = listen().await;
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(socket).await.unwrap();
+            ws.send(Message::Text("PING :a\r\nPING :b\r\n".into())).await.unwrap();
+            ws.send(Message::Text("PING :c".into())).await.unwrap();
+        });
+
+        let socket = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let (ws, _) = tokio_tungstenite::client_async(format!("ws://127.0.0.1:{}/", port), socket)
+            .await
+            .unwrap();
+        let mut lines = BufReader::new(WebSocket::new(ws)).lines();
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "PING :a");
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "PING :b");
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "PING :c");
+    }
+
+    #[tokio::test]
+    async fn tmi_over_websocket() {
+        let (listener, port) = listen().await;
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let ws = tokio_tungstenite::accept_async(socket).await.unwrap();
+            Client::spawn(WebSocket::new(ws))
+        });
+
+        let config = Config {
+            credentials: Login::Regular {
+                login: "test".into(),
+                token: "token".into(),
+            },
+            endpoint: Endpoint {
+                host: "127.0.0.1".into(),
+                port,
+                tls: None,
+                proxy: None,
+                protocol: Protocol::WebSocket,
+            },
+            ..Default::default()
+        };
+        let (sender, mut reader) = crate::conn::connect(config).await.unwrap().split();
+        let mut client = server.await.unwrap();
+
+        sender.join("forsen").await.unwrap();
+        client.wait_for("JOIN #forsen").await;
+        client.privmsg("forsen", "someone", "hello");
+        loop {
+            if let tmi::Message::Privmsg(message) = reader.next().await.unwrap() {
+                assert_eq!(message.text(), "hello");
+                break;
+            }
+        }
+
+        sender.privmsg("forsen", "hi").await.unwrap();
+        client.wait_for("PRIVMSG #forsen :hi").await;
+    }
+}
@@ -1,16 +1,17 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter, Write};
 use std::ops::Deref;
-use std::pin::Pin;
+use std::sync::Arc;
 
 use chrono::{DateTime, Duration, TimeZone, Utc};
 use thiserror::Error;
-use unicode_segmentation::UnicodeSegmentation;
 
-use crate::util::UnsafeSlice;
+use crate::util::ArcSlice;
 
-// TODO: go over each UnsafeSlice field with `pub`, and create a getter for it
+// TODO: go over each ArcSlice field with `pub`, and create a getter for it
 
-#[derive(Error, Debug, PartialEq)]
+#[derive(Error, Clone, Debug, PartialEq)]
 pub enum Error {
     #[error("Expected tag '{0}'")]
     MissingTag(String),
@@ -24,13 +25,12 @@ pub struct Message {
     pub tags: Tags,
     pub prefix: Option<Prefix>,
     pub cmd: Command,
-    pub channel: Option<UnsafeSlice>,
+    pub channel: Option<ArcSlice>,
     pub params: Option<Params>,
-    pub source: Pin<String>,
+    /// The whole line, which all of the parts point into
+    pub source: Arc<str>,
 }
 
-unsafe impl Send for Message {}
-
 impl Message {
     /// Parse a raw IRC Message
     ///
@@ -39,9 +39,10 @@ impl Message {
     /// the #<channel id> always being present
     /// before :params
     pub fn parse(source: String) -> Result<Message> {
-        let input = Pin::new(source);
-        let source = input.trim();
-        let (tags, remainder) = Tags::parse(&source);
+        let source = Arc::<str>::from(source);
+        let line = ArcSlice::from(source.clone());
+        let line = line.slice(line.as_str().trim());
+        let (tags, remainder) = Tags::parse(line);
         let (prefix, remainder) = Prefix::parse(remainder);
         let (cmd, remainder) = Command::parse(remainder);
         let (channel, remainder) = Channel::parse(remainder);
@@ -53,12 +54,174 @@ impl Message {
             cmd,
             channel,
             params,
-            source: input,
+            source,
         })
     }
+
+    /// Starts building an outgoing message with the command `cmd`
+    pub fn build(cmd: &str) -> Builder {
+        Builder {
+            tags: Vec::new(),
+            prefix: None,
+            cmd: Command::parse(cmd.into()).0,
+            channel: None,
+            params: None,
+        }
+    }
+
+    /// Writes the message in wire format, without the trailing `\r\n`.
+    ///
+    /// Tag values are written as they are stored, which is already escaped.
+    /// Parsed messages are written back exactly as they were received, as long
+    /// as their parts were separated by single spaces.
+    pub fn encode<W: Write>(&self, out: &mut W) -> fmt::Result {
+        if !self.tags.is_empty() {
+            out.write_char('@')?;
+            for (i, (key, value)) in self.tags.iter().enumerate() {
+                if i > 0 {
+                    out.write_char(';')?;
+                }
+                match value {
+                    Some(value) => write!(out, "{}={}", key, value)?,
+                    None => write!(out, "{}", key)?,
+                }
+            }
+            out.write_char(' ')?;
+        }
+        if let Some(prefix) = &self.prefix {
+            write!(out, ":{} ", prefix)?;
+        }
+        out.write_str(self.cmd.as_str())?;
+        if let Some(channel) = &self.channel {
+            write!(out, " #{}", channel)?;
+        }
+        if let Some(params) = &self.params {
+            write!(out, " {}", params.raw())?;
+        }
+        Ok(())
+    }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+impl Display for Message {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result { self.encode(f) }
+}
+
+/// Builder for outgoing messages, created with [`Message::build`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Builder {
+    tags: Vec<(ArcSlice, Option<ArcSlice>)>,
+    prefix: Option<Prefix>,
+    cmd: Command,
+    channel: Option<ArcSlice>,
+    params: Option<String>,
+}
+
+impl Builder {
+    /// Adds a tag, `value` is escaped
+    pub fn tag(mut self, key: &str, value: &str) -> Builder {
+        self.tags.push((key.into(), Some(escape(value).as_ref().into())));
+        self
+    }
+
+    /// Adds a key-only tag (`@key`)
+    pub fn flag(mut self, key: &str) -> Builder {
+        self.tags.push((key.into(), None));
+        self
+    }
+
+    /// Sets the prefix to `nick!user@host`, `nick@host` or `host`
+    pub fn prefix(mut self, nick: Option<&str>, user: Option<&str>, host: &str) -> Builder {
+        self.prefix = Some(Prefix {
+            nick: nick.map(Into::into),
+            user: nick.and(user).map(Into::into),
+            host: host.into(),
+        });
+        self
+    }
+
+    /// Sets the channel, without the leading `#`
+    pub fn channel(mut self, channel: &str) -> Builder {
+        self.channel = Some(channel.into());
+        self
+    }
+
+    /// Sets the raw params, e.g. `user :text`
+    pub fn params(mut self, params: &str) -> Builder {
+        self.params = Some(params.to_string());
+        self
+    }
+
+    /// Appends `text` as the trailing parameter, e.g. the text of a PRIVMSG
+    pub fn trailing(mut self, text: &str) -> Builder {
+        self.params = Some(match self.params {
+            Some(params) => format!("{} :{}", params, text),
+            None => format!(":{}", text),
+        });
+        self
+    }
+
+    /// Encodes the message, and parses it back, so that all of its parts point
+    /// into the same source
+    pub fn finish(self) -> Message {
+        let message = Message {
+            tags: Tags(self.tags),
+            prefix: self.prefix,
+            cmd: self.cmd,
+            channel: self.channel,
+            params: self.params.map(|params| Params(params.into())),
+            source: Arc::from(""),
+        };
+        Message::parse(message.to_string()).expect("Failed to parse built message")
+    }
+}
+
+/// Escapes a tag value according to the IRCv3 message-tags spec
+pub fn escape(value: &str) -> Cow<'_, str> {
+    if !value.contains(&[';', ' ', '\\', '\r', '\n'][..]) {
+        return Cow::Borrowed(value);
+    }
+    let mut out = String::with_capacity(value.len() + 8);
+    for c in value.chars() {
+        match c {
+            ';' => out.push_str("\\:"),
+            ' ' => out.push_str("\\s"),
+            '\\' => out.push_str("\\\\"),
+            '\r' => out.push_str("\\r"),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// Unescapes a tag value according to the IRCv3 message-tags spec.
+///
+/// Unknown escape sequences are replaced by the escaped character, and a
+/// trailing lone `\` is dropped.
+pub fn unescape(value: &str) -> Cow<'_, str> {
+    if !value.contains('\\') {
+        return Cow::Borrowed(value);
+    }
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => out.push(';'),
+            Some('s') => out.push(' '),
+            Some('r') => out.push('\r'),
+            Some('n') => out.push('\n'),
+            Some(c) => out.push(c),
+            None => (),
+        }
+    }
+    Cow::Owned(out)
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Command {
     Ping,
     Pong,
@@ -92,21 +255,21 @@ pub enum Command {
     /// Requesting an IRC capability
     Capability,
     /// Unknown command
-    Unknown(UnsafeSlice),
+    Unknown(ArcSlice),
 }
 
 impl Command {
     /// Parses a Twitch IRC command
     ///
     /// Returns (command, remainder)
-    pub fn parse(data: &str) -> (Command, &str) {
+    pub fn parse(data: ArcSlice) -> (Command, ArcSlice) {
         use Command::*;
-        let data = data.trim_start();
-        let end = match data.find(' ') {
+        let text = data.as_str().trim_start();
+        let end = match text.find(' ') {
             Some(v) => v,
-            None => data.len(),
+            None => text.len(),
         };
-        let cmd = &data[..end];
+        let cmd = &text[..end];
         let cmd = match cmd {
             "PING" => Ping,
             "PONG" => Pong,
@@ -124,21 +287,65 @@ impl Command {
             "USERNOTICE" => UserNotice,
             "USERSTATE" => UserState,
             "CAP" => Capability,
-            other => Unknown(other.into()),
+            other => Unknown(data.slice(other)),
         };
 
-        (cmd, &data[end..])
+        (cmd, data.slice(&text[end..]))
+    }
+
+    /// The command's name, as it appears in the source
+    pub fn as_str(&self) -> &str {
+        use Command::*;
+        match self {
+            Ping => "PING",
+            Pong => "PONG",
+            Join => "JOIN",
+            Part => "PART",
+            Privmsg => "PRIVMSG",
+            Whisper => "WHISPER",
+            Clearchat => "CLEARCHAT",
+            Clearmsg => "CLEARMSG",
+            GlobalUserState => "GLOBALUSERSTATE",
+            HostTarget => "HOSTTARGET",
+            Notice => "NOTICE",
+            Reconnect => "RECONNECT",
+            RoomState => "ROOMSTATE",
+            UserNotice => "USERNOTICE",
+            UserState => "USERSTATE",
+            Capability => "CAP",
+            Unknown(cmd) => cmd.as_str(),
+        }
     }
 }
 
-#[derive(Clone, PartialEq, Debug)]
-pub struct Tags(HashMap<UnsafeSlice, UnsafeSlice>);
+/// Tags in the order they were received.
+///
+/// Values are stored escaped, as they appear in the source. Key-only tags
+/// (`@key`) have no value. They and tags with empty values (`@key=`) are kept,
+/// so that the message can be encoded again, but the getters treat them as
+/// missing.
+#[derive(Clone, Debug)]
+pub struct Tags(Vec<(ArcSlice, Option<ArcSlice>)>);
 
 impl Deref for Tags {
-    type Target = HashMap<UnsafeSlice, UnsafeSlice>;
+    type Target = [(ArcSlice, Option<ArcSlice>)];
     fn deref(&self) -> &Self::Target { &self.0 }
 }
 
+/// Tags are compared like maps, ignoring their order and empty or missing
+/// values
+impl PartialEq for Tags {
+    fn eq(&self, other: &Tags) -> bool {
+        fn map(tags: &Tags) -> HashMap<&ArcSlice, &ArcSlice> {
+            tags.0
+                .iter()
+                .filter_map(|(key, value)| Some((key, value.as_ref().filter(|value| !value.is_empty())?)))
+                .collect()
+        }
+        map(self) == map(other)
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum DurationKind {
     Nanoseconds,
@@ -152,102 +359,75 @@ pub enum DurationKind {
 }
 
 impl Tags {
-    /// Parses IRC tags in the form
+    /// Parses IRC tags according to the IRCv3 message-tags spec:
     ///
     /// `@key0=[value0];key1=[value1];...;keyN-1=[valueN-1];keyN=[valueN] `
     ///
-    /// `[value]`s are optional
+    /// `[value]`s are optional, and stored escaped. Empty tags (`;;`) and tags
+    /// with an empty key are skipped. The only deviation from the spec is that
+    /// a space followed by `;` doesn't end the tags, because Twitch sometimes
+    /// sends values with a trailing space (e.g. `display-name=pajbot ;`).
     ///
     /// Returns (tags, remainder)
-    pub fn parse(data: &str) -> (Tags, &str) {
-        let data = match data.strip_prefix('@') {
-            Some(v) => v,
-            None => data,
+    pub fn parse(data: ArcSlice) -> (Tags, ArcSlice) {
+        let data = match data.as_str().strip_prefix('@') {
+            Some(v) => data.slice(v),
+            None => return (Tags(Vec::new()), data),
         };
-        let mut map: HashMap<UnsafeSlice, UnsafeSlice> = HashMap::new();
+        let text = data.as_str();
+        let bytes = text.as_bytes();
+        let mut tags = Vec::new();
+        // start of the current tag, and the position of its first `=`
+        let (mut start, mut eq) = (0, None);
         let mut end = 0;
-
-        let mut current_key: Option<&str> = None;
-        let mut remainder = data;
-        let mut local_i = 0;
-        let mut previous_char = "";
-
-        // TODO: this apparently isn't enough to parse unicode...
-        for (i, c) in data.grapheme_indices(true) {
-            match current_key {
-                None => match c {
-                    // when we parse ';', save key, and parse value
-                    "=" => {
-                        current_key = Some(&remainder[..local_i]);
-                        // remainder is set without this '='
-                        remainder = &remainder[(local_i + 1)..];
-                        local_i = 0;
-                    }
-                    c => {
-                        local_i += c.as_bytes().len();
-                    }
-                },
-                Some(key) => match c {
-                    // when we parse ';', save value, push it into map
-                    // and then parse key
-                    ";" => {
-                        // TODO the error may be here, the `..local_i` could be wrong.
-                        // have to investigate.
-                        let value = &remainder[..local_i];
-                        if !value.is_empty() {
-                            map.insert(key.into(), value.into());
-                        }
-                        // remainder is set without this ';'
-                        remainder = &remainder[(local_i + 1)..];
-                        local_i = 0;
-                        current_key = None;
-                    }
-                    // if we parse a ' :', that's the end of tags
-                    ":" if previous_char == " " => {
-                        let value = &remainder[..(local_i - 1)];
-                        if !value.trim().is_empty() {
-                            map.insert(key.into(), value.into());
-                        }
-                        end = i;
-                        break;
-                    }
-                    c => {
-                        local_i += c.as_bytes().len();
-                    }
-                },
+        loop {
+            let byte = bytes.get(end);
+            let done = match byte {
+                None => true,
+                Some(b' ') => bytes.get(end + 1) != Some(&b';'),
+                _ => false,
+            };
+            if done || byte == Some(&b';') {
+                // all delimiters are ASCII, so every index is a char boundary
+                let (key, value) = match eq {
+                    Some(eq) => (&text[start..eq], Some(&text[eq + 1..end])),
+                    None => (&text[start..end], None),
+                };
+                if !key.is_empty() {
+                    tags.push((data.slice(key), value.map(|value| data.slice(value))));
+                }
+                if done {
+                    break;
+                }
+                start = end + 1;
+                eq = None;
+            } else if byte == Some(&b'=') && eq.is_none() {
+                eq = Some(end);
             }
-            previous_char = c;
+            end += 1;
         }
 
-        (Tags(map), &data[end..])
+        (Tags(tags), data.slice(&text[end..]))
     }
 
     /// Iterates the tags to find one with key == `key`.
-    pub fn get(&self, key: &str) -> Option<UnsafeSlice> {
-        for (item_key, item_value) in self.0.iter() {
-            if key == item_key.as_ref() {
-                return Some(*item_value);
+    ///
+    /// If `key` appears more than once, the last value wins. The value is
+    /// returned escaped, see [`Tags::get_ns`] for free text.
+    pub fn get(&self, key: &str) -> Option<ArcSlice> {
+        for (item_key, item_value) in self.0.iter().rev() {
+            match item_value {
+                Some(value) if key == item_key.as_str() && !value.is_empty() => return Some(value.clone()),
+                _ => (),
             }
         }
 
         None
     }
 
-    /// Parses a string, transforming all whitespace "\\s" to actual whitespace.
-    pub fn get_ns(&self, key: &str) -> Option<String> {
-        self.get(key).map(|v| {
-            let v = v.as_ref();
-            let mut out = String::with_capacity(v.len());
-            let mut parts = v.split("\\s").peekable();
-            while let Some(part) = parts.next() {
-                out.push_str(part);
-                if parts.peek().is_some() {
-                    out.push(' ');
-                }
-            }
-            out
-        })
-    }
+    /// Parses a string, unescaping it according to the IRCv3 message-tags spec
+    /// (see [`unescape`]).
+    pub fn get_ns(&self, key: &str) -> Option<String> { self.get(key).map(|v| unescape(v.as_str()).into_owned()) }
 
     /// Parses a number
     pub fn get_number<N>(&self, key: &str) -> Option<N>
@@ -264,12 +444,12 @@ impl Tags {
         }
     }
 
-    /// Parses a numeric bool (0 or 1)
+    /// Parses a numeric (0 or 1) or literal (false or true) bool
     pub fn get_bool(&self, key: &str) -> Option<bool> {
         match self.get(key) {
             Some(v) => match v.as_ref() {
-                "0" => Some(false),
-                "1" => Some(true),
+                "0" | "false" => Some(false),
+                "1" | "true" => Some(true),
                 _ => None,
             },
             None => None,
@@ -277,13 +457,13 @@ impl Tags {
     }
 
     /// Parses a comma-separated list of values
-    pub fn get_csv(&self, key: &str) -> Option<Vec<UnsafeSlice>> {
+    pub fn get_csv(&self, key: &str) -> Option<Vec<ArcSlice>> {
         match self.get(key) {
             Some(v) => Some(
-                v.as_ref()
+                v.as_str()
                     .split(',')
-                    .filter(|v| !v.is_empty())
-                    .map(|v| v.into())
+                    .filter(|part| !part.is_empty())
+                    .map(|part| v.slice(part))
                     .collect(),
             ),
             None => None,
@@ -316,9 +496,7 @@ impl Tags {
 
     /// Like `.get()`, but returns an `Error` in case the key doesn't exist,
     /// or is invalid in some way
-    pub fn require(&self, key: &str) -> Result<UnsafeSlice> {
-        self.get(key).ok_or_else(|| Error::MissingTag(key.into()))
-    }
+    pub fn require(&self, key: &str) -> Result<ArcSlice> { self.get(key).ok_or_else(|| Error::MissingTag(key.into())) }
 
     /// Like `.get_ns()`, but returns an `Error` in case the key doesn't exist,
     /// or is invalid in some way
@@ -344,7 +522,7 @@ impl Tags {
 
     /// Like `.get_csv()`, but returns an `Error` in case the key doesn't exist,
     /// or is invalid in some way
-    pub fn require_csv(&self, key: &str) -> Result<Vec<UnsafeSlice>> {
+    pub fn require_csv(&self, key: &str) -> Result<Vec<ArcSlice>> {
         self.get_csv(key).ok_or_else(|| Error::MissingTag(key.into()))
     }
 
@@ -362,11 +540,21 @@ impl Tags {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Prefix {
-    pub nick: Option<UnsafeSlice>,
-    pub user: Option<UnsafeSlice>,
-    pub host: UnsafeSlice,
+    pub nick: Option<ArcSlice>,
+    pub user: Option<ArcSlice>,
+    pub host: ArcSlice,
+}
+
+impl Display for Prefix {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match (&self.nick, &self.user) {
+            (Some(nick), Some(user)) => write!(f, "{}!{}@{}", nick, user, self.host),
+            (Some(nick), None) => write!(f, "{}@{}", nick, self.host),
+            _ => write!(f, "{}", self.host),
+        }
+    }
 }
 
 impl Prefix {
@@ -377,8 +565,8 @@ impl Prefix {
     /// * `nick!user@host`
     ///
     /// Returns (prefix, remainder)
-    pub fn parse(data: &str) -> (Option<Prefix>, &str) {
-        let data = data.trim_start();
+    pub fn parse(source: ArcSlice) -> (Option<Prefix>, ArcSlice) {
+        let data = source.as_str().trim_start();
         if data.starts_with(':') {
             let start = 0;
             let end = match data[start..].find(' ') {
@@ -404,29 +592,29 @@ impl Prefix {
 
             (
                 Some(Prefix {
-                    nick: nick.map(|v| v.into()),
-                    user: user.map(|v| v.into()),
-                    host: host.into(),
+                    nick: nick.map(|v| source.slice(v)),
+                    user: user.map(|v| source.slice(v)),
+                    host: source.slice(host),
                 }),
-                &data[end..],
+                source.slice(&data[end..]),
             )
         } else {
-            (None, data)
+            (None, source.slice(data))
         }
     }
 }
 
 pub struct Channel;
 impl Channel {
-    pub fn parse(data: &str) -> (Option<UnsafeSlice>, &str) {
-        let data = data.trim_start();
+    pub fn parse(source: ArcSlice) -> (Option<ArcSlice>, ArcSlice) {
+        let data = source.as_str().trim_start();
         let (mut start, mut end) = (None, data.len());
         for (i, c) in data.char_indices() {
             match c {
                 // No channel, because we found the start of :message
                 // TODO: write test that takes into account '#' being present in the message
                 ':' if start.is_none() => {
-                    return (None, data);
+                    return (None, source.slice(data));
                 }
                 // Either we found `end`
                 ' ' if start.is_some() => {
@@ -435,7 +623,7 @@ impl Channel {
                 }
                 // or nothing
                 ' ' => {
-                    return (None, data);
+                    return (None, source.slice(data));
                 }
                 // We found `start`
                 '#' => start = Some(i),
@@ -444,30 +632,35 @@ impl Channel {
         }
         let (start, end) = match (start, end) {
             (Some(s), e) => (s, e),
-            _ => return (None, data),
+            _ => return (None, source.slice(data)),
         };
-        let (channel, remainder) = data[start..].split_at(end);
-        (Some((&channel[1..]).into()), remainder)
+        (Some(source.slice(&data[start + 1..end])), source.slice(&data[end..]))
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
-pub struct Params(UnsafeSlice);
+#[derive(Clone, Debug, PartialEq)]
+pub struct Params(ArcSlice);
 impl Params {
     /// Parse a params list
     ///
     /// Valid form: `[:]param0 [:]param1 [:]param2 [:]param3"
-    pub fn parse(data: &str) -> Option<Params> {
-        let data = data.trim_start();
+    pub fn parse(source: ArcSlice) -> Option<Params> {
+        let data = source.as_str().trim_start();
         if data.is_empty() {
             None
         } else {
-            Some(Params(data.into()))
+            Some(Params(source.slice(data)))
         }
     }
 
     pub fn raw(&self) -> &str { self.0.as_str() }
 
+    /// Returns the part of the params which `part` points to, e.g. one of the
+    /// items returned by [`Params::iter`].
+    ///
+    /// Panics if `part` is not a slice of `self.raw()`.
+    pub fn slice(&self, part: &str) -> ArcSlice { self.0.slice(part) }
+
     pub fn iter(&self) -> impl Iterator<Item = &str> {
         self.0
             .as_str()
@@ -484,7 +677,7 @@ mod tests {
     use super::*;
 
     #[test]
-    fn parse_empty_prefix() { assert_eq!(None, Prefix::parse("PING :tmi.twitch.tv").0) }
+    fn parse_empty_prefix() { assert_eq!(None, Prefix::parse("PING :tmi.twitch.tv".into()).0) }
 
     #[test]
     fn parse_prefix_host_only() {
@@ -495,7 +688,7 @@ mod tests {
                 user: None,
                 host: "tmi.twitch.tv".into()
             }),
-            Prefix::parse(":tmi.twitch.tv").0
+            Prefix::parse(":tmi.twitch.tv".into()).0
         );
     }
 
@@ -508,7 +701,7 @@ mod tests {
                 user: None,
                 host: "test.tmi.twitch.tv".into()
             }),
-            Prefix::parse(":test@test.tmi.twitch.tv").0
+            Prefix::parse(":test@test.tmi.twitch.tv".into()).0
         );
     }
 
@@ -521,12 +714,17 @@ mod tests {
                 user: Some("test".into()),
                 host: "test.tmi.twitch.tv".into()
             }),
-            Prefix::parse(":test!test@test.tmi.twitch.tv").0
+            Prefix::parse(":test!test@test.tmi.twitch.tv".into()).0
         );
     }
 
     #[test]
-    fn parse_command() { assert_eq!(Command::Privmsg, Command::parse("PRIVMSG").0) }
+    fn parse_command() { assert_eq!(Command::Privmsg, Command::parse("PRIVMSG".into()).0) }
+
+    #[test]
+    fn parse_channel_inside_token() {
+        assert_eq!((Some("b".into()), " :text".into()), Channel::parse("a#b :text".into()));
+    }
 
     // TODO: tests for parsing other message types
 
@@ -535,12 +733,12 @@ mod tests {
         let src = "PING :tmi.twitch.tv".to_string();
         assert_eq!(
             Message {
-                tags: Tags(HashMap::new()),
+                tags: Tags(Vec::new()),
                 prefix: None,
                 cmd: Command::Ping,
                 channel: None,
                 params: Some(Params(":tmi.twitch.tv".into())),
-                source: Pin::new(src.clone())
+                source: src.clone().into()
             },
             Message::parse(src).unwrap()
         )
@@ -552,7 +750,7 @@ mod tests {
 
         assert_eq!(
             Message {
-                tags: Tags(HashMap::new()),
+                tags: Tags(Vec::new()),
                 prefix: Some(Prefix {
                     nick: Some("test".into()),
                     user: Some("test".into()),
@@ -561,7 +759,7 @@ mod tests {
                 cmd: Command::Join,
                 channel: Some("channel".into()),
                 params: None,
-                source: Pin::new(src.clone())
+                source: src.clone().into()
             },
             Message::parse(src).unwrap()
         )
@@ -602,7 +800,7 @@ mod tests {
                         ("user-id", "29803735"),
                     ]
                     .into_iter()
-                    .map(|(k, v)| (k.into(), v.into()))
+                    .map(|(k, v)| (k.into(), Some(v.into())))
                     .collect()
                 ),
                 prefix: Some(Prefix {
@@ -613,7 +811,7 @@ mod tests {
                 cmd: Command::Privmsg,
                 channel: Some("pajlada".into()),
                 params: Some(Params(":dank cam".into())),
-                source: Pin::new(src.clone())
+                source: src.clone().into()
             },
             Message::parse(src).unwrap()
         );
@@ -640,7 +838,7 @@ mod tests {
                         ("display-name", "pajbot "),
                     ]
                     .into_iter()
-                    .map(|(k, v)| (k.into(), v.into()))
+                    .map(|(k, v)| (k.into(), Some(v.into())))
                     .collect(),
                 ),
                 prefix: Some(Prefix {
@@ -651,7 +849,7 @@ mod tests {
                 cmd: Command::Whisper,
                 channel: None,
                 params: Some(Params("randers :Riftey Kappa".into())),
-                source: Pin::new(src.clone()),
+                source: src.clone().into(),
             },
             Message::parse(src).unwrap()
         );
@@ -678,7 +876,7 @@ mod tests {
                         ("display-name", "pajbot"),
                     ]
                     .into_iter()
-                    .map(|(k, v)| (k.into(), v.into()))
+                    .map(|(k, v)| (k.into(), Some(v.into())))
                     .collect(),
                 ),
                 prefix: Some(Prefix {
@@ -689,7 +887,7 @@ mod tests {
                 cmd: Command::Whisper,
                 channel: None,
                 params: Some(Params("randers :\x01ACTION Riftey Kappa\x01".into())),
-                source: Pin::new(src.clone()),
+                source: src.clone().into(),
             },
             Message::parse(src).unwrap()
         );
@@ -712,7 +910,7 @@ mod tests {
                         ("tmi-sent-ts", "-6795364578871")
                     ]
                     .into_iter()
-                    .map(|(k, v)| (k.into(), v.into()))
+                    .map(|(k, v)| (k.into(), Some(v.into())))
                     .collect(),
                 ),
                 prefix: Some(Prefix {
@@ -725,7 +923,7 @@ mod tests {
                 params: Some(Params(
                     ":Pong! Uptime: 6h,15m; Temperature: 54.8°C; Latency to TMI: 183ms; Commands used: 795".into()
                 )),
-                source: Pin::new(src.clone()),
+                source: src.clone().into(),
             },
             Message::parse(src).unwrap()
         )
@@ -733,7 +931,6 @@ mod tests {
 
     #[test]
     fn parse_tags_with_long_unicode_chars() {
-        // TODO
         let src = "\
         @login=supibot;room-id=;target-msg-id=25fd76d9-4731-4907-978e-a391134ebd67;tmi-sent-ts=-6795364578871;\
         some-tag=とりくしい :tmi.twitch.tv CLEARMSG #randers :asdf\
@@ -749,7 +946,7 @@ mod tests {
                         ("some-tag", "とりくしい")
                     ]
                     .into_iter()
-                    .map(|(k, v)| (k.into(), v.into()))
+                    .map(|(k, v)| (k.into(), Some(v.into())))
                     .collect(),
                 ),
                 prefix: Some(Prefix {
@@ -760,9 +957,280 @@ mod tests {
                 cmd: Command::Clearmsg,
                 channel: Some("randers".into()),
                 params: Some(Params(":asdf".into())),
-                source: Pin::new(src.clone()),
+                source: src.clone().into(),
             },
             Message::parse(src).unwrap()
         )
     }
+
+    #[test]
+    fn encode_real_messages() {
+        let sources = [
+            "PING :tmi.twitch.tv",
+            ":test!test@test.tmi.twitch.tv JOIN #channel",
+            ":tmi.twitch.tv CAP * ACK :twitch.tv/commands twitch.tv/tags",
+            "@badge-info=;badges=;color=#0000FF;display-name=JuN1oRRRR;emotes=;flags=;\
+             id=e9d998c3-36f1-430f-89ec-6b887c28af36;mod=0;room-id=11148817;subscriber=0;tmi-sent-ts=1594545155039;\
+             turbo=0;user-id=29803735;user-type= :jun1orrrr!jun1orrrr@jun1orrrr.tmi.twitch.tv PRIVMSG #pajlada \
+             :dank cam",
+            "@badges=;color=#2E8B57;display-name=pajbot ;emotes=25:7-11;message-id=2034;\
+             thread-id=40286300_82008718;turbo=0;user-id=82008718;user-type= \
+             :pajbot!pajbot@pajbot.tmi.twitch.tv WHISPER randers :Riftey Kappa",
+            "@login=supibot;room-id=;target-msg-id=25fd76d9-4731-4907-978e-a391134ebd67;tmi-sent-ts=-6795364578871;\
+             some-tag=とりくしい :tmi.twitch.tv CLEARMSG #randers :asdf",
+            "@msg-id=subgift;msg-param-sub-plan-name=Channel\\sSubscription\\s(forsen);system-msg=A\\sgift\\:\\sthanks \
+             :tmi.twitch.tv USERNOTICE #forsen :hello",
+        ];
+        for source in sources.iter() {
+            assert_eq!(*source, Message::parse(source.to_string()).unwrap().to_string());
+        }
+
+        // key-only tags and empty values are told apart
+        let source = "@flag;key= :nick@host PRIVMSG #channel :text";
+        let message = Message::parse(source.into()).unwrap();
+        assert_eq!(source, message.to_string());
+        assert_eq!(None, message.tags[0].1);
+        assert_eq!(Some("".into()), message.tags[1].1);
+    }
+
+    #[test]
+    fn escape_tag_values() {
+        assert_eq!("plain", escape("plain"));
+        assert_eq!("a\\sb\\:c\\\\d\\re\\n", escape("a b;c\\d\re\n"));
+        assert_eq!("plain", unescape("plain"));
+        assert_eq!("a b;c\\d\re\n", unescape("a\\sb\\:c\\\\d\\re\\n"));
+        // unknown escapes are replaced by the escaped character
+        assert_eq!("ab", unescape("\\ab"));
+        // a trailing backslash is dropped
+        assert_eq!("a", unescape("a\\"));
+    }
+
+    #[test]
+    fn duplicate_and_empty_tags() {
+        let message = Message::parse("@a=1;b=;a=2 PING".into()).unwrap();
+        assert_eq!(Some("2".into()), message.tags.get("a"));
+        assert_eq!(None, message.tags.get("b"));
+        assert_eq!(3, message.tags.len());
+    }
+
+    #[test]
+    fn build_tagged_privmsg() {
+        let message = Message::build("PRIVMSG")
+            .tag("reply-parent-msg-id", "abc")
+            .tag("client-nonce", "a b;c")
+            .channel("forsen")
+            .trailing("hello there")
+            .finish();
+        assert_eq!(
+            "@reply-parent-msg-id=abc;client-nonce=a\\sb\\:c PRIVMSG #forsen :hello there",
+            message.to_string()
+        );
+        assert_eq!(Command::Privmsg, message.cmd);
+        assert_eq!(Some("forsen".into()), message.channel);
+        assert_eq!("a b;c", unescape(message.tags.get("client-nonce").unwrap().as_str()));
+
+        let message = Message::build("WHISPER")
+            .prefix(Some("a"), Some("a"), "a.tmi.twitch.tv")
+            .params("b")
+            .trailing("hi")
+            .finish();
+        assert_eq!(":a!a@a.tmi.twitch.tv WHISPER b :hi", message.to_string());
+    }
+
+    #[test]
+    fn parse_tags_per_spec() {
+        let (tags, remainder) = Tags::parse("@a=1;;=x;flag;b=c=d;e=\\s\\:;a=2 :tmi.twitch.tv PING".into());
+        assert_eq!(" :tmi.twitch.tv PING", remainder.as_str());
+        let tags: Vec<_> = tags
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_ref().map(ArcSlice::as_str)))
+            .collect();
+        assert_eq!(
+            vec![
+                ("a", Some("1")),
+                ("flag", None),
+                ("b", Some("c=d")),
+                ("e", Some("\\s\\:")),
+                ("a", Some("2"))
+            ],
+            tags
+        );
+
+        let (tags, remainder) = Tags::parse("PING :tmi.twitch.tv".into());
+        assert!(tags.is_empty());
+        assert_eq!("PING :tmi.twitch.tv", remainder.as_str());
+
+        // a lone `@` has no tags
+        let (tags, remainder) = Tags::parse("@ PING".into());
+        assert!(tags.is_empty());
+        assert_eq!(" PING", remainder.as_str());
+    }
+
+    #[test]
+    fn get_unescaped_tags() {
+        let message = Message::parse(
+            "@system-msg=a\\sb\\:c\\\\d\\e\\;msg-param-sub-plan-name=Channel\\sSubscription\\s(forsenlol) \
+             :tmi.twitch.tv USERNOTICE #forsen"
+                .into(),
+        )
+        .unwrap();
+        assert_eq!(Some("a b;c\\de".to_string()), message.tags.get_ns("system-msg"));
+        assert_eq!(
+            "Channel Subscription (forsenlol)",
+            message.tags.require_ns("msg-param-sub-plan-name").unwrap()
+        );
+    }
+
+    /// Lines shaped like the ones TMI sends, partly captured and partly made up
+    /// to cover more cases
+    const CORPUS: &[&str] = &[
+        ":tmi.twitch.tv 001 justinfan12345 :Welcome, GLHF!",
+        ":tmi.twitch.tv CAP * ACK :twitch.tv/commands twitch.tv/tags twitch.tv/membership",
+        ":justinfan12345!justinfan12345@justinfan12345.tmi.twitch.tv JOIN #pajlada",
+        ":justinfan12345.tmi.twitch.tv 353 justinfan12345 = #pajlada :justinfan12345",
+        "@emote-only=0;followers-only=-1;r9k=0;rituals=0;room-id=11148817;slow=0;subs-only=0 \
+         :tmi.twitch.tv ROOMSTATE #pajlada",
+        "@badge-info=subscriber/22;badges=moderator/1,subscriber/12;color=#19E6E6;display-name=randers;\
+         emote-sets=0,42,237,1564983,300374282;mod=1;subscriber=1;user-type=mod :tmi.twitch.tv USERSTATE #pajlada",
+        "@badge-info=;badges=;color=#0000FF;display-name=JuN1oRRRR;emotes=;flags=;\
+         id=e9d998c3-36f1-430f-89ec-6b887c28af36;mod=0;room-id=11148817;subscriber=0;tmi-sent-ts=1594545155039;\
+         turbo=0;user-id=29803735;user-type= :jun1orrrr!jun1orrrr@jun1orrrr.tmi.twitch.tv PRIVMSG #pajlada \
+         :dank cam",
+        "@badge-info=subscriber/5;badges=subscriber/3,premium/1;client-nonce=4e0a3f2b5d8f2a7c9b1e6d0f8a3c5b7e;\
+         color=#FF69B4;display-name=ＫＯＢＥ;emotes=25:0-4,12-16/1902:6-10;flags=;id=4ba3d10d-6b34-4d0e-9b46-1e7c1f2d3e4a;\
+         mod=0;room-id=22484632;subscriber=1;tmi-sent-ts=1594556065407;turbo=0;user-id=40286300;user-type= \
+         :kobe!kobe@kobe.tmi.twitch.tv PRIVMSG #forsen :Kappa Keepo Kappa",
+        "@badge-info=;badges=;color=;display-name=테스트;emotes=;flags=0-6:S.6;id=1d5a9c4e-7f2b-4c8d-9e0a-3b6f1c2d4e5a;\
+         mod=0;room-id=22484632;subscriber=0;tmi-sent-ts=1594556065408;turbo=0;user-id=12345;user-type= \
+         :test!test@test.tmi.twitch.tv PRIVMSG #forsen :\x01ACTION 안녕하세요 👋\x01",
+        "@badges=;color=#2E8B57;display-name=pajbot ;emotes=25:7-11;message-id=2034;\
+         thread-id=40286300_82008718;turbo=0;user-id=82008718;user-type= \
+         :pajbot!pajbot@pajbot.tmi.twitch.tv WHISPER randers :Riftey Kappa",
+        "@badge-info=subscriber/5;badges=subscriber/3,premium/1;color=#19E6E6;display-name=randers;emotes=;flags=;\
+         id=3d830f12-795c-447d-af3c-ea05e40fbddb;login=randers;mod=0;msg-id=resub;msg-param-cumulative-months=5;\
+         msg-param-months=0;msg-param-should-share-streak=0;\
+         msg-param-sub-plan-name=Channel\\sSubscription\\s(forsenlol);msg-param-sub-plan=Prime;room-id=22484632;\
+         subscriber=1;system-msg=randers\\ssubscribed\\swith\\sTwitch\\sPrime.\\sThey've\\ssubscribed\\sfor\\s5\\smonths!;\
+         tmi-sent-ts=1563096499780;user-id=40286300;user-type= :tmi.twitch.tv USERNOTICE #forsen :feelsgoodman",
+        "@badge-info=;badges=;color=;display-name=AnAnonymousGifter;emotes=;flags=;\
+         id=62c3fd39-84cc-452a-9096-628a5306633a;login=ananonymousgifter;mod=0;msg-id=subgift;msg-param-fun-string=\
+         FunStringThree;msg-param-gift-months=1;msg-param-months=13;msg-param-origin-id=da\\s39\\sa3\\see\\s5e;\
+         msg-param-recipient-display-name=Dot0422;msg-param-recipient-id=151784015;\
+         msg-param-recipient-user-name=dot0422;msg-param-sub-plan-name=Channel\\sSubscription\\s(xqcow)\\:\\s$24.99\\sSub;\
+         msg-param-sub-plan=3000;room-id=71092938;subscriber=0;\
+         system-msg=An\\sanonymous\\suser\\sgifted\\sa\\sTier\\s3\\ssub\\sto\\sDot0422!\\s;tmi-sent-ts=1594583782376;\
+         user-id=274598607;user-type= :tmi.twitch.tv USERNOTICE #xqcow",
+        "@badge-info=;badges=;color=;display-name=SevenTest1;emotes=30259:0-6;id=37feed0f-b9c7-4c3a-b475-21c6c6d21c3d;\
+         login=seventest1;mod=0;msg-id=ritual;msg-param-ritual-name=new_chatter;room-id=6316121;subscriber=0;\
+         system-msg=Seventoes\\sis\\snew\\shere!;tmi-sent-ts=1508363903826;turbo=0;user-id=131260580;user-type= \
+         :tmi.twitch.tv USERNOTICE #seventoes :HeyGuys",
+        "@login=supibot;room-id=;target-msg-id=25fd76d9-4731-4907-978e-a391134ebd67;tmi-sent-ts=-6795364578871 \
+         :tmi.twitch.tv CLEARMSG #randers :Pong! Uptime: 6h,15m; Temperature: 54.8°C; Latency to TMI: 183ms",
+        "@ban-duration=600;room-id=22484632;target-user-id=40286300;tmi-sent-ts=1594561360331 \
+         :tmi.twitch.tv CLEARCHAT #forsen :randers",
+        "@room-id=22484632;tmi-sent-ts=1594561392337 :tmi.twitch.tv CLEARCHAT #forsen",
+        "@msg-id=msg_banned :tmi.twitch.tv NOTICE #forsen :You are permanently banned from talking in forsen.",
+        ":tmi.twitch.tv NOTICE * :Login authentication failed",
+        ":tmi.twitch.tv HOSTTARGET #randers :leebaxd 0",
+        "@badge-info=;badges=;color=#19E6E6;display-name=randers;emote-sets=0,42,237;user-id=40286300;user-type= \
+         :tmi.twitch.tv GLOBALUSERSTATE",
+        ":tmi.twitch.tv RECONNECT",
+        "PING :tmi.twitch.tv",
+    ];
+
+    #[test]
+    fn parse_corpus() {
+        for line in CORPUS {
+            let message = Message::parse(line.to_string()).unwrap();
+            assert_eq!(*line, message.to_string());
+            for (key, value) in message.tags.iter() {
+                assert!(!key.as_str().is_empty());
+                if let Some(value) = value {
+                    assert!(!value.as_str().contains(';'), "{}: {}", key, value);
+                }
+            }
+            let _ = crate::tmi::Message::parse_irc(message);
+
+            // parsing truncated or corrupted lines must not panic
+            for (i, _) in line.char_indices() {
+                let _ = Message::parse(line[..i].to_string()).map(crate::tmi::Message::parse_irc);
+                let _ = Message::parse(line[i..].to_string()).map(crate::tmi::Message::parse_irc);
+                let corrupted = format!("{} ;{}", &line[..i], &line[i..]);
+                let _ = Message::parse(corrupted).map(crate::tmi::Message::parse_irc);
+            }
+        }
+    }
+
+    mod roundtrip {
+        use proptest::{collection, option, prelude::*};
+
+        use super::super::*;
+
+        fn command() -> impl Strategy<Value = String> {
+            prop_oneof![
+                Just("PRIVMSG".to_string()),
+                Just("USERNOTICE".to_string()),
+                Just("CAP".to_string()),
+                "[A-Z]{1,12}",
+                "[0-9]{3}",
+            ]
+        }
+
+        fn prefix() -> impl Strategy<Value = (Option<String>, Option<String>, String)> {
+            let part = "[a-z0-9_]{1,12}";
+            prop_oneof![
+                ("[a-z.]{1,20}").prop_map(|host| (None, None, host)),
+                (part, "[a-z.]{1,20}").prop_map(|(nick, host)| (Some(nick), None, host)),
+                (part, part, "[a-z.]{1,20}").prop_map(|(nick, user, host)| (Some(nick), Some(user), host)),
+            ]
+        }
+
+        prop_compose! {
+            fn message()(
+                tags in collection::btree_map("[a-z][a-z0-9-]{0,10}", option::of("(\\PC|[ ;\\\\\r\n])*"), 0..8),
+                prefix in option::of(prefix()),
+                cmd in command(),
+                channel in option::of("[a-z0-9_]{1,25}"),
+                middle in option::of("[a-z0-9_]{1,12}"),
+                trailing in option::of("\\PC*"),
+            ) -> Message {
+                let mut builder = Message::build(&cmd);
+                for (key, value) in tags.iter() {
+                    builder = match value {
+                        Some(value) => builder.tag(key, value),
+                        None => builder.flag(key),
+                    };
+                }
+                if let Some((nick, user, host)) = &prefix {
+                    builder = builder.prefix(nick.as_deref(), user.as_deref(), host);
+                }
+                if let Some(channel) = &channel {
+                    builder = builder.channel(channel);
+                }
+                if let Some(middle) = &middle {
+                    builder = builder.params(middle);
+                }
+                if let Some(trailing) = &trailing {
+                    // the parser trims the line
+                    builder = builder.trailing(trailing.trim_end());
+                }
+                builder.finish()
+            }
+        }
+
+        proptest! {
+            #[test]
+            fn parse_encoded(message in message()) {
+                let encoded = message.to_string();
+                prop_assert_eq!(&message.source[..], encoded.as_str());
+                prop_assert_eq!(message, Message::parse(encoded).unwrap());
+            }
+
+            #[test]
+            fn unescape_escaped(value in ".*") {
+                let escaped = escape(&value);
+                prop_assert_eq!(value.as_str(), unescape(&escaped));
+            }
+        }
+    }
 }
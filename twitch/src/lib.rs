@@ -5,10 +5,12 @@
 //! * [`tmi`](./twitch) - parsing Twitch-specific commands (PRIVMSG, ROOMSTATE,
 //!   USERNOTICE, etc.)
 //! * [`conn`](./conn) - TMI connection utility
+//! * [`testing`](./testing) - in-memory fake TMI server
 #![feature(str_split_once)]
 
 pub mod conn;
 pub mod irc;
+pub mod testing;
 pub mod tmi;
 pub(crate) mod util;
 
@@ -0,0 +1,344 @@
+//! In-memory fake TMI server, for testing without a network connection
+//!
+//! The server answers the handshake (CAP, PASS/NICK), JOINs (with JOIN,
+//! USERSTATE and ROOMSTATE), PARTs and PINGs the same way TMI does. Logging in
+//! with [`INVALID_TOKEN`] fails, any other token is accepted. Anything
+//! else has to be scripted by the test, through the [`Client`] returned by
+//! [`Server::accept`].
+//!
+//! ```no_run
+//! # async fn test() -> twitch::conn::Result<()> {
+//! let mut server = twitch::testing::Server::new();
+//! let (sender, mut reader) = twitch::conn::connect_with(Default::default(), server.transport())
+//!     .await?
+//!     .split();
+//! let mut client = server.accept().await;
+//!
+//! sender.join("test").await?;
+//! client.wait_for("JOIN #test").await;
+//! client.privmsg("test", "someone", "hello");
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use futures::future::BoxFuture;
+use tokio::{
+    io::{duplex, split, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream, ReadBuf},
+    sync::mpsc,
+};
+
+use crate::conn::{
+    transport::{Stream, Transport},
+    Error, Result,
+};
+
+/// Capacity of the in-memory pipe in each direction
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// Token which the server rejects
+pub const INVALID_TOKEN: &str = "oauth:invalid";
+
+/// Capabilities which the server acknowledges, the rest are rejected
+const CAPABILITIES: &[&str] = &["twitch.tv/commands", "twitch.tv/tags", "twitch.tv/membership"];
+
+/// Fake TMI server
+pub struct Server {
+    dialer: Dialer,
+    connections: mpsc::UnboundedReceiver<Client>,
+}
+
+impl Server {
+    pub fn new() -> Server {
+        let (sender, connections) = mpsc::unbounded_channel();
+        Server {
+            dialer: Dialer { connections: sender },
+            connections,
+        }
+    }
+
+    /// Transport which connects to this server
+    pub fn transport(&self) -> Dialer { self.dialer.clone() }
+
+    /// Waits for the next connection, including reconnects.
+    pub async fn accept(&mut self) -> Client {
+        self.connections
+            .recv()
+            .await
+            .expect("Server holds a Dialer, so the channel is never closed")
+    }
+}
+
+impl Default for Server {
+    fn default() -> Self { Server::new() }
+}
+
+/// Connects to a [`Server`]
+#[derive(Clone, Debug)]
+pub struct Dialer {
+    connections: mpsc::UnboundedSender<Client>,
+}
+
+impl Transport for Dialer {
+    fn connect(&self) -> BoxFuture<'_, Result<Box<dyn Stream>>> {
+        Box::pin(async move {
+            let (client, server) = duplex(PIPE_CAPACITY);
+            let server = Client::spawn(server);
+            let client = Pipe {
+                inner: client,
+                closed: server.closed.clone(),
+                stalled: server.stalled.clone(),
+            };
+            self.connections.send(server).map_err(|_| Error::ConnectionFailed)?;
+            Ok(Box::new(client) as Box<dyn Stream>)
+        })
+    }
+}
+
+/// Client side of one connection, writing to it fails once the [`Client`] is
+/// dropped, like writing to a TCP connection which was reset
+struct Pipe {
+    inner: DuplexStream,
+    closed: Arc<AtomicBool>,
+    /// Writes never complete while set, see [`Client::stall`]
+    stalled: Arc<AtomicBool>,
+}
+
+impl AsyncRead for Pipe {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Pipe {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.closed.load(Ordering::SeqCst) {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if this.stalled.load(Ordering::SeqCst) {
+            return Poll::Pending;
+        }
+        Pin::new(&mut this.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Server side of one connection.
+///
+/// Dropping it closes the connection.
+pub struct Client {
+    /// Lines to send to the client, `None` closes the connection
+    outgoing: mpsc::UnboundedSender<Option<String>>,
+    /// Lines sent by the client
+    received: mpsc::UnboundedReceiver<String>,
+    /// Nothing is sent to the client while set
+    frozen: Arc<AtomicBool>,
+    /// Set once dropped
+    closed: Arc<AtomicBool>,
+    /// The client's writes hang while set
+    stalled: Arc<AtomicBool>,
+}
+
+impl Client {
+    pub(crate) fn spawn(stream: impl Stream + 'static) -> Client {
+        let (reader, mut writer) = split(stream);
+        let (outgoing, mut to_write) = mpsc::unbounded_channel::<Option<String>>();
+        let (received, from_client) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            while let Some(Some(line)) = to_write.recv().await {
+                log::debug!("Fake TMI sent: {}", line);
+                if writer.write_all(format!("{}\r\n", line).as_bytes()).await.is_err() {
+                    return;
+                }
+            }
+            let _ = writer.shutdown().await;
+        });
+
+        let responses = outgoing.clone();
+        let frozen = Arc::new(AtomicBool::new(false));
+        let is_frozen = frozen.clone();
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(reader).lines();
+            let mut session = Session::default();
+            while let Ok(Some(line)) = lines.next_line().await {
+                for response in session.respond(&line) {
+                    if !is_frozen.load(Ordering::SeqCst) {
+                        let _ = responses.send(Some(response));
+                    }
+                }
+                let _ = received.send(line);
+            }
+        });
+
+        Client {
+            outgoing,
+            received: from_client,
+            frozen,
+            closed: Arc::new(AtomicBool::new(false)),
+            stalled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Sends a raw IRC `line` to the client, without the trailing `\r\n`
+    pub fn send(&self, line: impl Into<String>) {
+        if !self.frozen.load(Ordering::SeqCst) {
+            let _ = self.outgoing.send(Some(line.into()));
+        }
+    }
+
+    /// Stops sending anything to the client, including responses, as if the
+    /// connection silently stalled. Lines sent by the client are still
+    /// received.
+    pub fn freeze(&self) { self.frozen.store(true, Ordering::SeqCst) }
+
+    /// Sends to the client again after [`Client::freeze`], responses which
+    /// were held back in between are lost
+    pub fn thaw(&self) { self.frozen.store(false, Ordering::SeqCst) }
+
+    /// Stops accepting anything from the client, its writes hang as if the
+    /// connection's send buffer was full
+    pub fn stall(&self) { self.stalled.store(true, Ordering::SeqCst) }
+
+    /// Sends a PRIVMSG from `login` to `channel`
+    pub fn privmsg(&self, channel: &str, login: &str, text: &str) {
+        self.send(format!(
+            "@badge-info=;badges=;color=;display-name={login};emotes=;flags=;id={id};mod=0;room-id=1;subscriber=0;\
+             tmi-sent-ts={time};turbo=0;user-id=2;user-type= :{login}!{login}@{login}.tmi.twitch.tv PRIVMSG #{channel} \
+             :{text}",
+            login = login,
+            id = rand::random::<u64>(),
+            time = chrono::Utc::now().timestamp_millis(),
+            channel = channel,
+            text = text
+        ))
+    }
+
+    /// Waits for the next line sent by the client, without the trailing
+    /// `\r\n`. Returns `None` once the client closes the connection.
+    pub async fn recv(&mut self) -> Option<String> { self.received.recv().await }
+
+    /// Waits until the client sends `line`, skipping anything sent before it.
+    ///
+    /// Panics if the client closes the connection first.
+    pub async fn wait_for(&mut self, line: &str) {
+        loop {
+            match self.recv().await {
+                Some(received) if received == line => return,
+                Some(_) => continue,
+                None => panic!("Connection closed while waiting for {:?}", line),
+            }
+        }
+    }
+
+    /// Closes the connection
+    pub fn disconnect(self) {}
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+        let _ = self.outgoing.send(None);
+    }
+}
+
+/// What the client told the server during the handshake
+#[derive(Default)]
+struct Session {
+    login: String,
+    token: Option<String>,
+}
+
+impl Session {
+    /// What TMI would respond to `line`
+    fn respond(&mut self, line: &str) -> Vec<String> {
+        let login = &mut self.login;
+        let (command, params) = line.split_once(' ').unwrap_or((line, ""));
+        match command {
+            "CAP" => match params.strip_prefix("REQ :") {
+                Some(caps) => {
+                    let answer = if caps.split(' ').all(|cap| CAPABILITIES.contains(&cap)) {
+                        "ACK"
+                    } else {
+                        "NAK"
+                    };
+                    vec![format!(":tmi.twitch.tv CAP * {} :{}", answer, caps)]
+                }
+                None => vec![],
+            },
+            "PASS" => {
+                self.token = Some(params.to_string());
+                vec![]
+            }
+            "NICK" => {
+                if self.token.as_deref() == Some(INVALID_TOKEN) {
+                    return vec![":tmi.twitch.tv NOTICE * :Login authentication failed".into()];
+                }
+                *login = params.to_string();
+                let mut responses = vec![
+                    format!(":tmi.twitch.tv 001 {} :Welcome, GLHF!", login),
+                    format!(":tmi.twitch.tv 002 {} :Your host is tmi.twitch.tv", login),
+                    format!(":tmi.twitch.tv 003 {} :This server is rather new", login),
+                    format!(":tmi.twitch.tv 004 {} :-", login),
+                    format!(":tmi.twitch.tv 375 {} :-", login),
+                    format!(
+                        ":tmi.twitch.tv 372 {} :You are in a maze of twisty passages, all alike.",
+                        login
+                    ),
+                    format!(":tmi.twitch.tv 376 {} :>", login),
+                ];
+                // anonymous users don't get a GLOBALUSERSTATE
+                if !login.starts_with("justinfan") {
+                    responses.push(format!(
+                        "@badge-info=;badges=;color=;display-name={};emote-sets=0;user-id=1;user-type= \
+                         :tmi.twitch.tv GLOBALUSERSTATE",
+                        login
+                    ));
+                }
+                responses
+            }
+            "JOIN" => {
+                let mut responses = vec![];
+                for channel in params.split(',') {
+                    responses.push(format!(":{0}!{0}@{0}.tmi.twitch.tv JOIN {1}", login, channel));
+                    // anonymous users don't get a USERSTATE
+                    if !login.starts_with("justinfan") {
+                        responses.push(format!(
+                            "@badge-info=;badges=;color=;display-name={};emote-sets=0;mod=0;subscriber=0;user-type= \
+                             :tmi.twitch.tv USERSTATE {}",
+                            login, channel
+                        ));
+                    }
+                    responses.push(format!(
+                        "@emote-only=0;followers-only=-1;r9k=0;rituals=0;room-id=1;slow=0;subs-only=0 :tmi.twitch.tv \
+                         ROOMSTATE {}",
+                        channel
+                    ));
+                }
+                responses
+            }
+            "PART" => params
+                .split(',')
+                .map(|channel| format!(":{0}!{0}@{0}.tmi.twitch.tv PART {1}", login, channel))
+                .collect(),
+            "PING" => vec![format!(":tmi.twitch.tv PONG tmi.twitch.tv {}", params)],
+            _ => vec![],
+        }
+    }
+}
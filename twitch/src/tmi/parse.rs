@@ -2,20 +2,25 @@
 ///!
 ///! This *only* handles parsing.
 ///!
+///! Tag values which are free text, such as display names or message bodies,
+///! are unescaped into `String`s. Identifiers, such as ids, logins, colors or
+///! plans, are `ArcSlice`s of the raw tag value, Twitch never sends characters
+///! in them which would have to be escaped.
+///!
 ///! ```
 ///! match Message::parse(/* receive a message somehow */).unwrap() {
 ///!     Message::Privmsg(msg) => handle_privmsg(msg)
 ///! }
 ///! ```
-use std::convert::Into;
+use std::{convert::Into, ops::Range};
 
 use chrono::{DateTime, Duration, Utc};
 use thiserror::Error;
 use twitch_getters::twitch_getters;
 
 // TODO: there are still a bunch of String allocations which can be removed
-use crate::{irc, irc::DurationKind, util::UnsafeSlice};
-#[derive(Error, Debug, PartialEq)]
+use crate::{irc, irc::DurationKind, util::ArcSlice};
+#[derive(Error, Clone, Debug, PartialEq)]
 pub enum Error {
     #[error("Invalid tag '{0}'")]
     InvalidTag(String),
@@ -37,6 +42,8 @@ pub enum Error {
     InvalidTagValue(String, String),
     #[error("Received a malformed message")]
     MalformedMessage,
+    #[error("Line is longer than {0} bytes")]
+    LineTooLong(usize),
     #[error(transparent)]
     Irc(#[from] irc::Error),
 }
@@ -62,6 +69,20 @@ pub enum Message {
     UserState(self::UserState),
     Capability(self::Capability),
     Unknown(irc::Message),
+    /// Not sent by Twitch. Returned by [`Reader::next`] after the connection
+    /// was re-established and all channels were rejoined.
+    ///
+    /// [`Reader::next`]: crate::conn::Reader::next
+    Reconnected,
+    /// Not sent by Twitch. Returned by [`Reader::next`] in place of a line
+    /// which failed to parse, see [`Config::on_malformed`].
+    ///
+    /// [`Reader::next`]: crate::conn::Reader::next
+    /// [`Config::on_malformed`]: crate::conn::Config::on_malformed
+    Malformed {
+        raw: String,
+        error: Error,
+    },
 }
 
 impl Message {
@@ -90,12 +111,38 @@ impl Message {
             _ => Message::Unknown(value),
         })
     }
+    /// The IRC message this was parsed from, `None` for
+    /// [`Message::Reconnected`] and [`Message::Malformed`]
+    pub fn raw(&self) -> Option<&irc::Message> {
+        Some(match self {
+            Message::Ping(message) => &message.raw,
+            Message::Pong(message) => &message.raw,
+            Message::Join(message) => &message.raw,
+            Message::Part(message) => &message.raw,
+            Message::Privmsg(message) => &message.raw,
+            Message::Whisper(message) => &message.raw,
+            Message::Clearchat(message) => &message.raw,
+            Message::Clearmsg(message) => &message.raw,
+            Message::GlobalUserState(message) => &message.raw,
+            Message::HostTarget(message) => &message.raw,
+            Message::Notice(message) => &message.raw,
+            Message::Reconnect(message) => &message.raw,
+            Message::RoomState(message) => &message.raw,
+            Message::UserNotice(message) => &message.base().raw,
+            Message::UserState(message) => &message.raw,
+            Message::Capability(message) => &message.raw,
+            Message::Unknown(message) => message,
+            Message::Reconnected | Message::Malformed { .. } => return None,
+        })
+    }
+    /// The channel this message was sent to, if any
+    pub fn channel(&self) -> Option<&str> { self.raw().and_then(|raw| raw.channel.as_ref()).map(ArcSlice::as_str) }
 }
 
 #[twitch_getters]
 #[derive(Clone, Debug, PartialEq)]
 pub struct Ping {
-    arg: Option<UnsafeSlice>,
+    arg: Option<ArcSlice>,
     raw: irc::Message,
 }
 
@@ -105,9 +152,7 @@ impl Ping {
             arg: value
                 .params
                 .as_ref()
-                .map(|v| v.raw().strip_prefix(":"))
-                .flatten()
-                .map(|v| v.into()),
+                .and_then(|v| v.raw().strip_prefix(":").map(|arg| v.slice(arg))),
             raw: value,
         })
     }
@@ -116,19 +161,20 @@ impl Ping {
 #[twitch_getters]
 #[derive(Clone, Debug, PartialEq)]
 pub struct Pong {
-    arg: Option<UnsafeSlice>,
+    arg: Option<ArcSlice>,
     raw: irc::Message,
 }
 
 impl Pong {
     pub fn parse(value: irc::Message) -> Result<Pong> {
+        // TMI answers `PING :arg` with `PONG tmi.twitch.tv :arg`
         Ok(Pong {
-            arg: value
-                .params
-                .as_ref()
-                .map(|v| v.raw().strip_prefix(":"))
-                .flatten()
-                .map(|v| v.into()),
+            arg: value.params.as_ref().and_then(|v| {
+                let raw = v.raw();
+                raw.strip_prefix(':')
+                    .or_else(|| raw.split_once(" :").map(|(_, arg)| arg))
+                    .map(|arg| v.slice(arg))
+            }),
             raw: value,
         })
     }
@@ -137,16 +183,24 @@ impl Pong {
 #[twitch_getters]
 #[derive(Clone, Debug, PartialEq)]
 pub struct Join {
-    channel: UnsafeSlice,
-    nick: UnsafeSlice,
+    channel: ArcSlice,
+    nick: ArcSlice,
     raw: irc::Message,
 }
 
 impl Join {
     pub fn parse(value: irc::Message) -> Result<Self> {
         Ok(Join {
-            channel: value.channel.ok_or_else(|| Error::MissingParam("channel".into()))?,
-            nick: match value.prefix.ok_or_else(|| Error::MissingParam("nick".into()))?.nick {
+            channel: value
+                .channel
+                .clone()
+                .ok_or_else(|| Error::MissingParam("channel".into()))?,
+            nick: match value
+                .prefix
+                .clone()
+                .ok_or_else(|| Error::MissingParam("nick".into()))?
+                .nick
+            {
                 Some(nick) => nick,
                 None => return Err(Error::MissingParam("user".into())),
             },
@@ -158,16 +212,24 @@ impl Join {
 #[twitch_getters]
 #[derive(Clone, Debug, PartialEq)]
 pub struct Part {
-    channel: UnsafeSlice,
-    nick: UnsafeSlice,
+    channel: ArcSlice,
+    nick: ArcSlice,
     raw: irc::Message,
 }
 
 impl Part {
     pub fn parse(value: irc::Message) -> Result<Self> {
         Ok(Part {
-            channel: value.channel.ok_or_else(|| Error::MissingParam("channel".into()))?,
-            nick: match value.prefix.ok_or_else(|| Error::MissingParam("nick".into()))?.nick {
+            channel: value
+                .channel
+                .clone()
+                .ok_or_else(|| Error::MissingParam("channel".into()))?,
+            nick: match value
+                .prefix
+                .clone()
+                .ok_or_else(|| Error::MissingParam("nick".into()))?
+                .nick
+            {
                 Some(nick) => nick,
                 None => return Err(Error::MissingParam("nick".into())),
             },
@@ -176,21 +238,205 @@ impl Part {
     }
 }
 
+/// A chat badge, e.g. `subscriber/12`
+#[twitch_getters]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Badge {
+    name: ArcSlice,
+    /// Which variant of the badge is shown. In `badge-info`, this is the
+    /// exact number of months for `subscriber` and `founder`.
+    version: ArcSlice,
+}
+
+impl Badge {
+    /// Parses a comma-separated list of badges, i.e. the value of the `badges`
+    /// or `badge-info` tag
+    pub fn parse_list(value: Option<ArcSlice>) -> Vec<Badge> {
+        let value = match value {
+            Some(v) => v,
+            None => return Vec::new(),
+        };
+        value
+            .as_str()
+            .split(',')
+            .filter(|badge| !badge.is_empty())
+            .map(|badge| {
+                let (name, version) = badge.split_once('/').unwrap_or((badge, &badge[badge.len()..]));
+                Badge {
+                    name: value.slice(name),
+                    version: value.slice(version),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Implements badge-based privilege helpers for types with `badges` and
+/// `badge_info` fields
+macro_rules! impl_badge_helpers {
+    ($($ty:ty),*) => {$(
+        impl $ty {
+            /// Whether the user has a badge named `name`
+            pub fn has_badge(&self, name: &str) -> bool { self.badges.iter().any(|badge| badge.name() == name) }
+            pub fn is_broadcaster(&self) -> bool { self.has_badge("broadcaster") }
+            pub fn is_moderator(&self) -> bool { self.has_badge("moderator") }
+            pub fn is_vip(&self) -> bool { self.has_badge("vip") }
+            /// Founders are subscribers too, but they show the founder badge
+            /// instead of the subscriber one
+            pub fn is_subscriber(&self) -> bool { self.has_badge("subscriber") || self.is_founder() }
+            pub fn is_founder(&self) -> bool { self.has_badge("founder") }
+            /// The exact number of months the user has been subscribed for
+            pub fn subscriber_months(&self) -> Option<i64> {
+                self.badge_info
+                    .iter()
+                    .find(|badge| matches!(badge.name(), "subscriber" | "founder"))
+                    .and_then(|badge| badge.version().parse().ok())
+            }
+        }
+    )*};
+}
+
+impl_badge_helpers!(TwitchUser, GlobalUserState, UserState);
+
 #[twitch_getters]
 #[derive(Clone, Debug, PartialEq)]
 pub struct TwitchUser {
     /// The unique ID of the user - the `login` and `name` fields may
     /// arbitrarily change, but ID stays the same forever. For that reason,
     /// use this when identifying a user.
-    id: UnsafeSlice,
+    id: ArcSlice,
     /// Refers to the user's 'login' name, which is usually just the lowercased
     /// version of `name`
-    login: UnsafeSlice,
+    login: ArcSlice,
     /// Refers to the user's 'display' name, which should be used in user-facing
     /// contexts.
     pub name: String,
-    badge_info: Option<UnsafeSlice>,
-    badges: Option<UnsafeSlice>,
+    pub badge_info: Vec<Badge>,
+    pub badges: Vec<Badge>,
+}
+
+/// An emote used in a message
+#[twitch_getters]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Emote {
+    id: ArcSlice,
+    /// The emote's name, as it appears in the text
+    name: ArcSlice,
+    /// Every occurrence of the emote, as byte ranges into the text
+    pub ranges: Vec<Range<usize>>,
+}
+
+impl Emote {
+    /// Parses the `emotes` tag, e.g. `25:0-4,12-16/1902:6-10`.
+    ///
+    /// Twitch sends inclusive ranges of code points, these are converted into
+    /// byte ranges into `text`. Ranges which are out of bounds are skipped, as
+    /// well as emotes with no valid ranges.
+    pub fn parse_list(value: Option<ArcSlice>, text: Option<&ArcSlice>) -> Vec<Emote> {
+        let (value, text) = match (value, text) {
+            (Some(value), Some(text)) => (value, text),
+            _ => return Vec::new(),
+        };
+        // byte offset of every code point, and of the end of the text
+        let offsets: Vec<usize> = text
+            .as_str()
+            .char_indices()
+            .map(|(i, _)| i)
+            .chain(std::iter::once(text.len()))
+            .collect();
+
+        let mut emotes = Vec::new();
+        for emote in value.as_str().split('/').filter(|emote| !emote.is_empty()) {
+            let (id, ranges) = match emote.split_once(':') {
+                Some(v) => v,
+                None => continue,
+            };
+            let ranges: Vec<Range<usize>> = ranges
+                .split(',')
+                .filter_map(|range| {
+                    let (start, end) = range.split_once('-')?;
+                    let (start, end) = (start.parse::<usize>().ok()?, end.parse::<usize>().ok()?);
+                    if start > end {
+                        return None;
+                    }
+                    Some(*offsets.get(start)?..*offsets.get(end + 1)?)
+                })
+                .collect();
+            if let Some(first) = ranges.first() {
+                emotes.push(Emote {
+                    id: value.slice(id),
+                    name: text.slice(&text.as_str()[first.clone()]),
+                    ranges,
+                });
+            }
+        }
+        emotes
+    }
+}
+
+/// Part of a message's text, see [`Segments`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Segment<'a> {
+    Text(&'a str),
+    Emote(&'a Emote),
+}
+
+/// Iterates the text of a message as alternating text and emote segments
+pub struct Segments<'a> {
+    text: &'a str,
+    /// Emote occurrences sorted by their position
+    emotes: std::vec::IntoIter<(Range<usize>, &'a Emote)>,
+    /// Byte offset into `text` where the next segment starts
+    position: usize,
+    /// The emote which follows the text segment that was just returned
+    next: Option<(Range<usize>, &'a Emote)>,
+}
+
+impl<'a> Segments<'a> {
+    fn new(text: &'a str, emotes: &'a [Emote]) -> Segments<'a> {
+        let mut occurrences: Vec<_> = emotes
+            .iter()
+            .flat_map(|emote| emote.ranges.iter().map(move |range| (range.clone(), emote)))
+            .collect();
+        occurrences.sort_by_key(|(range, _)| range.start);
+        Segments {
+            text,
+            emotes: occurrences.into_iter(),
+            position: 0,
+            next: None,
+        }
+    }
+}
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = Segment<'a>;
+
+    fn next(&mut self) -> Option<Segment<'a>> {
+        if let Some((range, emote)) = self.next.take() {
+            self.position = range.end;
+            return Some(Segment::Emote(emote));
+        }
+        // skip emotes which overlap the previous one
+        let position = self.position;
+        let next = self.emotes.by_ref().find(|(range, _)| range.start >= position);
+        match next {
+            Some((range, emote)) if range.start == position => {
+                self.position = range.end;
+                Some(Segment::Emote(emote))
+            }
+            Some((range, emote)) => {
+                let text = &self.text[self.position..range.start];
+                self.next = Some((range, emote));
+                Some(Segment::Text(text))
+            }
+            None if self.position < self.text.len() => {
+                let text = &self.text[self.position..];
+                self.position = self.text.len();
+                Some(Segment::Text(text))
+            }
+            None => None,
+        }
+    }
 }
 
 /// If message starts with '\x01ACTION ' and ends with '\x01', then remove those
@@ -204,48 +450,84 @@ fn parse_message(msg: &str) -> (&str, bool) {
 #[twitch_getters]
 #[derive(Clone, Debug, PartialEq)]
 pub struct Privmsg {
-    channel: UnsafeSlice,
-    text: UnsafeSlice,
+    channel: ArcSlice,
+    text: ArcSlice,
     pub user: TwitchUser,
     pub is_action: bool,
     pub bits: Option<i64>,
-    color: Option<UnsafeSlice>,
-    #[csv]
-    emotes: UnsafeSlice,
-    id: UnsafeSlice,
-    room_id: UnsafeSlice,
+    color: Option<ArcSlice>,
+    pub emotes: Vec<Emote>,
+    /// The message this one replies to, if it was sent as a reply
+    pub reply: Option<ReplyParent>,
+    id: ArcSlice,
+    room_id: ArcSlice,
     pub time: DateTime<Utc>,
     raw: irc::Message,
 }
 
-// SAFETY: Privmsg owns the source String, and it has no interior mutability
-unsafe impl Sync for Privmsg {}
-unsafe impl Send for Privmsg {}
+/// The message which a [`Privmsg`] replies to
+#[twitch_getters]
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplyParent {
+    id: ArcSlice,
+    user_id: Option<ArcSlice>,
+    user_login: ArcSlice,
+    pub user_name: Option<String>,
+    pub text: String,
+}
+
+impl ReplyParent {
+    /// Parses the `reply-parent-*` tags, `None` if the message isn't a reply
+    pub fn parse(tags: &irc::Tags) -> Result<Option<ReplyParent>> {
+        let id = match tags.get("reply-parent-msg-id") {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        Ok(Some(ReplyParent {
+            id,
+            user_id: tags.get("reply-parent-user-id"),
+            user_login: tags.require("reply-parent-user-login")?,
+            user_name: tags.get_ns("reply-parent-display-name"),
+            text: tags.require_ns("reply-parent-msg-body")?,
+        }))
+    }
+}
 
 impl Privmsg {
+    /// Iterates the text as text and emote segments
+    pub fn segments(&self) -> Segments<'_> { Segments::new(self.text.as_str(), &self.emotes) }
+
     pub fn parse(source: irc::Message) -> Result<Self> {
         let (text, is_action) = match source.params.as_ref() {
-            Some(v) => parse_message(v.raw().trim_start().strip_prefix(':').ok_or(Error::MalformedMessage)?),
-            None => ("", false),
+            Some(v) => {
+                let (text, is_action) =
+                    parse_message(v.raw().trim_start().strip_prefix(':').ok_or(Error::MalformedMessage)?);
+                (v.slice(text), is_action)
+            }
+            None => (ArcSlice::default(), false),
         };
         Ok(Privmsg {
-            channel: source.channel.ok_or_else(|| Error::MissingParam("channel".into()))?,
-            text: text.into(),
+            channel: source
+                .channel
+                .clone()
+                .ok_or_else(|| Error::MissingParam("channel".into()))?,
+            emotes: Emote::parse_list(source.tags.get("emotes"), Some(&text)),
+            text,
             user: TwitchUser {
                 id: source.tags.require("user-id")?,
                 login: source
                     .prefix
-                    .ok_or_else(|| Error::MissingParam("nick".into()))?
-                    .nick
+                    .as_ref()
+                    .and_then(|prefix| prefix.nick.clone())
                     .ok_or_else(|| Error::MissingParam("nick".into()))?,
                 name: source.tags.require_ns("display-name")?,
-                badge_info: source.tags.get("badge-info"),
-                badges: source.tags.get("badges"),
+                badge_info: Badge::parse_list(source.tags.get("badge-info")),
+                badges: Badge::parse_list(source.tags.get("badges")),
             },
             is_action,
             bits: source.tags.get_number("bits"),
             color: source.tags.get("color"),
-            emotes: source.tags.get("emotes").unwrap_or_default(),
+            reply: ReplyParent::parse(&source.tags)?,
             id: source.tags.require("id")?,
             room_id: source.tags.require("room-id")?,
             time: source.tags.require_date("tmi-sent-ts")?,
@@ -257,21 +539,23 @@ impl Privmsg {
 #[twitch_getters]
 #[derive(Clone, Debug, PartialEq)]
 pub struct Whisper {
-    recipient: UnsafeSlice,
-    thread_id: UnsafeSlice,
+    recipient: ArcSlice,
+    thread_id: ArcSlice,
     pub user: TwitchUser,
-    text: UnsafeSlice,
+    text: ArcSlice,
     pub is_action: bool,
-    color: Option<UnsafeSlice>,
-    #[csv]
-    emotes: UnsafeSlice,
-    id: UnsafeSlice,
+    color: Option<ArcSlice>,
+    pub emotes: Vec<Emote>,
+    id: ArcSlice,
     raw: irc::Message,
 }
 
 impl Whisper {
+    /// Iterates the text as text and emote segments
+    pub fn segments(&self) -> Segments<'_> { Segments::new(self.text.as_str(), &self.emotes) }
+
     pub fn parse(source: irc::Message) -> Result<Self> {
-        let params = match source.params {
+        let params = match source.params.as_ref() {
             Some(v) => v,
             None => return Err(Error::EmptyParams),
         };
@@ -284,25 +568,26 @@ impl Whisper {
             None => return Err(Error::MalformedMessage),
         };
         let (text, is_action) = parse_message(message);
+        let text = params.slice(text);
 
         Ok(Whisper {
-            recipient: recipient.into(),
+            recipient: params.slice(recipient),
             thread_id: source.tags.require("thread-id")?,
             user: TwitchUser {
                 id: source.tags.require("user-id")?,
                 login: source
                     .prefix
-                    .ok_or_else(|| Error::MissingParam("nick".into()))?
-                    .nick
+                    .as_ref()
+                    .and_then(|prefix| prefix.nick.clone())
                     .ok_or_else(|| Error::MissingParam("nick".into()))?,
                 name: source.tags.require_ns("display-name")?,
-                badge_info: source.tags.get("badge-info"),
-                badges: source.tags.get("badges"),
+                badge_info: Badge::parse_list(source.tags.get("badge-info")),
+                badges: Badge::parse_list(source.tags.get("badges")),
             },
-            text: text.into(),
+            emotes: Emote::parse_list(source.tags.get("emotes"), Some(&text)),
+            text,
             is_action,
             color: source.tags.get("color"),
-            emotes: source.tags.get("emotes").unwrap_or_default(),
             id: source.tags.require("message-id")?,
             raw: source,
         })
@@ -312,10 +597,10 @@ impl Whisper {
 #[twitch_getters]
 #[derive(Clone, Debug, PartialEq)]
 pub struct Clearchat {
-    channel: UnsafeSlice,
+    channel: ArcSlice,
     /// None = clear the entire chat
-    target: Option<UnsafeSlice>,
-    target_id: Option<UnsafeSlice>,
+    target: Option<ArcSlice>,
+    target_id: Option<ArcSlice>,
     pub time: DateTime<Utc>,
     /// None = permanent ban
     pub duration: Option<Duration>,
@@ -327,12 +612,13 @@ impl Clearchat {
         let target = source
             .params
             .as_ref()
-            .map(|v| v.raw())
-            .map(|v| v.trim_start().strip_prefix(":").unwrap_or(v))
-            .map(|v| v.into());
+            .map(|v| v.slice(v.raw().trim_start().strip_prefix(":").unwrap_or_else(|| v.raw())));
 
         Ok(Clearchat {
-            channel: source.channel.ok_or_else(|| Error::MissingParam("channel".into()))?,
+            channel: source
+                .channel
+                .clone()
+                .ok_or_else(|| Error::MissingParam("channel".into()))?,
             target,
             target_id: source.tags.get("target-user-id"),
             time: source.tags.require_date("tmi-sent-ts")?,
@@ -345,27 +631,25 @@ impl Clearchat {
 #[twitch_getters]
 #[derive(Clone, Debug, PartialEq)]
 pub struct Clearmsg {
-    pub channel: UnsafeSlice,
-    pub login: UnsafeSlice,
+    pub channel: ArcSlice,
+    pub login: ArcSlice,
     /// Deleted message text
-    pub text: UnsafeSlice,
-    pub target_msg_id: UnsafeSlice,
+    pub text: ArcSlice,
+    pub target_msg_id: ArcSlice,
     raw: irc::Message,
 }
 
 impl Clearmsg {
     pub fn parse(source: irc::Message) -> Result<Self> {
-        let text = match source
-            .params
-            .as_ref()
-            .map(|v| v.raw())
-            .map(|v| v.trim_start().strip_prefix(':').unwrap_or(v))
-        {
-            Some(v) => v.into(),
+        let text = match source.params.as_ref() {
+            Some(v) => v.slice(v.raw().trim_start().strip_prefix(':').unwrap_or_else(|| v.raw())),
             None => return Err(Error::MalformedMessage),
         };
         Ok(Clearmsg {
-            channel: source.channel.ok_or_else(|| Error::MissingParam("channel".into()))?,
+            channel: source
+                .channel
+                .clone()
+                .ok_or_else(|| Error::MissingParam("channel".into()))?,
             login: source.tags.require("login")?,
             text,
             target_msg_id: source.tags.require("target-msg-id")?,
@@ -378,14 +662,13 @@ impl Clearmsg {
 #[twitch_getters]
 #[derive(Clone, Debug, PartialEq)]
 pub struct GlobalUserState {
-    user_id: UnsafeSlice,
+    user_id: ArcSlice,
     pub display_name: String,
-    badge_info: Option<UnsafeSlice>,
+    pub badge_info: Vec<Badge>,
+    pub badges: Vec<Badge>,
+    color: Option<ArcSlice>,
     #[csv]
-    badges: UnsafeSlice,
-    color: Option<UnsafeSlice>,
-    #[csv]
-    emote_sets: UnsafeSlice,
+    emote_sets: ArcSlice,
     raw: irc::Message,
 }
 
@@ -394,8 +677,8 @@ impl GlobalUserState {
         Ok(GlobalUserState {
             user_id: source.tags.require("user-id")?,
             display_name: source.tags.require_ns("display-name")?,
-            badge_info: source.tags.get("badge-info"),
-            badges: source.tags.get("badges").unwrap_or_default(),
+            badge_info: Badge::parse_list(source.tags.get("badge-info")),
+            badges: Badge::parse_list(source.tags.get("badges")),
             color: source.tags.get("color"),
             emote_sets: source.tags.get("emote-sets").unwrap_or_default(),
             raw: source,
@@ -406,9 +689,9 @@ impl GlobalUserState {
 #[twitch_getters]
 #[derive(Clone, Debug, PartialEq)]
 pub struct HostTarget {
-    hosting_channel: UnsafeSlice,
+    hosting_channel: ArcSlice,
     /// None = stop hosting
-    target_channel: Option<UnsafeSlice>,
+    target_channel: Option<ArcSlice>,
     pub viewer_count: Option<i64>,
     raw: irc::Message,
 }
@@ -418,12 +701,12 @@ impl HostTarget {
         let (target_channel, viewer_count) = match source
             .params
             .as_ref()
-            .map(|v| v.raw())
-            .map(|v| v.trim_start().strip_prefix(':').unwrap_or(v))
-            .map(|v| match v.split_once(' ') {
+            .map(|params| (params, params.raw().trim_start()))
+            .map(|(params, v)| (params, v.strip_prefix(':').unwrap_or(v)))
+            .map(|(params, v)| match v.split_once(' ') {
                 Some((l, r)) => (
                     if !l.is_empty() && l != "-" {
-                        Some(l.into())
+                        Some(params.slice(l))
                     } else {
                         None
                     },
@@ -435,7 +718,10 @@ impl HostTarget {
             None => return Err(Error::MalformedMessage),
         };
         Ok(HostTarget {
-            hosting_channel: source.channel.ok_or_else(|| Error::MissingParam("channel".into()))?,
+            hosting_channel: source
+                .channel
+                .clone()
+                .ok_or_else(|| Error::MissingParam("channel".into()))?,
             target_channel,
             viewer_count,
             raw: source,
@@ -481,6 +767,8 @@ pub enum NoticeId {
     BadCommercialError,
     /// You cannot delete the broadcaster's messages.
     BadDeleteMessageBroadcaster,
+    /// Failed to delete the message.
+    BadDeleteMessageError,
     /// You cannot delete messages from another moderator <user>.
     BadDeleteMessageMod,
     /// There was a problem hosting <channel>. Please try again in a minute.
@@ -528,6 +816,18 @@ pub enum NoticeId {
     BadUnhostError,
     /// <user> is not a moderator of this channel.
     BadUnmodMod,
+    /// <user> is not a VIP of this channel.
+    BadUnvipGranteeNotVip,
+    /// You have to reach the Build a Community achievement to add VIPs.
+    BadVipAchievementIncomplete,
+    /// <user> is already a VIP of this channel.
+    BadVipGranteeAlreadyVip,
+    /// You cannot add <user> as a VIP, because they're banned from this
+    /// channel.
+    BadVipGranteeBanned,
+    /// Unable to add VIP. Visit the Achievements page to learn how to add
+    /// more VIPs.
+    BadVipMaxVipsReached,
     /// <user> is now banned from this channel.
     BanSuccess,
     /// Commands available to you in this room (use /help <command> for
@@ -689,6 +989,8 @@ pub enum NoticeId {
     UntimeoutBanned,
     /// <user> is no longer timed out in this channel.
     UntimeoutSuccess,
+    /// You have removed <user> as a VIP of this channel.
+    UnvipSuccess,
     /// Usage: “/ban <username> [reason]” Permanently prevent a user from
     /// chatting. Reason is optional and will be shown to the target and other
     /// moderators. Use “/unban” to remove a ban.
@@ -703,6 +1005,8 @@ pub enum NoticeId {
     /// Usage: “/commercial [length]”Triggers a commercial. Length (optional)
     /// must be a positive number of seconds.
     UsageCommercial,
+    /// Usage: “/delete <msg id>”Deletes the specified message.
+    UsageDelete,
     /// Usage: “/disconnect”Reconnects to chat.
     UsageDisconnect,
     /// Usage: /emoteonlyoff”Disables emote-only mode.
@@ -768,6 +1072,12 @@ pub enum NoticeId {
     UsageUnraid,
     /// Usage: “/untimeout <username>”Removes a timeout on a user.
     UsageUntimeout,
+    /// Usage: “/unvip <username>”Revoke VIP status from a user.
+    UsageUnvip,
+    /// Usage: “/vip <username>”Grant VIP status to a user.
+    UsageVip,
+    /// You have added <user> as a VIP of this channel.
+    VipSuccess,
     /// You have been banned from sending whispers.
     WhisperBanned,
     /// That user has been banned from receiving whispers.
@@ -786,165 +1096,311 @@ pub enum NoticeId {
     WhisperRestricted,
     /// That user's settings prevent them from receiving this whisper.
     WhisperRestrictedRecipient,
+    /// Any id which doesn't have its own variant yet
+    Unknown(String),
+}
+
+/// Broad kind of a [`NoticeId`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum NoticeCategory {
+    /// Something was sent too quickly, e.g. `msg_ratelimit` or `msg_slowmode`
+    RateLimit,
+    /// The user isn't allowed to do something, e.g. `no_permission`,
+    /// `msg_banned` or `msg_subsonly`
+    Permission,
+    /// Outcome of a moderation command, e.g. `ban_success` or `bad_timeout_mod`
+    ModerationResult,
+    /// A room mode was changed, or already set, e.g. `slow_on`
+    RoomMode,
+    /// Anything else, including unknown ids
+    Other,
 }
 
 impl NoticeId {
-    pub fn parse(value: &str) -> Result<NoticeId> {
+    /// Parses a `msg-id`, ids which aren't known yet are kept as
+    /// [`NoticeId::Unknown`]
+    pub fn parse(value: &str) -> NoticeId {
         match value {
-            "already_banned" => Ok(NoticeId::AlreadyBanned),
-            "already_emote_only_off" => Ok(NoticeId::AlreadyEmoteOnlyOff),
-            "already_emote_only_on" => Ok(NoticeId::AlreadyEmoteOnlyOn),
-            "already_r9k_off" => Ok(NoticeId::AlreadyR9kOff),
-            "already_r9k_on" => Ok(NoticeId::AlreadyR9kOn),
-            "already_subs_off" => Ok(NoticeId::AlreadySubsOff),
-            "already_subs_on" => Ok(NoticeId::AlreadySubsOn),
-            "bad_ban_admin" => Ok(NoticeId::BadBanAdmin),
-            "bad_ban_anon" => Ok(NoticeId::BadBanAnon),
-            "bad_ban_broadcaster" => Ok(NoticeId::BadBanBroadcaster),
-            "bad_ban_global_mod" => Ok(NoticeId::BadBanGlobalMod),
-            "bad_ban_mod" => Ok(NoticeId::BadBanMod),
-            "bad_ban_self" => Ok(NoticeId::BadBanSelf),
-            "bad_ban_staff" => Ok(NoticeId::BadBanStaff),
-            "bad_commercial_error" => Ok(NoticeId::BadCommercialError),
-            "bad_delete_message_broadcaster" => Ok(NoticeId::BadDeleteMessageBroadcaster),
-            "bad_delete_message_mod" => Ok(NoticeId::BadDeleteMessageMod),
-            "bad_host_error" => Ok(NoticeId::BadHostError),
-            "bad_host_hosting" => Ok(NoticeId::BadHostHosting),
-            "bad_host_rate_exceeded" => Ok(NoticeId::BadHostRateExceeded),
-            "bad_host_rejected" => Ok(NoticeId::BadHostRejected),
-            "bad_host_self" => Ok(NoticeId::BadHostSelf),
-            "bad_marker_client" => Ok(NoticeId::BadMarkerClient),
-            "bad_mod_banned" => Ok(NoticeId::BadModBanned),
-            "bad_mod_mod" => Ok(NoticeId::BadModMod),
-            "bad_slow_duration" => Ok(NoticeId::BadSlowDuration),
-            "bad_timeout_admin" => Ok(NoticeId::BadTimeoutAdmin),
-            "bad_timeout_anon" => Ok(NoticeId::BadTimeoutAnon),
-            "bad_timeout_broadcaster" => Ok(NoticeId::BadTimeoutBroadcaster),
-            "bad_timeout_duration" => Ok(NoticeId::BadTimeoutDuration),
-            "bad_timeout_global_mod" => Ok(NoticeId::BadTimeoutGlobalMod),
-            "bad_timeout_mod" => Ok(NoticeId::BadTimeoutMod),
-            "bad_timeout_self" => Ok(NoticeId::BadTimeoutSelf),
-            "bad_timeout_staff" => Ok(NoticeId::BadTimeoutStaff),
-            "bad_unban_no_ban" => Ok(NoticeId::BadUnbanNoBan),
-            "bad_unhost_error" => Ok(NoticeId::BadUnhostError),
-            "bad_unmod_mod" => Ok(NoticeId::BadUnmodMod),
-            "ban_success" => Ok(NoticeId::BanSuccess),
-            "cmds_available" => Ok(NoticeId::CmdsAvailable),
-            "color_changed" => Ok(NoticeId::ColorChanged),
-            "commercial_success" => Ok(NoticeId::CommercialSuccess),
-            "delete_message_success" => Ok(NoticeId::DeleteMessageSuccess),
-            "emote_only_off" => Ok(NoticeId::EmoteOnlyOff),
-            "emote_only_on" => Ok(NoticeId::EmoteOnlyOn),
-            "followers_off" => Ok(NoticeId::FollowersOff),
-            "followers_on" => Ok(NoticeId::FollowersOn),
-            "followers_onzero" => Ok(NoticeId::FollowersOnzero),
-            "host_off" => Ok(NoticeId::HostOff),
-            "host_on" => Ok(NoticeId::HostOn),
-            "host_success" => Ok(NoticeId::HostSuccess),
-            "host_success_viewers" => Ok(NoticeId::HostSuccessViewers),
-            "host_target_went_offline" => Ok(NoticeId::HostTargetWentOffline),
-            "hosts_remaining" => Ok(NoticeId::HostsRemaining),
-            "invalid_user" => Ok(NoticeId::InvalidUser),
-            "mod_success" => Ok(NoticeId::ModSuccess),
-            "msg_banned" => Ok(NoticeId::MsgBanned),
-            "msg_bad_characters" => Ok(NoticeId::MsgBadCharacters),
-            "msg_channel_blocked" => Ok(NoticeId::MsgChannelBlocked),
-            "msg_channel_suspended" => Ok(NoticeId::MsgChannelSuspended),
-            "msg_duplicate" => Ok(NoticeId::MsgDuplicate),
-            "msg_emoteonly" => Ok(NoticeId::MsgEmoteonly),
-            "msg_facebook" => Ok(NoticeId::MsgFacebook),
-            "msg_followersonly" => Ok(NoticeId::MsgFollowersonly),
-            "msg_followersonly_followed" => Ok(NoticeId::MsgFollowersonlyFollowed),
-            "msg_followersonly_zero" => Ok(NoticeId::MsgFollowersonlyZero),
-            "msg_r9k" => Ok(NoticeId::MsgR9k),
-            "msg_ratelimit" => Ok(NoticeId::MsgRatelimit),
-            "msg_rejected" => Ok(NoticeId::MsgRejected),
-            "msg_rejected_mandatory" => Ok(NoticeId::MsgRejectedMandatory),
-            "msg_room_not_found" => Ok(NoticeId::MsgRoomNotFound),
-            "msg_slowmode" => Ok(NoticeId::MsgSlowmode),
-            "msg_subsonly" => Ok(NoticeId::MsgSubsonly),
-            "msg_suspended" => Ok(NoticeId::MsgSuspended),
-            "msg_timedout" => Ok(NoticeId::MsgTimedout),
-            "msg_verified_email" => Ok(NoticeId::MsgVerifiedEmail),
-            "no_help" => Ok(NoticeId::NoHelp),
-            "no_mods" => Ok(NoticeId::NoMods),
-            "not_hosting" => Ok(NoticeId::NotHosting),
-            "no_permission" => Ok(NoticeId::NoPermission),
-            "r9k_off" => Ok(NoticeId::R9kOff),
-            "r9k_on" => Ok(NoticeId::R9kOn),
-            "raid_error_already_raiding" => Ok(NoticeId::RaidErrorAlreadyRaiding),
-            "raid_error_forbidden" => Ok(NoticeId::RaidErrorForbidden),
-            "raid_error_self" => Ok(NoticeId::RaidErrorSelf),
-            "raid_error_too_many_viewers" => Ok(NoticeId::RaidErrorTooManyViewers),
-            "raid_error_unexpected" => Ok(NoticeId::RaidErrorUnexpected),
-            "raid_notice_mature" => Ok(NoticeId::RaidNoticeMature),
-            "raid_notice_restricted_chat" => Ok(NoticeId::RaidNoticeRestrictedChat),
-            "room_mods" => Ok(NoticeId::RoomMods),
-            "slow_off" => Ok(NoticeId::SlowOff),
-            "slow_on" => Ok(NoticeId::SlowOn),
-            "subs_off" => Ok(NoticeId::SubsOff),
-            "subs_on" => Ok(NoticeId::SubsOn),
-            "timeout_no_timeout" => Ok(NoticeId::TimeoutNoTimeout),
-            "timeout_success" => Ok(NoticeId::TimeoutSuccess),
-            "tos_ban" => Ok(NoticeId::TosBan),
-            "turbo_only_color" => Ok(NoticeId::TurboOnlyColor),
-            "unban_success" => Ok(NoticeId::UnbanSuccess),
-            "unmod_success" => Ok(NoticeId::UnmodSuccess),
-            "unraid_error_no_active_raid" => Ok(NoticeId::UnraidErrorNoActiveRaid),
-            "unraid_error_unexpected" => Ok(NoticeId::UnraidErrorUnexpected),
-            "unraid_success" => Ok(NoticeId::UnraidSuccess),
-            "unrecognized_cmd" => Ok(NoticeId::UnrecognizedCmd),
-            "unsupported_chatrooms_cmd" => Ok(NoticeId::UnsupportedChatroomsCmd),
-            "untimeout_banned" => Ok(NoticeId::UntimeoutBanned),
-            "untimeout_success" => Ok(NoticeId::UntimeoutSuccess),
-            "usage_ban" => Ok(NoticeId::UsageBan),
-            "usage_clear" => Ok(NoticeId::UsageClear),
-            "usage_color" => Ok(NoticeId::UsageColor),
-            "usage_commercial" => Ok(NoticeId::UsageCommercial),
-            "usage_disconnect" => Ok(NoticeId::UsageDisconnect),
-            "usage_emote_only_off" => Ok(NoticeId::UsageEmoteOnlyOff),
-            "usage_emote_only_on" => Ok(NoticeId::UsageEmoteOnlyOn),
-            "usage_followers_off" => Ok(NoticeId::UsageFollowersOff),
-            "usage_followers_on" => Ok(NoticeId::UsageFollowersOn),
-            "usage_help" => Ok(NoticeId::UsageHelp),
-            "usage_host" => Ok(NoticeId::UsageHost),
-            "usage_marker" => Ok(NoticeId::UsageMarker),
-            "usage_me" => Ok(NoticeId::UsageMe),
-            "usage_mod" => Ok(NoticeId::UsageMod),
-            "usage_mods" => Ok(NoticeId::UsageMods),
-            "usage_r9k_off" => Ok(NoticeId::UsageR9kOff),
-            "usage_r9k_on" => Ok(NoticeId::UsageR9kOn),
-            "usage_raid" => Ok(NoticeId::UsageRaid),
-            "usage_slow_off" => Ok(NoticeId::UsageSlowOff),
-            "usage_slow_on" => Ok(NoticeId::UsageSlowOn),
-            "usage_subs_off" => Ok(NoticeId::UsageSubsOff),
-            "usage_subs_on" => Ok(NoticeId::UsageSubsOn),
-            "usage_timeout" => Ok(NoticeId::UsageTimeout),
-            "usage_unban" => Ok(NoticeId::UsageUnban),
-            "usage_unhost" => Ok(NoticeId::UsageUnhost),
-            "usage_unmod" => Ok(NoticeId::UsageUnmod),
-            "usage_unraid" => Ok(NoticeId::UsageUnraid),
-            "usage_untimeout" => Ok(NoticeId::UsageUntimeout),
-            "whisper_banned" => Ok(NoticeId::WhisperBanned),
-            "whisper_banned_recipient" => Ok(NoticeId::WhisperBannedRecipient),
-            "whisper_invalid_args" => Ok(NoticeId::WhisperInvalidArgs),
-            "whisper_invalid_login" => Ok(NoticeId::WhisperInvalidLogin),
-            "whisper_invalid_self" => Ok(NoticeId::WhisperInvalidSelf),
-            "whisper_limit_per_min" => Ok(NoticeId::WhisperLimitPerMin),
-            "whisper_limit_per_sec" => Ok(NoticeId::WhisperLimitPerSec),
-            "whisper_restricted" => Ok(NoticeId::WhisperRestricted),
-            "whisper_restricted_recipient" => Ok(NoticeId::WhisperRestrictedRecipient),
-            _ => Err(Error::InvalidTagValue("msg-id".into(), value.into())),
+            "already_banned" => NoticeId::AlreadyBanned,
+            "already_emote_only_off" => NoticeId::AlreadyEmoteOnlyOff,
+            "already_emote_only_on" => NoticeId::AlreadyEmoteOnlyOn,
+            "already_r9k_off" => NoticeId::AlreadyR9kOff,
+            "already_r9k_on" => NoticeId::AlreadyR9kOn,
+            "already_subs_off" => NoticeId::AlreadySubsOff,
+            "already_subs_on" => NoticeId::AlreadySubsOn,
+            "bad_ban_admin" => NoticeId::BadBanAdmin,
+            "bad_ban_anon" => NoticeId::BadBanAnon,
+            "bad_ban_broadcaster" => NoticeId::BadBanBroadcaster,
+            "bad_ban_global_mod" => NoticeId::BadBanGlobalMod,
+            "bad_ban_mod" => NoticeId::BadBanMod,
+            "bad_ban_self" => NoticeId::BadBanSelf,
+            "bad_ban_staff" => NoticeId::BadBanStaff,
+            "bad_commercial_error" => NoticeId::BadCommercialError,
+            "bad_delete_message_broadcaster" => NoticeId::BadDeleteMessageBroadcaster,
+            "bad_delete_message_error" => NoticeId::BadDeleteMessageError,
+            "bad_delete_message_mod" => NoticeId::BadDeleteMessageMod,
+            "bad_host_error" => NoticeId::BadHostError,
+            "bad_host_hosting" => NoticeId::BadHostHosting,
+            "bad_host_rate_exceeded" => NoticeId::BadHostRateExceeded,
+            "bad_host_rejected" => NoticeId::BadHostRejected,
+            "bad_host_self" => NoticeId::BadHostSelf,
+            "bad_marker_client" => NoticeId::BadMarkerClient,
+            "bad_mod_banned" => NoticeId::BadModBanned,
+            "bad_mod_mod" => NoticeId::BadModMod,
+            "bad_slow_duration" => NoticeId::BadSlowDuration,
+            "bad_timeout_admin" => NoticeId::BadTimeoutAdmin,
+            "bad_timeout_anon" => NoticeId::BadTimeoutAnon,
+            "bad_timeout_broadcaster" => NoticeId::BadTimeoutBroadcaster,
+            "bad_timeout_duration" => NoticeId::BadTimeoutDuration,
+            "bad_timeout_global_mod" => NoticeId::BadTimeoutGlobalMod,
+            "bad_timeout_mod" => NoticeId::BadTimeoutMod,
+            "bad_timeout_self" => NoticeId::BadTimeoutSelf,
+            "bad_timeout_staff" => NoticeId::BadTimeoutStaff,
+            "bad_unban_no_ban" => NoticeId::BadUnbanNoBan,
+            "bad_unhost_error" => NoticeId::BadUnhostError,
+            "bad_unmod_mod" => NoticeId::BadUnmodMod,
+            "bad_unvip_grantee_not_vip" => NoticeId::BadUnvipGranteeNotVip,
+            "bad_vip_achievement_incomplete" => NoticeId::BadVipAchievementIncomplete,
+            "bad_vip_grantee_already_vip" => NoticeId::BadVipGranteeAlreadyVip,
+            "bad_vip_grantee_banned" => NoticeId::BadVipGranteeBanned,
+            "bad_vip_max_vips_reached" => NoticeId::BadVipMaxVipsReached,
+            "ban_success" => NoticeId::BanSuccess,
+            "cmds_available" => NoticeId::CmdsAvailable,
+            "color_changed" => NoticeId::ColorChanged,
+            "commercial_success" => NoticeId::CommercialSuccess,
+            "delete_message_success" => NoticeId::DeleteMessageSuccess,
+            "emote_only_off" => NoticeId::EmoteOnlyOff,
+            "emote_only_on" => NoticeId::EmoteOnlyOn,
+            "followers_off" => NoticeId::FollowersOff,
+            "followers_on" => NoticeId::FollowersOn,
+            "followers_onzero" => NoticeId::FollowersOnzero,
+            "host_off" => NoticeId::HostOff,
+            "host_on" => NoticeId::HostOn,
+            "host_success" => NoticeId::HostSuccess,
+            "host_success_viewers" => NoticeId::HostSuccessViewers,
+            "host_target_went_offline" => NoticeId::HostTargetWentOffline,
+            "hosts_remaining" => NoticeId::HostsRemaining,
+            "invalid_user" => NoticeId::InvalidUser,
+            "mod_success" => NoticeId::ModSuccess,
+            "msg_banned" => NoticeId::MsgBanned,
+            "msg_bad_characters" => NoticeId::MsgBadCharacters,
+            "msg_channel_blocked" => NoticeId::MsgChannelBlocked,
+            "msg_channel_suspended" => NoticeId::MsgChannelSuspended,
+            "msg_duplicate" => NoticeId::MsgDuplicate,
+            "msg_emoteonly" => NoticeId::MsgEmoteonly,
+            "msg_facebook" => NoticeId::MsgFacebook,
+            "msg_followersonly" => NoticeId::MsgFollowersonly,
+            "msg_followersonly_followed" => NoticeId::MsgFollowersonlyFollowed,
+            "msg_followersonly_zero" => NoticeId::MsgFollowersonlyZero,
+            "msg_r9k" => NoticeId::MsgR9k,
+            "msg_ratelimit" => NoticeId::MsgRatelimit,
+            "msg_rejected" => NoticeId::MsgRejected,
+            "msg_rejected_mandatory" => NoticeId::MsgRejectedMandatory,
+            "msg_room_not_found" => NoticeId::MsgRoomNotFound,
+            "msg_slowmode" => NoticeId::MsgSlowmode,
+            "msg_subsonly" => NoticeId::MsgSubsonly,
+            "msg_suspended" => NoticeId::MsgSuspended,
+            "msg_timedout" => NoticeId::MsgTimedout,
+            "msg_verified_email" => NoticeId::MsgVerifiedEmail,
+            "no_help" => NoticeId::NoHelp,
+            "no_mods" => NoticeId::NoMods,
+            "not_hosting" => NoticeId::NotHosting,
+            "no_permission" => NoticeId::NoPermission,
+            "r9k_off" => NoticeId::R9kOff,
+            "r9k_on" => NoticeId::R9kOn,
+            "raid_error_already_raiding" => NoticeId::RaidErrorAlreadyRaiding,
+            "raid_error_forbidden" => NoticeId::RaidErrorForbidden,
+            "raid_error_self" => NoticeId::RaidErrorSelf,
+            "raid_error_too_many_viewers" => NoticeId::RaidErrorTooManyViewers,
+            "raid_error_unexpected" => NoticeId::RaidErrorUnexpected,
+            "raid_notice_mature" => NoticeId::RaidNoticeMature,
+            "raid_notice_restricted_chat" => NoticeId::RaidNoticeRestrictedChat,
+            "room_mods" => NoticeId::RoomMods,
+            "slow_off" => NoticeId::SlowOff,
+            "slow_on" => NoticeId::SlowOn,
+            "subs_off" => NoticeId::SubsOff,
+            "subs_on" => NoticeId::SubsOn,
+            "timeout_no_timeout" => NoticeId::TimeoutNoTimeout,
+            "timeout_success" => NoticeId::TimeoutSuccess,
+            "tos_ban" => NoticeId::TosBan,
+            "turbo_only_color" => NoticeId::TurboOnlyColor,
+            "unban_success" => NoticeId::UnbanSuccess,
+            "unmod_success" => NoticeId::UnmodSuccess,
+            "unraid_error_no_active_raid" => NoticeId::UnraidErrorNoActiveRaid,
+            "unraid_error_unexpected" => NoticeId::UnraidErrorUnexpected,
+            "unraid_success" => NoticeId::UnraidSuccess,
+            "unrecognized_cmd" => NoticeId::UnrecognizedCmd,
+            "unsupported_chatrooms_cmd" => NoticeId::UnsupportedChatroomsCmd,
+            "untimeout_banned" => NoticeId::UntimeoutBanned,
+            "untimeout_success" => NoticeId::UntimeoutSuccess,
+            "unvip_success" => NoticeId::UnvipSuccess,
+            "usage_ban" => NoticeId::UsageBan,
+            "usage_clear" => NoticeId::UsageClear,
+            "usage_color" => NoticeId::UsageColor,
+            "usage_commercial" => NoticeId::UsageCommercial,
+            "usage_delete" => NoticeId::UsageDelete,
+            "usage_disconnect" => NoticeId::UsageDisconnect,
+            "usage_emote_only_off" => NoticeId::UsageEmoteOnlyOff,
+            "usage_emote_only_on" => NoticeId::UsageEmoteOnlyOn,
+            "usage_followers_off" => NoticeId::UsageFollowersOff,
+            "usage_followers_on" => NoticeId::UsageFollowersOn,
+            "usage_help" => NoticeId::UsageHelp,
+            "usage_host" => NoticeId::UsageHost,
+            "usage_marker" => NoticeId::UsageMarker,
+            "usage_me" => NoticeId::UsageMe,
+            "usage_mod" => NoticeId::UsageMod,
+            "usage_mods" => NoticeId::UsageMods,
+            "usage_r9k_off" => NoticeId::UsageR9kOff,
+            "usage_r9k_on" => NoticeId::UsageR9kOn,
+            "usage_raid" => NoticeId::UsageRaid,
+            "usage_slow_off" => NoticeId::UsageSlowOff,
+            "usage_slow_on" => NoticeId::UsageSlowOn,
+            "usage_subs_off" => NoticeId::UsageSubsOff,
+            "usage_subs_on" => NoticeId::UsageSubsOn,
+            "usage_timeout" => NoticeId::UsageTimeout,
+            "usage_unban" => NoticeId::UsageUnban,
+            "usage_unhost" => NoticeId::UsageUnhost,
+            "usage_unmod" => NoticeId::UsageUnmod,
+            "usage_unraid" => NoticeId::UsageUnraid,
+            "usage_untimeout" => NoticeId::UsageUntimeout,
+            "usage_unvip" => NoticeId::UsageUnvip,
+            "usage_vip" => NoticeId::UsageVip,
+            "vip_success" => NoticeId::VipSuccess,
+            "whisper_banned" => NoticeId::WhisperBanned,
+            "whisper_banned_recipient" => NoticeId::WhisperBannedRecipient,
+            "whisper_invalid_args" => NoticeId::WhisperInvalidArgs,
+            "whisper_invalid_login" => NoticeId::WhisperInvalidLogin,
+            "whisper_invalid_self" => NoticeId::WhisperInvalidSelf,
+            "whisper_limit_per_min" => NoticeId::WhisperLimitPerMin,
+            "whisper_limit_per_sec" => NoticeId::WhisperLimitPerSec,
+            "whisper_restricted" => NoticeId::WhisperRestricted,
+            "whisper_restricted_recipient" => NoticeId::WhisperRestrictedRecipient,
+            _ => NoticeId::Unknown(value.to_string()),
         }
     }
+
+    pub fn category(&self) -> NoticeCategory {
+        use NoticeId::*;
+        match self {
+            MsgRatelimit | MsgDuplicate | MsgSlowmode | WhisperLimitPerMin | WhisperLimitPerSec
+            | BadHostRateExceeded => NoticeCategory::RateLimit,
+            NoPermission
+            | MsgBanned
+            | MsgBadCharacters
+            | MsgChannelBlocked
+            | MsgChannelSuspended
+            | MsgEmoteonly
+            | MsgFacebook
+            | MsgFollowersonly
+            | MsgFollowersonlyFollowed
+            | MsgFollowersonlyZero
+            | MsgR9k
+            | MsgRejected
+            | MsgRejectedMandatory
+            | MsgRoomNotFound
+            | MsgSubsonly
+            | MsgSuspended
+            | MsgTimedout
+            | MsgVerifiedEmail
+            | WhisperBanned
+            | WhisperBannedRecipient
+            | WhisperRestricted
+            | WhisperRestrictedRecipient
+            | TurboOnlyColor
+            | BadMarkerClient => NoticeCategory::Permission,
+            AlreadyBanned
+            | BadBanAdmin
+            | BadBanAnon
+            | BadBanBroadcaster
+            | BadBanGlobalMod
+            | BadBanMod
+            | BadBanSelf
+            | BadBanStaff
+            | BadDeleteMessageBroadcaster
+            | BadDeleteMessageError
+            | BadDeleteMessageMod
+            | BadModBanned
+            | BadModMod
+            | BadTimeoutAdmin
+            | BadTimeoutAnon
+            | BadTimeoutBroadcaster
+            | BadTimeoutDuration
+            | BadTimeoutGlobalMod
+            | BadTimeoutMod
+            | BadTimeoutSelf
+            | BadTimeoutStaff
+            | BadUnbanNoBan
+            | BadUnmodMod
+            | BadUnvipGranteeNotVip
+            | BadVipAchievementIncomplete
+            | BadVipGranteeAlreadyVip
+            | BadVipGranteeBanned
+            | BadVipMaxVipsReached
+            | BanSuccess
+            | DeleteMessageSuccess
+            | InvalidUser
+            | ModSuccess
+            | NoMods
+            | RoomMods
+            | TimeoutNoTimeout
+            | TimeoutSuccess
+            | UnbanSuccess
+            | UnmodSuccess
+            | UntimeoutBanned
+            | UntimeoutSuccess
+            | UnvipSuccess
+            | VipSuccess => NoticeCategory::ModerationResult,
+            AlreadyEmoteOnlyOff | AlreadyEmoteOnlyOn | AlreadyR9kOff | AlreadyR9kOn | AlreadySubsOff
+            | AlreadySubsOn | BadSlowDuration | EmoteOnlyOff | EmoteOnlyOn | FollowersOff | FollowersOn
+            | FollowersOnzero | R9kOff | R9kOn | SlowOff | SlowOn | SubsOff | SubsOn => NoticeCategory::RoomMode,
+            _ => NoticeCategory::Other,
+        }
+    }
+
+    /// Whether this notice is the answer to a PRIVMSG or whisper the client
+    /// just sent, which means that it was not delivered.
+    pub fn rejects_message(&self) -> bool {
+        use NoticeId::*;
+        matches!(
+            self,
+            MsgBanned
+                | MsgBadCharacters
+                | MsgChannelBlocked
+                | MsgChannelSuspended
+                | MsgDuplicate
+                | MsgEmoteonly
+                | MsgFacebook
+                | MsgFollowersonly
+                | MsgFollowersonlyFollowed
+                | MsgFollowersonlyZero
+                | MsgR9k
+                | MsgRatelimit
+                | MsgRejected
+                | MsgRejectedMandatory
+                | MsgRoomNotFound
+                | MsgSlowmode
+                | MsgSubsonly
+                | MsgSuspended
+                | MsgTimedout
+                | MsgVerifiedEmail
+                | WhisperBanned
+                | WhisperBannedRecipient
+                | WhisperInvalidLogin
+                | WhisperInvalidSelf
+                | WhisperLimitPerMin
+                | WhisperLimitPerSec
+                | WhisperRestricted
+                | WhisperRestrictedRecipient
+        )
+    }
 }
 
 #[twitch_getters]
 #[derive(Clone, Debug, PartialEq)]
 pub struct Notice {
     pub id: Option<NoticeId>,
-    channel: Option<UnsafeSlice>,
-    message: UnsafeSlice,
+    channel: Option<ArcSlice>,
+    message: ArcSlice,
     raw: irc::Message,
 }
 
@@ -955,13 +1411,11 @@ impl Notice {
         // :SOME_MESSAGE                in this case we skip this ^
         let (id, message) = match source.tags.get("msg-id") {
             Some(v) => (
-                Some(NoticeId::parse(v.as_ref())?),
+                Some(NoticeId::parse(v.as_ref())),
                 source
                     .params
                     .as_ref()
-                    .map(|v| v.raw())
-                    .map(|v| v.trim_start().strip_prefix(':').unwrap_or(v))
-                    .map(|v| v.into())
+                    .map(|v| v.slice(v.raw().trim_start().strip_prefix(':').unwrap_or_else(|| v.raw())))
                     .ok_or(Error::MalformedMessage)?,
             ),
             None => (
@@ -969,21 +1423,22 @@ impl Notice {
                 source
                     .params
                     .as_ref()
-                    .map(|v| v.raw())
-                    .map(|v| {
-                        v.strip_prefix('*')
-                            .unwrap_or(v)
-                            .trim_start()
-                            .strip_prefix(':')
-                            .unwrap_or(v)
+                    .map(|params| {
+                        let v = params.raw();
+                        params.slice(
+                            v.strip_prefix('*')
+                                .unwrap_or(v)
+                                .trim_start()
+                                .strip_prefix(':')
+                                .unwrap_or(v),
+                        )
                     })
-                    .map(|v| v.into())
                     .ok_or(Error::MalformedMessage)?,
             ),
         };
         Ok(Notice {
             id,
-            channel: source.channel,
+            channel: source.channel.clone(),
             message,
             raw: source,
         })
@@ -1025,7 +1480,7 @@ impl FollowerOnlyMode {
 #[twitch_getters]
 #[derive(Clone, Debug, PartialEq)]
 pub struct RoomState {
-    channel: UnsafeSlice,
+    channel: ArcSlice,
     /// Only Twitch emotes are allowed in chat
     pub emote_only: Option<bool>,
     /// See `FollowerOnlyMode` for more info
@@ -1051,7 +1506,10 @@ pub struct RoomState {
 
 impl RoomState {
     pub fn parse(source: irc::Message) -> Result<Self> {
-        let channel = source.channel.ok_or_else(|| Error::MissingParam("channel".into()))?;
+        let channel = source
+            .channel
+            .clone()
+            .ok_or_else(|| Error::MissingParam("channel".into()))?;
         let emote_only = source.tags.get_bool("emote-only");
         let followers_only = source.tags.get_number("followers-only").map(FollowerOnlyMode::parse);
         let r9k = source.tags.get_bool("r9k");
@@ -1077,19 +1535,23 @@ impl RoomState {
 #[twitch_getters]
 #[derive(Clone, Debug, PartialEq)]
 pub struct UserNoticeBase {
-    channel: UnsafeSlice,
-    text: Option<UnsafeSlice>,
+    channel: ArcSlice,
+    text: Option<ArcSlice>,
     pub user: TwitchUser,
-    color: Option<UnsafeSlice>,
-    #[csv]
-    emotes: UnsafeSlice,
-    id: UnsafeSlice,
-    room_id: UnsafeSlice,
+    color: Option<ArcSlice>,
+    pub emotes: Vec<Emote>,
+    id: ArcSlice,
+    room_id: ArcSlice,
     pub system_msg: String,
     pub time: DateTime<Utc>,
     raw: irc::Message,
 }
 
+impl UserNoticeBase {
+    /// Iterates the text as text and emote segments
+    pub fn segments(&self) -> Segments<'_> { Segments::new(self.text().unwrap_or_default(), &self.emotes) }
+}
+
 #[twitch_getters]
 #[derive(Clone, Debug, PartialEq)]
 pub struct Sub {
@@ -1097,7 +1559,7 @@ pub struct Sub {
     pub cumulative_months: i64,
     pub should_share_streak: bool,
     pub streak_months: i64,
-    sub_plan: UnsafeSlice,
+    sub_plan: ArcSlice,
     pub sub_plan_name: String,
     pub is_resub: bool,
 }
@@ -1108,9 +1570,9 @@ pub struct SubGift {
     pub base: UserNoticeBase,
     pub cumulative_months: i64,
     pub recipient_display_name: String,
-    recipient_id: UnsafeSlice,
-    recipient_login: UnsafeSlice,
-    sub_plan: UnsafeSlice,
+    recipient_id: ArcSlice,
+    recipient_login: ArcSlice,
+    sub_plan: ArcSlice,
     pub sub_plan_name: String,
     pub gift_months: i64,
     /// If the SubGift is anonymous, it means the sender
@@ -1128,9 +1590,9 @@ pub struct SubMysteryGift {
 pub struct GiftPaidUpgrade {
     pub base: UserNoticeBase,
     pub promo_gift_total: i64,
-    promo_name: UnsafeSlice,
-    sender_login: Option<UnsafeSlice>,
-    sender_name: Option<UnsafeSlice>,
+    pub promo_name: String,
+    sender_login: Option<ArcSlice>,
+    pub sender_name: Option<String>,
     pub is_anon: bool,
 }
 
@@ -1146,7 +1608,7 @@ pub struct Raid {
     /// Display name of raid origin channel
     pub source_display_name: String,
     /// Login of raid origin channel
-    source_login: UnsafeSlice,
+    source_login: ArcSlice,
     pub viewer_count: i64,
 }
 
@@ -1159,7 +1621,7 @@ pub struct Unraid {
 #[derive(Clone, Debug, PartialEq)]
 pub struct Ritual {
     pub base: UserNoticeBase,
-    ritual_name: UnsafeSlice,
+    ritual_name: ArcSlice,
 }
 
 #[twitch_getters]
@@ -1167,7 +1629,68 @@ pub struct Ritual {
 pub struct BitsBadgeTier {
     pub base: UserNoticeBase,
     /// Tier of bits badge the user just earned
-    threshold: UnsafeSlice,
+    threshold: ArcSlice,
+}
+
+#[twitch_getters]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Announcement {
+    pub base: UserNoticeBase,
+    /// Highlight color, e.g. `PRIMARY`, `BLUE` or `GREEN`
+    color: ArcSlice,
+}
+
+#[twitch_getters]
+#[derive(Clone, Debug, PartialEq)]
+pub struct PrimePaidUpgrade {
+    pub base: UserNoticeBase,
+    sub_plan: ArcSlice,
+}
+
+#[twitch_getters]
+#[derive(Clone, Debug, PartialEq)]
+pub struct ViewerMilestone {
+    pub base: UserNoticeBase,
+    /// Kind of milestone, e.g. `watch-streak`
+    category: ArcSlice,
+    /// e.g. the number of streams watched in a row
+    pub value: i64,
+    /// Channel points rewarded for the milestone
+    pub reward: Option<i64>,
+}
+
+#[twitch_getters]
+#[derive(Clone, Debug, PartialEq)]
+pub struct PayForward {
+    pub base: UserNoticeBase,
+    /// The user who gifted a sub to `base.user`, and is now paid forward
+    prior_gifter_id: Option<ArcSlice>,
+    prior_gifter_login: Option<ArcSlice>,
+    pub prior_gifter_name: Option<String>,
+    pub is_prior_gifter_anon: bool,
+    /// `None` if the gift went to the community instead of a single user
+    recipient_id: Option<ArcSlice>,
+    recipient_login: Option<ArcSlice>,
+    pub recipient_name: Option<String>,
+    pub is_community: bool,
+}
+
+#[twitch_getters]
+#[derive(Clone, Debug, PartialEq)]
+pub struct CharityDonation {
+    pub base: UserNoticeBase,
+    pub charity_name: String,
+    /// Donated amount in the currency's smallest unit, see `exponent`
+    pub amount: i64,
+    /// ISO 4217 currency code
+    currency: ArcSlice,
+    /// Number of decimal places of `amount`, e.g. 2 for USD
+    pub exponent: i64,
+}
+
+impl CharityDonation {
+    /// The donated amount in the currency's main unit
+    pub fn value(&self) -> f64 { self.amount as f64 / 10f64.powi(self.exponent as i32) }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -1181,28 +1704,62 @@ pub enum UserNotice {
     Unraid(Unraid),
     Ritual(Ritual),
     BitsBadgeTier(BitsBadgeTier),
+    Announcement(Announcement),
+    PrimePaidUpgrade(PrimePaidUpgrade),
+    ViewerMilestone(ViewerMilestone),
+    /// `standardpayforward` or `communitypayforward`
+    PayForward(PayForward),
+    CharityDonation(CharityDonation),
+    /// Any `msg-id` which doesn't have its own variant yet
+    Other {
+        msg_id: ArcSlice,
+        base: UserNoticeBase,
+    },
 }
 
 impl UserNotice {
+    /// Fields shared by all kinds of notices
+    pub fn base(&self) -> &UserNoticeBase {
+        match self {
+            UserNotice::Sub(notice) => &notice.base,
+            UserNotice::SubGift(notice) => &notice.base,
+            UserNotice::SubMysteryGift(notice) => &notice.base,
+            UserNotice::GiftPaidUpgrade(notice) => &notice.base,
+            UserNotice::RewardGift(notice) => &notice.base,
+            UserNotice::Raid(notice) => &notice.base,
+            UserNotice::Unraid(notice) => &notice.base,
+            UserNotice::Ritual(notice) => &notice.base,
+            UserNotice::BitsBadgeTier(notice) => &notice.base,
+            UserNotice::Announcement(notice) => &notice.base,
+            UserNotice::PrimePaidUpgrade(notice) => &notice.base,
+            UserNotice::ViewerMilestone(notice) => &notice.base,
+            UserNotice::PayForward(notice) => &notice.base,
+            UserNotice::CharityDonation(notice) => &notice.base,
+            UserNotice::Other { base, .. } => base,
+        }
+    }
+
     pub fn parse(source: irc::Message) -> Result<Self> {
         let base = |source: irc::Message| -> Result<UserNoticeBase> {
+            let text = source
+                .params
+                .as_ref()
+                .map(|v| v.slice(v.raw().trim_start().strip_prefix(':').unwrap_or_else(|| v.raw())));
             Ok(UserNoticeBase {
-                channel: source.channel.ok_or_else(|| Error::MissingParam("channel".into()))?,
-                text: source
-                    .params
-                    .as_ref()
-                    .map(|v| v.raw())
-                    .map(|v| v.trim_start().strip_prefix(':').unwrap_or(v))
-                    .map(|v| v.into()),
+                channel: source
+                    .channel
+                    .clone()
+                    .ok_or_else(|| Error::MissingParam("channel".into()))?,
+                emotes: Emote::parse_list(source.tags.get("emotes"), text.as_ref()),
+                text,
                 user: TwitchUser {
                     id: source.tags.require("user-id")?,
                     login: source.tags.require("login")?,
                     name: source.tags.require_ns("display-name")?,
-                    badge_info: source.tags.get("badge-info"),
-                    badges: source.tags.get("badges"),
+                    badge_info: Badge::parse_list(source.tags.get("badge-info")),
+                    badges: Badge::parse_list(source.tags.get("badges")),
                 },
                 color: source.tags.get("color"),
-                emotes: source.tags.get("emotes").unwrap_or_default(),
                 id: source.tags.require("id")?,
                 room_id: source.tags.require("room-id")?,
                 time: source.tags.require_date("tmi-sent-ts")?,
@@ -1255,17 +1812,17 @@ impl UserNotice {
             "submysterygift" => UserNotice::SubMysteryGift(SubMysteryGift { base: base(source)? }),
             "giftpaidupgrade" => UserNotice::GiftPaidUpgrade(GiftPaidUpgrade {
                 promo_gift_total: source.tags.require_number("msg-param-promo-gift-total")?,
-                promo_name: source.tags.require("msg-param-promo-name")?,
+                promo_name: source.tags.require_ns("msg-param-promo-name")?,
                 sender_login: source.tags.get("msg-param-sender-login"),
-                sender_name: source.tags.get("msg-param-sender-name"),
+                sender_name: source.tags.get_ns("msg-param-sender-name"),
                 is_anon: false,
                 base: base(source)?,
             }),
             "anongiftpaidupgrade" => UserNotice::GiftPaidUpgrade(GiftPaidUpgrade {
                 promo_gift_total: source.tags.require_number("msg-param-promo-gift-total")?,
-                promo_name: source.tags.require("msg-param-promo-name")?,
+                promo_name: source.tags.require_ns("msg-param-promo-name")?,
                 sender_login: source.tags.get("msg-param-sender-login"),
-                sender_name: source.tags.get("msg-param-sender-name"),
+                sender_name: source.tags.get_ns("msg-param-sender-name"),
                 is_anon: true,
                 base: base(source)?,
             }),
@@ -1285,7 +1842,45 @@ impl UserNotice {
                 threshold: source.tags.require("msg-param-threshold")?,
                 base: base(source)?,
             }),
-            invalid => return Err(Error::InvalidTagValue("msg-id".into(), invalid.into())),
+            "announcement" => UserNotice::Announcement(Announcement {
+                color: source.tags.require("msg-param-color")?,
+                base: base(source)?,
+            }),
+            "primepaidupgrade" => UserNotice::PrimePaidUpgrade(PrimePaidUpgrade {
+                sub_plan: source.tags.require("msg-param-sub-plan")?,
+                base: base(source)?,
+            }),
+            "viewermilestone" => UserNotice::ViewerMilestone(ViewerMilestone {
+                category: source.tags.require("msg-param-category")?,
+                value: source.tags.require_number("msg-param-value")?,
+                reward: source.tags.get_number("msg-param-copoReward"),
+                base: base(source)?,
+            }),
+            kind @ "standardpayforward" | kind @ "communitypayforward" => UserNotice::PayForward(PayForward {
+                prior_gifter_id: source.tags.get("msg-param-prior-gifter-id"),
+                prior_gifter_login: source.tags.get("msg-param-prior-gifter-user-name"),
+                prior_gifter_name: source.tags.get_ns("msg-param-prior-gifter-display-name"),
+                is_prior_gifter_anon: source
+                    .tags
+                    .get_bool("msg-param-prior-gifter-anonymous")
+                    .unwrap_or(false),
+                recipient_id: source.tags.get("msg-param-recipient-id"),
+                recipient_login: source.tags.get("msg-param-recipient-user-name"),
+                recipient_name: source.tags.get_ns("msg-param-recipient-display-name"),
+                is_community: kind == "communitypayforward",
+                base: base(source)?,
+            }),
+            "charitydonation" => UserNotice::CharityDonation(CharityDonation {
+                charity_name: source.tags.require_ns("msg-param-charity-name")?,
+                amount: source.tags.require_number("msg-param-donation-amount")?,
+                currency: source.tags.require("msg-param-donation-currency")?,
+                exponent: source.tags.require_number("msg-param-exponent")?,
+                base: base(source)?,
+            }),
+            _ => UserNotice::Other {
+                msg_id: source.tags.require("msg-id")?,
+                base: base(source)?,
+            },
         })
     }
 }
@@ -1293,24 +1888,26 @@ impl UserNotice {
 #[twitch_getters]
 #[derive(Clone, Debug, PartialEq)]
 pub struct UserState {
-    channel: UnsafeSlice,
+    channel: ArcSlice,
     pub display_name: String,
-    badge_info: Option<UnsafeSlice>,
+    pub badge_info: Vec<Badge>,
+    pub badges: Vec<Badge>,
+    color: Option<ArcSlice>,
     #[csv]
-    badges: UnsafeSlice,
-    color: Option<UnsafeSlice>,
-    #[csv]
-    emote_sets: UnsafeSlice,
+    emote_sets: ArcSlice,
     raw: irc::Message,
 }
 
 impl UserState {
     pub fn parse(source: irc::Message) -> Result<Self> {
         Ok(UserState {
-            channel: source.channel.ok_or_else(|| Error::MissingParam("channel".into()))?,
+            channel: source
+                .channel
+                .clone()
+                .ok_or_else(|| Error::MissingParam("channel".into()))?,
             display_name: source.tags.require_ns("display-name")?,
-            badge_info: source.tags.get("badge-info"),
-            badges: source.tags.get("badges").unwrap_or_default(),
+            badge_info: Badge::parse_list(source.tags.get("badge-info")),
+            badges: Badge::parse_list(source.tags.get("badges")),
             color: source.tags.get("color"),
             emote_sets: source.tags.get("emote-sets").unwrap_or_default(),
             raw: source,
@@ -1344,15 +1941,15 @@ impl CapabilitySubCmd {
 #[derive(Clone, Debug, PartialEq)]
 pub struct Capability {
     pub subcmd: CapabilitySubCmd,
-    which: UnsafeSlice,
+    which: ArcSlice,
     raw: irc::Message,
 }
 
 impl Capability {
     pub fn parse(source: irc::Message) -> Result<Self> {
         // skip the first param, which is '*'
-        let params = source.params.ok_or(Error::MalformedMessage)?;
-        let params = params
+        let source_params = source.params.as_ref().ok_or(Error::MalformedMessage)?;
+        let params = source_params
             .raw()
             .trim_start()
             .strip_prefix("* ")
@@ -1360,7 +1957,7 @@ impl Capability {
         let (subcmd, which) = match params.split_once(' ') {
             Some((s, w)) => (
                 CapabilitySubCmd::parse(s).ok_or(Error::MalformedMessage)?,
-                w.trim_start().strip_prefix(':').ok_or(Error::MalformedMessage)?.into(),
+                source_params.slice(w.trim_start().strip_prefix(':').ok_or(Error::MalformedMessage)?),
             ),
             None => return Err(Error::MalformedMessage),
         };
@@ -1381,6 +1978,131 @@ mod tests {
 
     // TODO: tests for error cases
 
+    #[test]
+    fn parse_badges() {
+        let badges = Badge::parse_list(Some("moderator/1,subscriber/3012,predictions/blue-1,,partner".into()));
+        let badges: Vec<_> = badges.iter().map(|badge| (badge.name(), badge.version())).collect();
+        assert_eq!(
+            vec![
+                ("moderator", "1"),
+                ("subscriber", "3012"),
+                ("predictions", "blue-1"),
+                ("partner", "")
+            ],
+            badges
+        );
+        assert!(Badge::parse_list(None).is_empty());
+    }
+
+    #[test]
+    fn badge_helpers() {
+        let src = "\
+        @badge-info=founder/14;badges=vip/1,founder/0;color=;display-name=randers;emotes=;id=1;room-id=2;\
+        tmi-sent-ts=1594545155039;user-id=3 :randers!randers@randers.tmi.twitch.tv PRIVMSG #forsen :hello\
+        "
+        .to_string();
+        let user = match Message::parse(src).unwrap() {
+            Message::Privmsg(message) => message.user,
+            _ => panic!("Expected a PRIVMSG"),
+        };
+        assert!(user.is_vip());
+        assert!(user.is_founder());
+        assert!(user.is_subscriber());
+        assert!(!user.is_moderator());
+        assert!(!user.is_broadcaster());
+        assert_eq!(Some(14), user.subscriber_months());
+
+        let src = "\
+        @badge-info=subscriber/22;badges=broadcaster/1,subscriber/12;color=;display-name=randers;emote-sets=0;\
+        mod=0;subscriber=1;user-type= :tmi.twitch.tv USERSTATE #randers\
+        "
+        .to_string();
+        let state = match Message::parse(src).unwrap() {
+            Message::UserState(state) => state,
+            _ => panic!("Expected a USERSTATE"),
+        };
+        assert!(state.is_broadcaster());
+        assert!(state.is_subscriber());
+        assert!(!state.is_founder());
+        assert_eq!(Some(22), state.subscriber_months());
+    }
+
+    #[test]
+    fn parse_emotes() {
+        // "👋" is one code point, but 4 bytes
+        let text: ArcSlice = "👋 Kappa hi Kappa ❤ PogChamp".into();
+        let emotes = Emote::parse_list(Some("25:2-6,11-15/88:19-26".into()), Some(&text));
+        assert_eq!(2, emotes.len());
+        assert_eq!("25", emotes[0].id());
+        assert_eq!("Kappa", emotes[0].name());
+        assert_eq!(vec![5..10, 14..19], emotes[0].ranges);
+        assert_eq!("88", emotes[1].id());
+        assert_eq!("PogChamp", emotes[1].name());
+        assert_eq!(&text.as_str()[emotes[1].ranges[0].clone()], "PogChamp");
+
+        // out of bounds, reversed and malformed ranges are skipped
+        let emotes = Emote::parse_list(Some("25:2-6,2-40,6-2,x-1/1:30-31/2".into()), Some(&text));
+        assert_eq!(1, emotes.len());
+        assert_eq!(vec![5..10], emotes[0].ranges);
+
+        assert_eq!(Emote::parse_list(None, Some(&text)), vec![]);
+        assert_eq!(Emote::parse_list(Some("".into()), Some(&text)), vec![]);
+        assert_eq!(Emote::parse_list(Some("25:0-4".into()), None), vec![]);
+    }
+
+    #[test]
+    fn emote_segments() {
+        let src = "\
+        @badge-info=;badges=;color=;display-name=randers;emotes=25:0-4,14-18/1902:6-10;id=1;room-id=2;\
+        tmi-sent-ts=1594545155039;user-id=3 :randers!randers@randers.tmi.twitch.tv PRIVMSG #forsen \
+        :\u{1}ACTION Kappa Keepo 👋 Kappa\u{1}\
+        "
+        .to_string();
+        let message = match Message::parse(src).unwrap() {
+            Message::Privmsg(message) => message,
+            _ => panic!("Expected a PRIVMSG"),
+        };
+        let segments: Vec<_> = message
+            .segments()
+            .map(|segment| match segment {
+                Segment::Text(text) => format!("text:{}", text),
+                Segment::Emote(emote) => format!("emote:{}", emote.id()),
+            })
+            .collect();
+        assert_eq!(
+            segments,
+            vec!["emote:25", "text: ", "emote:1902", "text: 👋 ", "emote:25"]
+        );
+
+        // overlapping ranges keep the first emote
+        let text: ArcSlice = "Kappa hi".into();
+        let emotes = Emote::parse_list(Some("25:0-4/1:2-3".into()), Some(&text));
+        let segments: Vec<_> = Segments::new(text.as_str(), &emotes).collect();
+        assert_eq!(segments, vec![Segment::Emote(&emotes[0]), Segment::Text(" hi")]);
+    }
+
+    #[test]
+    fn messages_share_their_source() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Message>();
+
+        let message = Message::parse(
+            "@badge-info=;badges=;color=;display-name=randers;emotes=;id=1;room-id=2;\
+             tmi-sent-ts=1594545155039;user-id=3 :randers!randers@randers.tmi.twitch.tv PRIVMSG #forsen :hello"
+                .into(),
+        )
+        .unwrap();
+        let clone = message.clone();
+        match (&message, &clone) {
+            (Message::Privmsg(a), Message::Privmsg(b)) => {
+                assert_eq!("hello", b.text());
+                assert!(std::sync::Arc::ptr_eq(&a.raw.source, &b.raw.source));
+                assert_eq!(a.text().as_ptr(), b.text().as_ptr());
+            }
+            _ => panic!("Expected a PRIVMSG"),
+        }
+    }
+
     #[test]
     pub fn parse_ping() {
         let src = "PING :tmi.twitch.tv".to_string();
@@ -1423,6 +2145,20 @@ mod tests {
         )
     }
 
+    #[test]
+    pub fn parse_pong_from_tmi() {
+        let src = ":tmi.twitch.tv PONG tmi.twitch.tv :1234".to_string();
+        let msg = irc::Message::parse(src).unwrap();
+
+        assert_eq!(
+            Message::Pong(Pong {
+                arg: Some("1234".into()),
+                raw: msg.clone()
+            }),
+            Message::parse_irc(msg).unwrap()
+        )
+    }
+
     #[test]
     pub fn parse_pong_no_arg() {
         let src = "PONG".to_string();
@@ -1486,13 +2222,14 @@ mod tests {
                     id: "40286300".into(),
                     login: "randers".into(),
                     name: "randers".into(),
-                    badge_info: Some("subscriber/5".into()),
-                    badges: Some("broadcaster/1,subscriber/0".into())
+                    badge_info: Badge::parse_list(Some("subscriber/5".into())),
+                    badges: Badge::parse_list(Some("broadcaster/1,subscriber/0".into()))
                 },
                 is_action: false,
                 bits: None,
                 color: Some("#19E6E6".into()),
-                emotes: "".into(),
+                emotes: vec![],
+                reply: None,
                 id: "7eb848c9-1060-4e5e-9f4c-612877982e79".into(),
                 room_id: "40286300".into(),
                 time: Utc.timestamp_millis(1563096499780i64),
@@ -1521,13 +2258,14 @@ mod tests {
                     id: "40286300".into(),
                     login: "randers".into(),
                     name: "randers".into(),
-                    badge_info: Some("subscriber/5".into()),
-                    badges: Some("broadcaster/1,subscriber/0".into())
+                    badge_info: Badge::parse_list(Some("subscriber/5".into())),
+                    badges: Badge::parse_list(Some("broadcaster/1,subscriber/0".into()))
                 },
                 is_action: true,
                 bits: None,
                 color: Some("#19E6E6".into()),
-                emotes: "".into(),
+                emotes: vec![],
+                reply: None,
                 id: "7eb848c9-1060-4e5e-9f4c-612877982e79".into(),
                 room_id: "40286300".into(),
                 time: Utc.timestamp_millis(1563096499780i64),
@@ -1537,6 +2275,35 @@ mod tests {
         )
     }
 
+    #[test]
+    pub fn parse_privmsg_reply() {
+        let src = "\
+        @badge-info=;badges=;color=;display-name=randers;emotes=;id=1;reply-parent-display-name=Pajlada\\s;\
+        reply-parent-msg-body=hello\\sthere;reply-parent-msg-id=6b13e51b-7ecb-43b5-ba5b-2bb5288df696;\
+        reply-parent-user-id=11148817;reply-parent-user-login=pajlada;room-id=2;tmi-sent-ts=1594545155039;\
+        user-id=3 :randers!randers@randers.tmi.twitch.tv PRIVMSG #forsen :@pajlada hi\
+        "
+        .to_string();
+        let message = match Message::parse(src).unwrap() {
+            Message::Privmsg(message) => message,
+            _ => panic!("Expected a PRIVMSG"),
+        };
+        let reply = message.reply.unwrap();
+        assert_eq!("6b13e51b-7ecb-43b5-ba5b-2bb5288df696", reply.id());
+        assert_eq!(Some("11148817"), reply.user_id());
+        assert_eq!("pajlada", reply.user_login());
+        assert_eq!(Some("Pajlada ".into()), reply.user_name);
+        assert_eq!("hello there", reply.text);
+
+        // the parent's login and text are required
+        let src = "\
+        @badge-info=;badges=;color=;display-name=randers;emotes=;id=1;reply-parent-msg-id=1;room-id=2;\
+        tmi-sent-ts=1594545155039;user-id=3 :randers!randers@randers.tmi.twitch.tv PRIVMSG #forsen :hi\
+        "
+        .to_string();
+        assert!(Message::parse(src).is_err());
+    }
+
     #[test]
     pub fn parse_whisper_non_action() {
         let src = "\
@@ -1555,13 +2322,13 @@ mod tests {
                     id: "82008718".into(),
                     login: "pajbot".into(),
                     name: "pajbot".into(),
-                    badge_info: None,
-                    badges: None
+                    badge_info: vec![],
+                    badges: vec![]
                 },
                 text: "Riftey Kappa".into(),
                 is_action: false,
                 color: Some("#2E8B57".into()),
-                emotes: "25:7-11".into(),
+                emotes: Emote::parse_list(Some("25:7-11".into()), Some(&"Riftey Kappa".into())),
                 id: "2034".into(),
                 raw: msg.clone(),
             }),
@@ -1587,13 +2354,13 @@ mod tests {
                     id: "82008718".into(),
                     login: "pajbot".into(),
                     name: "pajbot".into(),
-                    badge_info: None,
-                    badges: None
+                    badge_info: vec![],
+                    badges: vec![]
                 },
                 text: "Riftey Kappa".into(),
                 is_action: true,
                 color: Some("#2E8B57".into()),
-                emotes: "25:7-11".into(),
+                emotes: Emote::parse_list(Some("25:7-11".into()), Some(&"Riftey Kappa".into())),
                 id: "2034".into(),
                 raw: msg.clone(),
             }),
@@ -1704,8 +2471,8 @@ mod tests {
             Message::GlobalUserState(GlobalUserState {
                 user_id: "40286300".into(),
                 display_name: "RANDERS".into(),
-                badge_info: None,
-                badges: "bits-charity/1".into(),
+                badge_info: vec![],
+                badges: Badge::parse_list(Some("bits-charity/1".into())),
                 color: Some("#19E6E6".into()),
                 emote_sets: "0,42,237".into(),
                 raw: msg.clone(),
@@ -1727,8 +2494,8 @@ mod tests {
             Message::GlobalUserState(GlobalUserState {
                 user_id: "422021310".into(),
                 display_name: "receivertest3".into(),
-                badge_info: None,
-                badges: "".into(),
+                badge_info: vec![],
+                badges: vec![],
                 color: None,
                 emote_sets: "0".into(),
                 raw: msg.clone(),
@@ -1789,6 +2556,43 @@ mod tests {
         )
     }
 
+    #[test]
+    pub fn parse_notice_unknown() {
+        let src = "\
+        @msg-id=some_new_id :tmi.twitch.tv NOTICE #forsen :Something happened.\
+        "
+        .to_string();
+        let notice = match Message::parse(src).unwrap() {
+            Message::Notice(notice) => notice,
+            _ => panic!("Expected a NOTICE"),
+        };
+        assert_eq!(Some(NoticeId::Unknown("some_new_id".into())), notice.id);
+        assert_eq!("Something happened.", notice.message());
+    }
+
+    #[test]
+    fn notice_categories() {
+        assert_eq!(NoticeCategory::RateLimit, NoticeId::parse("msg_ratelimit").category());
+        assert_eq!(NoticeCategory::Permission, NoticeId::parse("no_permission").category());
+        assert_eq!(
+            NoticeCategory::ModerationResult,
+            NoticeId::parse("bad_ban_mod").category()
+        );
+        assert_eq!(
+            NoticeCategory::ModerationResult,
+            NoticeId::parse("timeout_success").category()
+        );
+        assert_eq!(NoticeCategory::RoomMode, NoticeId::parse("slow_on").category());
+        assert_eq!(NoticeCategory::Other, NoticeId::parse("host_on").category());
+        assert_eq!(NoticeCategory::Other, NoticeId::parse("some_new_id").category());
+
+        assert!(NoticeId::parse("msg_duplicate").rejects_message());
+        assert!(NoticeId::parse("msg_subsonly").rejects_message());
+        assert!(NoticeId::parse("whisper_limit_per_sec").rejects_message());
+        assert!(!NoticeId::parse("ban_success").rejects_message());
+        assert!(!NoticeId::parse("some_new_id").rejects_message());
+    }
+
     #[test]
     pub fn parse_notice_bad_auth() {
         let src = ":tmi.twitch.tv NOTICE * :Improperly formatted auth".to_string();
@@ -1872,11 +2676,11 @@ mod tests {
                         id: "1337".into(),
                         login: "ronni".into(),
                         name: "ronni".into(),
-                        badge_info: None,
-                        badges: Some("staff/1,broadcaster/1,turbo/1".into())
+                        badge_info: vec![],
+                        badges: Badge::parse_list(Some("staff/1,broadcaster/1,turbo/1".into()))
                     },
                     color: Some("#008000".into()),
-                    emotes: "".into(),
+                    emotes: vec![],
                     id: "db25007f-7a18-43eb-9379-80131e44d633".into(),
                     room_id: "1337".into(),
                     system_msg: "ronni has subscribed for 6 months!".into(),
@@ -1919,11 +2723,11 @@ mod tests {
                         id: "13405587".into(),
                         login: "tww2".into(),
                         name: "TWW2".into(),
-                        badge_info: None,
-                        badges: Some("staff/1,premium/1".into())
+                        badge_info: vec![],
+                        badges: Badge::parse_list(Some("staff/1,premium/1".into()))
                     },
                     color: Some("#0000FF".into()),
-                    emotes: "".into(),
+                    emotes: vec![],
                     id: "e9176cd8-5e22-4684-ad40-ce53c2561c5e".into(),
                     room_id: "19571752".into(),
                     system_msg: "TWW2 gifted a Tier 1 sub to Mr_Woodchuck!".into(),
@@ -1943,6 +2747,86 @@ mod tests {
         )
     }
 
+    fn parse_usernotice(tags: &str) -> UserNotice {
+        let src = format!(
+            "@badge-info=;badges=;color=;display-name=Randers;emotes=;id=1;login=randers;room-id=2;\
+             system-msg=hi;tmi-sent-ts=1594545155039;user-id=3;{} :tmi.twitch.tv USERNOTICE #forsen :hello",
+            tags
+        );
+        match Message::parse(src).unwrap() {
+            Message::UserNotice(notice) => notice,
+            _ => panic!("Expected a USERNOTICE"),
+        }
+    }
+
+    #[test]
+    pub fn parse_usernotice_kinds() {
+        match parse_usernotice("msg-id=announcement;msg-param-color=PRIMARY") {
+            UserNotice::Announcement(notice) => {
+                assert_eq!("PRIMARY", notice.color());
+                assert_eq!(Some("hello"), notice.base.text());
+            }
+            other => panic!("Expected an announcement, got {:?}", other),
+        }
+        match parse_usernotice("msg-id=primepaidupgrade;msg-param-sub-plan=1000") {
+            UserNotice::PrimePaidUpgrade(notice) => assert_eq!("1000", notice.sub_plan()),
+            other => panic!("Expected a primepaidupgrade, got {:?}", other),
+        }
+        match parse_usernotice(
+            "msg-id=viewermilestone;msg-param-category=watch-streak;msg-param-copoReward=450;\
+             msg-param-id=3;msg-param-value=5",
+        ) {
+            UserNotice::ViewerMilestone(notice) => {
+                assert_eq!("watch-streak", notice.category());
+                assert_eq!(5, notice.value);
+                assert_eq!(Some(450), notice.reward);
+            }
+            other => panic!("Expected a viewermilestone, got {:?}", other),
+        }
+        match parse_usernotice(
+            "msg-id=standardpayforward;msg-param-prior-gifter-anonymous=false;\
+             msg-param-prior-gifter-display-name=Pajlada;msg-param-prior-gifter-id=11148817;\
+             msg-param-prior-gifter-user-name=pajlada;msg-param-recipient-display-name=Forsen;\
+             msg-param-recipient-id=22484632;msg-param-recipient-user-name=forsen",
+        ) {
+            UserNotice::PayForward(notice) => {
+                assert!(!notice.is_community);
+                assert!(!notice.is_prior_gifter_anon);
+                assert_eq!(Some("pajlada"), notice.prior_gifter_login());
+                assert_eq!(Some("Pajlada".into()), notice.prior_gifter_name);
+                assert_eq!(Some("forsen"), notice.recipient_login());
+            }
+            other => panic!("Expected a standardpayforward, got {:?}", other),
+        }
+        match parse_usernotice("msg-id=communitypayforward;msg-param-prior-gifter-anonymous=true") {
+            UserNotice::PayForward(notice) => {
+                assert!(notice.is_community);
+                assert!(notice.is_prior_gifter_anon);
+                assert_eq!(None, notice.prior_gifter_login());
+                assert_eq!(None, notice.recipient_id());
+            }
+            other => panic!("Expected a communitypayforward, got {:?}", other),
+        }
+        match parse_usernotice(
+            "msg-id=charitydonation;msg-param-charity-name=Doctors\\sWithout\\sBorders;\
+             msg-param-donation-amount=1050;msg-param-donation-currency=USD;msg-param-exponent=2",
+        ) {
+            UserNotice::CharityDonation(notice) => {
+                assert_eq!("Doctors Without Borders", notice.charity_name);
+                assert_eq!("USD", notice.currency());
+                assert!((notice.value() - 10.5).abs() < f64::EPSILON);
+            }
+            other => panic!("Expected a charitydonation, got {:?}", other),
+        }
+        match parse_usernotice("msg-id=somethingnew;msg-param-foo=bar") {
+            UserNotice::Other { msg_id, base } => {
+                assert_eq!("somethingnew", msg_id.as_str());
+                assert_eq!("randers", base.user.login());
+            }
+            other => panic!("Expected an unknown notice, got {:?}", other),
+        }
+    }
+
     #[test]
     pub fn parse_reconnect() {
         let src = ":tmi.twitch.tv RECONNECT".to_string();
@@ -1968,8 +2852,8 @@ mod tests {
             Message::UserState(UserState {
                 channel: "randers".into(),
                 display_name: "zwb3_pyramids".into(),
-                badge_info: None,
-                badges: "".into(),
+                badge_info: vec![],
+                badges: vec![],
                 color: Some("#FF0000".into()),
                 emote_sets: "0".into(),
                 raw: msg.clone(),
@@ -1,6 +1,12 @@
 use std::fmt::{self, Write};
 
 use chrono::Duration;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::irc;
+
+/// Maximum number of characters in a single chat message
+pub const MAX_MESSAGE_LENGTH: usize = 500;
 
 /*
 TODO: write tests
@@ -21,6 +27,17 @@ TODO: write tests
 ✅ /slowoff
 ✅ /followers {TIME}
 ✅ /followersoff
+✅ /delete <msg id>
+✅ /mod <user>
+✅ /unmod <user>
+✅ /vip <user>
+✅ /unvip <user>
+✅ /raid <channel>
+✅ /unraid
+✅ /marker [description]
+✅ /commercial [length]
+✅ /announce <message>
+✅ /color <color>
 */
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -46,6 +63,52 @@ impl Default for SameMessageBypass {
     fn default() -> Self { SameMessageBypass { flag: 0 } }
 }
 
+/// Splits `message` into parts of at most `limit` characters.
+///
+/// Parts are split at the last whitespace which fits. A single word which is
+/// longer than `limit` starts a new part, and is then cut at grapheme
+/// boundaries, so that a character is never cut in half. An emote inside such
+/// a word can be cut though, as the parts wouldn't fit in `limit` otherwise.
+/// Whitespace around the split is removed.
+pub fn split(message: &str, limit: usize) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut rest = message.trim();
+    while !rest.is_empty() {
+        // end of the last grapheme which still fits
+        let mut end = 0;
+        let mut length = 0;
+        for (i, grapheme) in rest.grapheme_indices(true) {
+            length += grapheme.chars().count();
+            if length > limit {
+                break;
+            }
+            end = i + grapheme.len();
+        }
+        if end == rest.len() {
+            parts.push(rest);
+            break;
+        }
+        if end == 0 {
+            // a single grapheme which is longer than `limit`
+            if let Some(grapheme) = rest.graphemes(true).next() {
+                end = grapheme.len();
+            }
+        }
+        // prefer splitting at whitespace
+        let end = if rest[end..].starts_with(char::is_whitespace) {
+            end
+        } else {
+            match rest[..end].rfind(char::is_whitespace) {
+                Some(space) if !rest[..space].trim_end().is_empty() => space,
+                _ => end,
+            }
+        };
+        parts.push(rest[..end].trim_end());
+        rest = rest[end..].trim_start();
+    }
+    parts
+}
+
 struct NoAllocWrite<'a>(&'a mut String);
 impl<'a> fmt::Write for NoAllocWrite<'a> {
     fn write_str(&mut self, s: &str) -> fmt::Result {
@@ -57,6 +120,10 @@ impl<'a> fmt::Write for NoAllocWrite<'a> {
     }
 }
 
+pub fn ping(buffer: &mut String, arg: &str) -> fmt::Result {
+    buffer.clear();
+    write!(NoAllocWrite(buffer), "PING :{}\r\n", arg)
+}
 pub fn pong(buffer: &mut String, arg: Option<&str>) -> fmt::Result {
     buffer.clear();
     match arg {
@@ -72,6 +139,11 @@ pub fn cap(buffer: &mut String, with_membership: bool) -> fmt::Result {
         if with_membership { " twitch.tv/membership" } else { "" }
     )
 }
+/// Requests a single capability, so that it's ACK'd or NAK'd on its own
+pub fn cap_req(buffer: &mut String, capability: &str) -> fmt::Result {
+    buffer.clear();
+    write!(NoAllocWrite(buffer), "CAP REQ :{}\r\n", capability)
+}
 pub fn pass(buffer: &mut String, token: &str) -> fmt::Result {
     buffer.clear();
     write!(NoAllocWrite(buffer), "PASS {}\r\n", token)
@@ -99,6 +171,24 @@ pub fn privmsg(buffer: &mut String, channel: &str, smb: &mut SameMessageBypass,
         smb.get()
     )
 }
+/// Sends `message` as a reply to the message with the id `parent`
+pub fn reply(
+    buffer: &mut String,
+    channel: &str,
+    parent: &str,
+    smb: &mut SameMessageBypass,
+    message: &str,
+) -> fmt::Result {
+    buffer.clear();
+    write!(
+        NoAllocWrite(buffer),
+        "@reply-parent-msg-id={} PRIVMSG #{} :{}{}\r\n",
+        irc::escape(parent),
+        channel,
+        message,
+        smb.get()
+    )
+}
 pub fn whisper(buffer: &mut String, user: &str, message: &str) -> fmt::Result {
     buffer.clear();
     // sending to special '#jtv' channel which is join-less, so messages can be
@@ -140,6 +230,73 @@ pub fn unban(buffer: &mut String, channel: &str, user: &str) -> fmt::Result {
     buffer.clear();
     write!(NoAllocWrite(buffer), "PRIVMSG #{} :/unban {}\r\n", channel, user)
 }
+/// Deletes the message with the id `message_id`, see
+/// [`Privmsg::id`](crate::Privmsg::id)
+pub fn delete(buffer: &mut String, channel: &str, message_id: &str) -> fmt::Result {
+    buffer.clear();
+    write!(NoAllocWrite(buffer), "PRIVMSG #{} :/delete {}\r\n", channel, message_id)
+}
+pub fn mod_user(buffer: &mut String, channel: &str, user: &str) -> fmt::Result {
+    buffer.clear();
+    write!(NoAllocWrite(buffer), "PRIVMSG #{} :/mod {}\r\n", channel, user)
+}
+pub fn unmod_user(buffer: &mut String, channel: &str, user: &str) -> fmt::Result {
+    buffer.clear();
+    write!(NoAllocWrite(buffer), "PRIVMSG #{} :/unmod {}\r\n", channel, user)
+}
+pub fn vip(buffer: &mut String, channel: &str, user: &str) -> fmt::Result {
+    buffer.clear();
+    write!(NoAllocWrite(buffer), "PRIVMSG #{} :/vip {}\r\n", channel, user)
+}
+pub fn unvip(buffer: &mut String, channel: &str, user: &str) -> fmt::Result {
+    buffer.clear();
+    write!(NoAllocWrite(buffer), "PRIVMSG #{} :/unvip {}\r\n", channel, user)
+}
+/// Raids `target` from `channel`
+pub fn raid(buffer: &mut String, channel: &str, target: &str) -> fmt::Result {
+    buffer.clear();
+    write!(NoAllocWrite(buffer), "PRIVMSG #{} :/raid {}\r\n", channel, target)
+}
+pub fn unraid(buffer: &mut String, channel: &str) -> fmt::Result {
+    buffer.clear();
+    write!(NoAllocWrite(buffer), "PRIVMSG #{} :/unraid\r\n", channel)
+}
+/// Adds a stream marker, the `description` may be up to 140 characters
+pub fn marker(buffer: &mut String, channel: &str, description: Option<&str>) -> fmt::Result {
+    buffer.clear();
+    match description {
+        Some(description) => write!(
+            NoAllocWrite(buffer),
+            "PRIVMSG #{} :/marker {}\r\n",
+            channel,
+            description
+        ),
+        None => write!(NoAllocWrite(buffer), "PRIVMSG #{} :/marker\r\n", channel),
+    }
+}
+/// Runs a commercial. In case `length` is `None`, default is 30 seconds.
+pub fn commercial(buffer: &mut String, channel: &str, length: Option<Duration>) -> fmt::Result {
+    buffer.clear();
+    match length {
+        Some(length) => write!(
+            NoAllocWrite(buffer),
+            "PRIVMSG #{} :/commercial {}\r\n",
+            channel,
+            length.num_seconds()
+        ),
+        None => write!(NoAllocWrite(buffer), "PRIVMSG #{} :/commercial\r\n", channel),
+    }
+}
+pub fn announce(buffer: &mut String, channel: &str, message: &str) -> fmt::Result {
+    buffer.clear();
+    write!(NoAllocWrite(buffer), "PRIVMSG #{} :/announce {}\r\n", channel, message)
+}
+/// Changes the bot's name color, `color` is either a hex color like
+/// `#1E90FF` or one of Twitch's color names
+pub fn color(buffer: &mut String, channel: &str, color: &str) -> fmt::Result {
+    buffer.clear();
+    write!(NoAllocWrite(buffer), "PRIVMSG #{} :/color {}\r\n", channel, color)
+}
 pub enum Mode {
     R9K,
     Subscribers,
@@ -204,6 +361,47 @@ pub mod tests {
         privmsg(&mut buf, "a", &mut SameMessageBypass::default(), "b").unwrap_err();
     }
 
+    #[test]
+    fn write_reply() {
+        let mut buf = String::with_capacity(1024);
+        reply(
+            &mut buf,
+            "CHANNEL",
+            "b34ccfc7-4977-403a-8a94-33c6bac34fb8",
+            &mut SameMessageBypass::default(),
+            "MESSAGE",
+        )
+        .unwrap();
+        assert_eq!(
+            buf,
+            "@reply-parent-msg-id=b34ccfc7-4977-403a-8a94-33c6bac34fb8 PRIVMSG #CHANNEL :MESSAGE\r\n".to_string()
+        );
+    }
+    #[test]
+    fn split_at_whitespace() {
+        assert_eq!(split("hello world", 500), vec!["hello world"]);
+        assert_eq!(split("hello world", 5), vec!["hello", "world"]);
+        assert_eq!(split("hello world", 8), vec!["hello", "world"]);
+        assert_eq!(split("  a   b  ", 1), vec!["a", "b"]);
+        assert_eq!(split("", 5), Vec::<&str>::new());
+        assert_eq!(split("aaaaaa bb", 4), vec!["aaaa", "aa", "bb"]);
+        // an over-long word starts a new part before it's cut
+        assert_eq!(split("bb aaaaaa", 4), vec!["bb", "aaaa", "aa"]);
+    }
+    #[test]
+    fn split_at_graphemes() {
+        // each of these is a single grapheme, made of multiple code points
+        let family = "👨\u{200d}👩\u{200d}👧";
+        let message = format!("{0}{0}{0}", family);
+        assert_eq!(split(&message, 10), vec![format!("{0}{0}", family), family.to_string()]);
+        assert_eq!(split("ééé", 2), vec!["éé", "é"]);
+        // a grapheme which doesn't fit is sent on its own
+        assert_eq!(split(family, 2), vec![family]);
+        for part in split(&"Kappa ".repeat(200), 500) {
+            assert!(part.chars().count() <= 500);
+            assert!(part.ends_with("Kappa"));
+        }
+    }
     #[test]
     fn write_command_whisper() {
         // /w {USERNAME} {MESSAGE}
@@ -261,6 +459,75 @@ pub mod tests {
         assert_eq!(buf, "PRIVMSG #CHANNEL :/unban USER\r\n".to_string());
     }
     #[test]
+    fn write_command_delete() {
+        // /delete <msg id>
+        let mut buf = String::with_capacity(1024);
+        delete(&mut buf, "CHANNEL", "885196de-cb67-427a-baa8-82f9b0fcd05f").unwrap();
+        assert_eq!(
+            buf,
+            "PRIVMSG #CHANNEL :/delete 885196de-cb67-427a-baa8-82f9b0fcd05f\r\n".to_string()
+        );
+    }
+    #[test]
+    fn write_command_mod() {
+        // /mod <user>, /unmod <user>
+        let mut buf = String::with_capacity(1024);
+        mod_user(&mut buf, "CHANNEL", "USER").unwrap();
+        assert_eq!(buf, "PRIVMSG #CHANNEL :/mod USER\r\n".to_string());
+        unmod_user(&mut buf, "CHANNEL", "USER").unwrap();
+        assert_eq!(buf, "PRIVMSG #CHANNEL :/unmod USER\r\n".to_string());
+    }
+    #[test]
+    fn write_command_vip() {
+        // /vip <user>, /unvip <user>
+        let mut buf = String::with_capacity(1024);
+        vip(&mut buf, "CHANNEL", "USER").unwrap();
+        assert_eq!(buf, "PRIVMSG #CHANNEL :/vip USER\r\n".to_string());
+        unvip(&mut buf, "CHANNEL", "USER").unwrap();
+        assert_eq!(buf, "PRIVMSG #CHANNEL :/unvip USER\r\n".to_string());
+    }
+    #[test]
+    fn write_command_raid() {
+        // /raid <channel>, /unraid
+        let mut buf = String::with_capacity(1024);
+        raid(&mut buf, "CHANNEL", "TARGET").unwrap();
+        assert_eq!(buf, "PRIVMSG #CHANNEL :/raid TARGET\r\n".to_string());
+        unraid(&mut buf, "CHANNEL").unwrap();
+        assert_eq!(buf, "PRIVMSG #CHANNEL :/unraid\r\n".to_string());
+    }
+    #[test]
+    fn write_command_marker() {
+        // /marker [description]
+        let mut buf = String::with_capacity(1024);
+        marker(&mut buf, "CHANNEL", Some("DESCRIPTION")).unwrap();
+        assert_eq!(buf, "PRIVMSG #CHANNEL :/marker DESCRIPTION\r\n".to_string());
+        marker(&mut buf, "CHANNEL", None).unwrap();
+        assert_eq!(buf, "PRIVMSG #CHANNEL :/marker\r\n".to_string());
+    }
+    #[test]
+    fn write_command_commercial() {
+        // /commercial [length]
+        let mut buf = String::with_capacity(1024);
+        commercial(&mut buf, "CHANNEL", Some(Duration::seconds(90))).unwrap();
+        assert_eq!(buf, "PRIVMSG #CHANNEL :/commercial 90\r\n".to_string());
+        commercial(&mut buf, "CHANNEL", None).unwrap();
+        assert_eq!(buf, "PRIVMSG #CHANNEL :/commercial\r\n".to_string());
+    }
+    #[test]
+    fn write_command_announce() {
+        // /announce <message>
+        let mut buf = String::with_capacity(1024);
+        announce(&mut buf, "CHANNEL", "MESSAGE").unwrap();
+        assert_eq!(buf, "PRIVMSG #CHANNEL :/announce MESSAGE\r\n".to_string());
+    }
+    #[test]
+    fn write_command_color() {
+        // /color <color>
+        let mut buf = String::with_capacity(1024);
+        color(&mut buf, "CHANNEL", "#1E90FF").unwrap();
+        assert_eq!(buf, "PRIVMSG #CHANNEL :/color #1E90FF\r\n".to_string());
+    }
+    #[test]
     fn write_command_uniquechat() {
         // /uniquechat
         let mut buf = String::with_capacity(1024);
@@ -1,51 +1,83 @@
 use std::{
+    borrow::Borrow,
     convert::{AsRef, From},
     fmt,
     fmt::{Debug, Display, Formatter},
     hash::{Hash, Hasher},
+    sync::Arc,
 };
-use std::{slice, str};
 
-#[derive(Clone, Copy)]
-pub struct UnsafeSlice {
-    ptr: *const u8,
-    len: usize,
+/// A string slice which shares ownership of the string it points into.
+///
+/// Parsed messages store their parts as byte ranges into the same source
+/// line, so cloning a message only increments reference counts.
+#[derive(Clone)]
+pub struct ArcSlice {
+    source: Arc<str>,
+    start: usize,
+    end: usize,
 }
 
-impl UnsafeSlice {
-    pub fn len(&self) -> usize { self.len }
+impl ArcSlice {
+    pub fn len(&self) -> usize { self.end - self.start }
 
-    pub fn as_str<'a>(&self) -> &'a str {
-        unsafe { str::from_utf8_unchecked(slice::from_raw_parts(self.ptr, self.len)) }
+    pub fn is_empty(&self) -> bool { self.start == self.end }
+
+    pub fn as_str(&self) -> &str { &self.source[self.start..self.end] }
+
+    /// Returns the part of this slice which `part` points to.
+    ///
+    /// Panics if `part` is not a slice of `self.as_str()`.
+    pub fn slice(&self, part: &str) -> ArcSlice {
+        let start = (part.as_ptr() as usize)
+            .checked_sub(self.as_str().as_ptr() as usize)
+            .filter(|offset| offset + part.len() <= self.len())
+            .map(|offset| self.start + offset)
+            .expect("`part` is not a slice of `self`");
+        ArcSlice {
+            source: self.source.clone(),
+            start,
+            end: start + part.len(),
+        }
     }
 }
 
-impl From<&str> for UnsafeSlice {
-    fn from(value: &str) -> UnsafeSlice {
-        UnsafeSlice {
-            ptr: value.as_ptr(),
-            len: value.len(),
+impl From<Arc<str>> for ArcSlice {
+    fn from(value: Arc<str>) -> ArcSlice {
+        ArcSlice {
+            start: 0,
+            end: value.len(),
+            source: value,
         }
     }
 }
+impl From<String> for ArcSlice {
+    fn from(value: String) -> ArcSlice { Arc::<str>::from(value).into() }
+}
+impl From<&str> for ArcSlice {
+    fn from(value: &str) -> ArcSlice { Arc::<str>::from(value).into() }
+}
 
-impl AsRef<str> for UnsafeSlice {
-    fn as_ref(&self) -> &str { str::from_utf8(unsafe { slice::from_raw_parts(self.ptr, self.len) }).unwrap() }
+impl AsRef<str> for ArcSlice {
+    fn as_ref(&self) -> &str { self.as_str() }
+}
+impl Borrow<str> for ArcSlice {
+    fn borrow(&self) -> &str { self.as_str() }
 }
-impl Debug for UnsafeSlice {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result { Debug::fmt(AsRef::<str>::as_ref(self), f) }
+impl Debug for ArcSlice {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result { Debug::fmt(self.as_str(), f) }
 }
-impl Display for UnsafeSlice {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result { Display::fmt(AsRef::<str>::as_ref(self), f) }
+impl Display for ArcSlice {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result { Display::fmt(self.as_str(), f) }
 }
-impl Eq for UnsafeSlice {}
-impl PartialEq<UnsafeSlice> for UnsafeSlice {
-    fn eq(&self, other: &UnsafeSlice) -> bool { (AsRef::<str>::as_ref(self)).eq(AsRef::<str>::as_ref(other)) }
+impl Eq for ArcSlice {}
+impl PartialEq<ArcSlice> for ArcSlice {
+    fn eq(&self, other: &ArcSlice) -> bool { self.as_str().eq(other.as_str()) }
 }
-impl Hash for UnsafeSlice {
-    fn hash<H: Hasher>(&self, state: &mut H) { (AsRef::<str>::as_ref(self)).hash(state) }
+impl Hash for ArcSlice {
+    fn hash<H: Hasher>(&self, state: &mut H) { self.as_str().hash(state) }
 }
-impl Default for UnsafeSlice {
+impl Default for ArcSlice {
     fn default() -> Self { "".into() }
 }
 
@@ -56,12 +88,31 @@ mod tests {
     use super::*;
 
     #[test]
-    fn unsafeslice_usable_in_hash_map() {
-        let data = "Hello".to_string();
-        let slice: UnsafeSlice = (&data[..]).into();
+    fn arcslice_usable_in_hash_map() {
+        let slice: ArcSlice = "Hello".into();
 
-        let mut map = HashMap::<UnsafeSlice, UnsafeSlice>::new();
-        map.insert(slice, slice);
+        let mut map = HashMap::<ArcSlice, ArcSlice>::new();
+        map.insert(slice.clone(), slice.clone());
         assert_eq!(map.get(&slice).unwrap(), &slice);
+        assert_eq!(map.get("Hello").unwrap(), &slice);
+    }
+
+    #[test]
+    fn arcslice_shares_its_source() {
+        let line: ArcSlice = "PRIVMSG #forsen :hello".into();
+        let channel = line.slice(&line.as_str()[9..15]);
+        assert_eq!("forsen", channel.as_str());
+        assert!(Arc::ptr_eq(&line.source, &channel.source));
+
+        let text = channel.slice(&channel.as_str()[2..]);
+        assert_eq!("rsen", text.as_str());
+        assert_eq!(11, text.start);
+    }
+
+    #[test]
+    #[should_panic]
+    fn arcslice_rejects_foreign_parts() {
+        let line: ArcSlice = "PRIVMSG #forsen :hello".into();
+        line.slice("forsen");
     }
 }
@@ -1,4 +1,4 @@
-//! A proc macro for generating getters for `UnsafeSlice` fields. See the
+//! A proc macro for generating getters for `ArcSlice` fields. See the
 //! documentation on [`twitch_getters`] for more info.
 //!
 //! [`twitch_getters`]: crate::twitch_getters
@@ -13,7 +13,7 @@ use proc_macro::TokenStream;
 use quote::ToTokens;
 use syn::{spanned::Spanned, ItemStruct};
 
-const UNSAFE_SLICE_TYPE_NAME: &str = "UnsafeSlice";
+const SLICE_TYPE_NAME: &str = "ArcSlice";
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum GetterType {
@@ -23,22 +23,22 @@ enum GetterType {
     Vec,
 }
 
-/// Generates getters for `UnsafeSlice` fields contained in the struct. Only
+/// Generates getters for `ArcSlice` fields contained in the struct. Only
 /// bare, Option, and Vec fields are supported.
 ///
 ///
 /// ```ignore
-//  use crate::util::UnsafeSlice;
+//  use crate::util::ArcSlice;
 /// use twitch_getters::twitch_getters;
 ///
 ///  #[twitch_getters]
 ///  struct TwitchStruct {
-///     // UnsafeSlice fields
-///     nick: UnsafeSlice,
-///     sub: Option<UnsafeSlice>,
-///     badges: Vec<UnsafeSlice>,
+///     // ArcSlice fields
+///     nick: ArcSlice,
+///     sub: Option<ArcSlice>,
+///     badges: Vec<ArcSlice>,
 ///     #[csv]
-///     comma_sep_field: UnsafeSlice,  
+///     comma_sep_field: ArcSlice,  
 ///     // Any other fields
 ///     some_other_vec: Vec<i32>,
 ///     some_option: Option<String>
@@ -69,7 +69,7 @@ pub fn twitch_getters(_metadata: TokenStream, input: TokenStream) -> TokenStream
     let mut item: syn::Item = syn::parse(input).expect("This macro can only be used with structs.");
 
     let (name, fields) = match &mut item {
-        syn::Item::Struct(i) => (i.ident.clone(), collect_unsafe_slice_fields(i, UNSAFE_SLICE_TYPE_NAME)),
+        syn::Item::Struct(i) => (i.ident.clone(), collect_slice_fields(i, SLICE_TYPE_NAME)),
         _ => {
             item.span()
                 .unstable()
@@ -119,7 +119,7 @@ pub fn twitch_getters(_metadata: TokenStream, input: TokenStream) -> TokenStream
     output.into()
 }
 
-fn collect_unsafe_slice_fields(i: &mut ItemStruct, type_name: &str) -> Vec<(String, GetterType)> {
+fn collect_slice_fields(i: &mut ItemStruct, type_name: &str) -> Vec<(String, GetterType)> {
     let mut getters = vec![];
 
     if let syn::Fields::Named(fields) = &mut i.fields {
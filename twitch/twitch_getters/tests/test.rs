@@ -3,20 +3,20 @@ extern crate twitch_getters;
 use twitch_getters::twitch_getters;
 
 #[derive(Clone, Copy)]
-struct UnsafeSlice;
+struct ArcSlice;
 
-impl UnsafeSlice {
+impl ArcSlice {
     pub fn as_str<'a>(&self) -> &'a str { "test string, ok?" }
 }
 
 #[allow(unused)]
 #[twitch_getters]
 pub struct TestStruct {
-    field: UnsafeSlice,
+    field: ArcSlice,
     #[csv]
-    list: UnsafeSlice,
-    optional: Option<UnsafeSlice>,
-    vec: Vec<UnsafeSlice>,
+    list: ArcSlice,
+    optional: Option<ArcSlice>,
+    vec: Vec<ArcSlice>,
     msg: String,
 }
 
@@ -24,10 +24,10 @@ pub struct TestStruct {
 fn test_generated_methods() {
     let msg = String::from("a quick brown fox jumped over the lazy dog");
     let t = TestStruct {
-        field: UnsafeSlice,
-        list: UnsafeSlice,
-        optional: Some(UnsafeSlice),
-        vec: vec![UnsafeSlice],
+        field: ArcSlice,
+        list: ArcSlice,
+        optional: Some(ArcSlice),
+        vec: vec![ArcSlice],
         msg,
     };
     assert_eq!(t.field(), "test string, ok?");